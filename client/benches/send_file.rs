@@ -0,0 +1,72 @@
+//! Throughput of sending a shared file to a peer over loopback TCP, zero-copy through
+//! `sendfile(2)` against the buffered copies other writers get.
+//!
+//! ```sh
+//! cargo bench -p p2p-client --bench send_file
+//! ```
+
+use criterion::{Criterion, Throughput, criterion_group, criterion_main};
+use p2p_client::file_server::send_file;
+use p2p_client::upload::Uploads;
+use std::io::{Seek, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::PathBuf;
+
+const SIZE: u64 = 64 * 1024 * 1024;
+
+/// Hides the socket from `std::io::copy`, like encrypting or compressing writers would
+struct Buffered(TcpStream);
+
+impl Write for Buffered {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.write(buf)
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.0.flush()
+    }
+}
+
+/// A connection to a peer reading and dropping everything it's sent
+fn connect_to_sink() -> TcpStream {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    std::thread::spawn(move || {
+        let (mut conn, _) = listener.accept().unwrap();
+        std::io::copy(&mut conn, &mut std::io::sink())
+    });
+    stream
+}
+
+fn bench_send_file(c: &mut Criterion) {
+    let path = std::env::temp_dir().join(format!("p2prs-bench-{}.bin", std::process::id()));
+    let mut file = std::fs::File::create(&path).unwrap();
+    let block: Vec<u8> = (0..1024 * 1024).map(|i| (i % 251) as u8).collect();
+    for _ in 0..SIZE / block.len() as u64 {
+        file.write_all(&block).unwrap();
+    }
+    let mut file = std::fs::File::open(&path).unwrap();
+    let uploads = Uploads::new();
+    let upload = uploads.start(uploads.slot().unwrap(), None, PathBuf::from("bench"), SIZE);
+
+    let mut group = c.benchmark_group("send_file");
+    group.throughput(Throughput::Bytes(SIZE)).sample_size(10);
+    let mut sink = connect_to_sink();
+    group.bench_function("zero_copy", |b| {
+        b.iter(|| {
+            file.rewind().unwrap();
+            send_file(&mut file, SIZE, &mut sink, &upload).unwrap()
+        })
+    });
+    let mut sink = Buffered(connect_to_sink());
+    group.bench_function("buffered", |b| {
+        b.iter(|| {
+            file.rewind().unwrap();
+            send_file(&mut file, SIZE, &mut sink, &upload).unwrap()
+        })
+    });
+    group.finish();
+    std::fs::remove_file(&path).unwrap();
+}
+
+criterion_group!(benches, bench_send_file);
+criterion_main!(benches);
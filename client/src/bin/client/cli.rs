@@ -0,0 +1,187 @@
+//! Command line arguments, and how the results of commands are printed.
+
+use clap::{Args, Parser, Subcommand};
+use common::log::LogFormat;
+use serde_json::Value;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::path::PathBuf;
+
+#[derive(Parser)]
+#[command(
+    name = "client",
+    about = "Share and download files between peers",
+    after_help = "Exit codes: 0 on success, 1 on failure, 2 on bad usage or configuration, 3 \
+                  when no peer has the file, 4 when no serving client is reachable"
+)]
+pub struct Cli {
+    /// Print results as JSON
+    #[arg(long, global = true)]
+    pub json: bool,
+    /// Control socket of the serving client [default: $XDG_RUNTIME_DIR/p2prs.sock]
+    #[arg(long, global = true, env = "P2PRS_CONTROL", value_name = "PATH")]
+    pub control: Option<PathBuf>,
+    /// Config file [default: $XDG_CONFIG_HOME/p2prs/client.toml if it exists]
+    #[arg(long, global = true, env = "P2PRS_CONFIG", value_name = "PATH")]
+    pub config: Option<PathBuf>,
+    /// off, error, warn, info, debug or trace, optionally followed by levels for some
+    /// targets, like info,p2p_client::tracker=debug
+    #[arg(long, global = true, env = "P2PRS_LOG", value_name = "LEVEL")]
+    pub log_level: Option<String>,
+    /// text or json
+    #[arg(long, global = true, env = "P2PRS_LOG_FORMAT", value_name = "FORMAT")]
+    pub log_format: Option<LogFormat>,
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Share files with other peers until stopped
+    Serve(ServeArgs),
+    /// Download a file
+    Get(GetArgs),
+    /// List the peers known to the serving client
+    Peers,
+    /// List the files peers share
+    Files,
+    /// List the files peers share whose path contains PATTERN, ignoring case
+    Search { pattern: String },
+    /// Browse peers, files, uploads and downloads, and pick files to download
+    Tui,
+}
+
+/// Overrides the config file's `[serve]`, `[upload]`, `[download]`, `[dht]` and `[lan]`
+/// sections
+#[derive(Args)]
+pub struct ServeArgs {
+    /// Directory whose files are shared, can be repeated. More can be shared through the
+    /// control socket.
+    #[arg(value_name = "DIR")]
+    pub dirs: Vec<PathBuf>,
+    /// Tracker to register with, can be repeated [default: 127.0.0.1:6969 unless --dht or
+    /// --lan is given]
+    #[arg(
+        long = "tracker",
+        env = "P2PRS_TRACKERS",
+        value_delimiter = ',',
+        value_name = "ADDR"
+    )]
+    pub trackers: Vec<SocketAddrV4>,
+    /// IP the file server listens on [default: 127.0.0.1, or 0.0.0.0 with --lan]
+    #[arg(long, env = "P2PRS_BIND", value_name = "IP")]
+    pub bind: Option<Ipv4Addr>,
+    /// Port the file server listens on, random when 0 [default: 0]
+    #[arg(long, env = "P2PRS_PORT")]
+    pub port: Option<u16>,
+    /// Only use the first reachable tracker, falling over to the next ones in order
+    #[arg(long)]
+    pub failover: bool,
+    /// Uploads running at once, more requests wait for one [default: unlimited]
+    #[arg(long, value_name = "N")]
+    pub upload_slots: Option<usize>,
+    /// File requests waiting for an upload slot, more are answered that we're busy
+    /// [default: 16]
+    #[arg(long, value_name = "N")]
+    pub upload_queue: Option<usize>,
+    /// Bytes per second sent by every upload together [default: unlimited]
+    #[arg(long, value_name = "BYTES")]
+    pub upload_rate: Option<u64>,
+    /// Bytes per second sent to each peer [default: unlimited]
+    #[arg(long, value_name = "BYTES")]
+    pub peer_upload_rate: Option<u64>,
+    /// Downloads running at once, more wait in the queue [default: unlimited]
+    #[arg(long, value_name = "N")]
+    pub max_downloads: Option<usize>,
+    /// Bytes per second received by every download together [default: unlimited]
+    #[arg(long, value_name = "BYTES")]
+    pub download_rate: Option<u64>,
+    /// Announce the shared files in the DHT
+    #[arg(long)]
+    pub dht: bool,
+    /// Node to join the DHT through, can be repeated. Peers learned from trackers are used too.
+    #[arg(long, value_name = "ADDR")]
+    pub bootstrap: Vec<SocketAddrV4>,
+    /// Find peers on the LAN through multicast announcements
+    #[arg(long)]
+    pub lan: bool,
+    /// Multicast group LAN announcements are sent to [default: 239.255.42.99:6971]
+    #[arg(long, value_name = "ADDR")]
+    pub lan_group: Option<SocketAddrV4>,
+    /// Seconds between LAN announcements [default: 5]
+    #[arg(long, value_name = "SECS")]
+    pub lan_interval: Option<u64>,
+    /// File holding the client's identity, created if missing [default: a new one every run]
+    #[arg(long, env = "P2PRS_IDENTITY_KEY", value_name = "PATH")]
+    pub identity_key: Option<PathBuf>,
+}
+
+#[derive(Args)]
+pub struct GetArgs {
+    /// Path of the file, or the hex SHA-256 of its path
+    pub file: String,
+    /// Where to write the file, `-` for stdout [default: the file's name]
+    #[arg(long, short)]
+    pub out: Option<PathBuf>,
+    /// Peer to download from, instead of asking the serving client who has the file
+    #[arg(long, value_name = "ADDR")]
+    pub peer: Option<SocketAddrV4>,
+    /// Look up who has the file in the DHT, joined through this node
+    #[arg(long, value_name = "BOOTSTRAP_ADDR")]
+    pub dht: Option<SocketAddrV4>,
+    /// Reach the peer through this relaying tracker, for peers that can't be connected to
+    #[arg(long, value_name = "ADDR")]
+    pub relay: Option<SocketAddrV4>,
+}
+
+/// Prints the `peers` method's result
+pub fn print_peers(peers: &Value, json: bool) {
+    if json {
+        return println!("{peers}");
+    }
+    for peer in peers.as_array().into_iter().flatten() {
+        let sources: Vec<_> = peer["sources"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(Value::as_str)
+            .collect();
+        println!(
+            "{:<21}  {:>4} files  {}",
+            peer["addr"].as_str().unwrap_or_default(),
+            peer["files"].as_array().map_or(0, Vec::len),
+            sources.join(", "),
+        );
+    }
+}
+
+/// The files in the `files` method's result whose path contains `pattern`, ignoring case
+pub fn search<'f>(files: &'f Value, pattern: &str) -> Vec<&'f Value> {
+    let pattern = pattern.to_lowercase();
+    files
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter(|f| {
+            let path = f["path"].as_str().unwrap_or_default();
+            path.to_lowercase().contains(&pattern)
+        })
+        .collect()
+}
+
+/// Prints the `files` method's result, only the files whose path contains `pattern`
+pub fn print_files(files: &Value, pattern: Option<&str>, json: bool) {
+    let files = search(files, pattern.unwrap_or_default());
+    if json {
+        return println!("{}", Value::from_iter(files.into_iter().cloned()));
+    }
+    for file in files {
+        let peers = file["peers"].as_array().map_or(0, Vec::len);
+        println!(
+            "{:>12}  {}  {}  ({peers} {})",
+            file["size"].as_u64().unwrap_or_default(),
+            file["hash"].as_str().unwrap_or_default(),
+            file["path"].as_str().unwrap_or_default(),
+            if peers == 1 { "peer" } else { "peers" },
+        );
+    }
+}
@@ -0,0 +1,299 @@
+//! The client's configuration: built-in defaults, overridden by the TOML file, overridden by
+//! environment variables and command line arguments.
+//!
+//! ```toml
+//! control = "/run/user/1000/p2prs.sock"
+//! identity_key = "/home/me/.local/share/p2prs/identity"
+//!
+//! [serve]
+//! bind = "0.0.0.0"
+//! port = 4000
+//! share = ["/home/me/shared"]
+//! trackers = ["10.0.0.1:6969", "10.0.0.2:6969"]
+//! failover = true
+//!
+//! [upload]
+//! slots = 4
+//! queue = 16
+//! rate = 10485760
+//! peer_rate = 1048576
+//!
+//! [download]
+//! max_running = 2
+//! rate = 5242880
+//!
+//! [dht]
+//! enabled = true
+//! bootstrap = ["10.0.0.3:4000"]
+//!
+//! [lan]
+//! enabled = true
+//! group = "239.255.42.99:6971"
+//! interval_secs = 5
+//!
+//! [log]
+//! level = "info,p2p_client::tracker=debug"
+//! format = "json"
+//! ```
+
+use crate::cli::{Cli, ServeArgs};
+use common::config::{ConfigError, LogConfig};
+use p2p_client::dht::{self, NodeId};
+use p2p_client::{ClientError, Limits, UPLOAD_QUEUE};
+use p2p_client::{control, lan};
+use serde::Deserialize;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::num::NonZeroU64;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClientConfig {
+    /// Control socket of the serving client
+    pub control: PathBuf,
+    /// File holding the client's identity in the DHT and on the LAN, created if missing. A
+    /// random identity is used for every run when unset.
+    pub identity_key: Option<PathBuf>,
+    pub serve: ServeConfig,
+    pub upload: UploadConfig,
+    pub download: DownloadConfig,
+    pub dht: DhtConfig,
+    pub lan: LanConfig,
+    pub log: LogConfig,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServeConfig {
+    /// IP the file server listens on, 127.0.0.1 or 0.0.0.0 with the LAN enabled by default
+    pub bind: Option<Ipv4Addr>,
+    /// Port the file server listens on, random when 0
+    pub port: u16,
+    /// Directories whose files are shared
+    pub share: Vec<PathBuf>,
+    /// 127.0.0.1:6969 by default, unless the DHT or the LAN is enabled
+    pub trackers: Vec<SocketAddrV4>,
+    /// Only use the first reachable tracker, falling over to the next ones in order
+    pub failover: bool,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UploadConfig {
+    /// Uploads running at once, unlimited when unset
+    pub slots: Option<usize>,
+    /// File requests waiting for an upload slot, more are answered that we're busy
+    pub queue: usize,
+    /// Bytes per second sent by every upload together
+    pub rate: Option<u64>,
+    /// Bytes per second sent to each peer
+    pub peer_rate: Option<u64>,
+}
+
+/// Limits of the downloads started through the control socket, which can be changed while
+/// serving
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DownloadConfig {
+    /// Downloads running at once, more wait in the queue
+    pub max_running: Option<usize>,
+    /// Bytes per second received by every download together
+    pub rate: Option<u64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DhtConfig {
+    pub enabled: bool,
+    pub bootstrap: Vec<SocketAddrV4>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LanConfig {
+    pub enabled: bool,
+    pub group: SocketAddrV4,
+    pub interval_secs: u64,
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        Self {
+            control: control::default_socket_path(),
+            identity_key: None,
+            serve: ServeConfig::default(),
+            upload: UploadConfig::default(),
+            download: DownloadConfig::default(),
+            dht: DhtConfig::default(),
+            lan: LanConfig::default(),
+            log: LogConfig::default(),
+        }
+    }
+}
+
+impl Default for UploadConfig {
+    fn default() -> Self {
+        Self {
+            slots: None,
+            queue: UPLOAD_QUEUE,
+            rate: None,
+            peer_rate: None,
+        }
+    }
+}
+
+impl Default for LanConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            group: lan::GROUP,
+            interval_secs: lan::INTERVAL.as_secs(),
+        }
+    }
+}
+
+impl ClientConfig {
+    /// Loads the config file given in `cli` and applies the options every command takes over
+    /// it
+    pub fn load(cli: &Cli) -> Result<Self, ConfigError> {
+        let mut config: Self = common::config::load(cli.config.as_deref(), "client.toml")?;
+        if let Some(control) = &cli.control {
+            control.clone_into(&mut config.control);
+        }
+        if let Some(level) = &cli.log_level {
+            level.clone_into(&mut config.log.level);
+        }
+        config.log.format = cli.log_format.unwrap_or(config.log.format);
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn apply_serve(&mut self, args: ServeArgs) -> Result<(), ConfigError> {
+        if !args.dirs.is_empty() {
+            self.serve.share = args.dirs;
+        }
+        if !args.trackers.is_empty() {
+            self.serve.trackers = args.trackers;
+        }
+        self.serve.bind = args.bind.or(self.serve.bind);
+        self.serve.port = args.port.unwrap_or(self.serve.port);
+        self.serve.failover |= args.failover;
+        self.upload.slots = args.upload_slots.or(self.upload.slots);
+        self.upload.queue = args.upload_queue.unwrap_or(self.upload.queue);
+        self.upload.rate = args.upload_rate.or(self.upload.rate);
+        self.upload.peer_rate = args.peer_upload_rate.or(self.upload.peer_rate);
+        self.download.max_running = args.max_downloads.or(self.download.max_running);
+        self.download.rate = args.download_rate.or(self.download.rate);
+        self.dht.enabled |= args.dht;
+        if !args.bootstrap.is_empty() {
+            self.dht.bootstrap = args.bootstrap;
+        }
+        self.lan.enabled |= args.lan;
+        self.lan.group = args.lan_group.unwrap_or(self.lan.group);
+        self.lan.interval_secs = args.lan_interval.unwrap_or(self.lan.interval_secs);
+        self.identity_key = args.identity_key.or(self.identity_key.take());
+        self.validate()
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |field, reason: &str| {
+            Err(ConfigError::Invalid {
+                field,
+                reason: reason.to_string(),
+            })
+        };
+        if !self.dht.enabled && !self.dht.bootstrap.is_empty() {
+            return invalid("dht.bootstrap", "given but the DHT isn't enabled");
+        }
+        if self.upload.slots == Some(0) {
+            return invalid("upload.slots", "must be at least 1");
+        }
+        if self.upload.rate == Some(0) {
+            return invalid("upload.rate", "must be at least 1");
+        }
+        if self.upload.peer_rate == Some(0) {
+            return invalid("upload.peer_rate", "must be at least 1");
+        }
+        if self.download.max_running == Some(0) {
+            return invalid("download.max_running", "must be at least 1");
+        }
+        if self.download.rate == Some(0) {
+            return invalid("download.rate", "must be at least 1");
+        }
+        if self.lan.interval_secs == 0 {
+            return invalid("lan.interval_secs", "must be at least 1");
+        }
+        if self.lan.group.port() == 0 {
+            return invalid("lan.group", "needs a port");
+        }
+        self.log.filter()?;
+        Ok(())
+    }
+
+    /// The trackers to register with, 127.0.0.1:6969 when none is given and neither the DHT
+    /// nor the LAN is enabled
+    pub fn trackers(&self) -> Vec<SocketAddrV4> {
+        let mut trackers = self.serve.trackers.clone();
+        if trackers.is_empty() && !self.dht.enabled && !self.lan.enabled {
+            trackers.push(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 6969));
+        }
+        trackers
+    }
+
+    pub fn file_server_addr(&self) -> SocketAddrV4 {
+        // LAN peers must be able to reach the file server
+        let default = if self.lan.enabled {
+            Ipv4Addr::UNSPECIFIED
+        } else {
+            Ipv4Addr::LOCALHOST
+        };
+        SocketAddrV4::new(self.serve.bind.unwrap_or(default), self.serve.port)
+    }
+
+    pub fn limits(&self) -> Limits {
+        Limits {
+            max_uploads: self.upload.slots,
+            upload_queue: self.upload.queue,
+            upload_rate: self.upload.rate.and_then(NonZeroU64::new),
+            peer_upload_rate: self.upload.peer_rate.and_then(NonZeroU64::new),
+            max_downloads: self.download.max_running,
+            download_rate: self.download.rate.and_then(NonZeroU64::new),
+            ..Limits::default()
+        }
+    }
+
+    pub fn lan_interval(&self) -> Duration {
+        Duration::from_secs(self.lan.interval_secs)
+    }
+
+    /// The identity stored in the `identity_key` file, creating it if missing
+    pub fn identity(&self) -> Result<Option<NodeId>, ClientError> {
+        self.identity_key.as_deref().map(load_identity).transpose()
+    }
+}
+
+fn load_identity(path: &Path) -> Result<NodeId, ClientError> {
+    match std::fs::read_to_string(path) {
+        Ok(hex) => NodeId::from_hex(hex.trim()).ok_or_else(|| {
+            ConfigError::Invalid {
+                field: "identity_key",
+                reason: format!("{} doesn't hold 64 hex digits", path.display()),
+            }
+            .into()
+        }),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            let id = dht::random_id(&path.display().to_string());
+            if let Some(dir) = path.parent() {
+                std::fs::create_dir_all(dir)?;
+            }
+            std::fs::write(path, format!("{id}\n"))?;
+            Ok(id)
+        }
+        Err(source) => Err(ConfigError::Read {
+            path: path.to_path_buf(),
+            source,
+        }
+        .into()),
+    }
+}
@@ -0,0 +1,189 @@
+use clap::Parser;
+use cli::{Cli, Command, GetArgs, ServeArgs};
+use p2p_client::dht::{self, Dht};
+use p2p_client::download::{DownloadOptions, DownloadState, Downloads};
+use p2p_client::events::{Event, Events};
+use p2p_client::{ClientBuilder, ClientError, control};
+use serde_json::{Value, json};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+
+mod cli;
+
+mod config;
+use config::ClientConfig;
+
+mod tui;
+
+#[cfg(test)]
+mod test;
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let result = ClientConfig::load(&cli)
+        .map_err(ClientError::from)
+        .and_then(|config| run(cli.command, config, cli.json));
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            let message = match &e {
+                ClientError::NoFileName(_) => format!("{e}, give one with --out"),
+                e => e.to_string(),
+            };
+            if cli.json {
+                eprintln!("{}", json!({ "error": message }));
+            } else {
+                eprintln!("ERROR: {message}");
+            }
+            exit_code(&e)
+        }
+    }
+}
+
+fn run(command: Command, config: ClientConfig, json: bool) -> Result<(), ClientError> {
+    common::log::init(&config.log)?;
+    let control_path = config.control.clone();
+    match command {
+        Command::Serve(args) => serve_file_main(args, config),
+        Command::Get(args) => get_file_main(args, &control_path, json),
+        Command::Peers => control::call(&control_path, "peers", Value::Null)
+            .map(|peers| cli::print_peers(&peers, json)),
+        Command::Files => control::call(&control_path, "files", Value::Null)
+            .map(|files| cli::print_files(&files, None, json)),
+        Command::Search { pattern } => control::call(&control_path, "files", Value::Null)
+            .map(|files| cli::print_files(&files, Some(&pattern), json)),
+        Command::Tui => tui::run(&control_path),
+    }
+}
+
+/// See the exit codes in [`Cli`]'s help
+fn exit_code(e: &ClientError) -> ExitCode {
+    match e {
+        ClientError::NoProvider(_) | ClientError::UnknownHash(_) => ExitCode::from(3),
+        ClientError::ControlUnavailable { .. } => ExitCode::from(4),
+        ClientError::Config(_) | ClientError::NotADirectory(_) => ExitCode::from(2),
+        _ => ExitCode::FAILURE,
+    }
+}
+
+/// Downloads a file. Hashes are resolved to paths, and the peer to download from is found,
+/// through the serving client unless `--peer` or `--dht` is given.
+fn get_file_main(args: GetArgs, control_path: &Path, json: bool) -> Result<(), ClientError> {
+    let hash = dht::NodeId::from_hex(&args.file);
+    let files = if hash.is_some() || (args.peer.is_none() && args.dht.is_none()) {
+        control::call(control_path, "files", Value::Null)?
+    } else {
+        Value::Null
+    };
+    let files = files.as_array().map_or(&[][..], Vec::as_slice);
+    let file = files.iter().find(|f| match hash {
+        Some(hash) => f["hash"] == hash.to_string(),
+        None => f["path"] == args.file.as_str(),
+    });
+    let path = match (hash, file) {
+        (Some(_), Some(file)) => PathBuf::from(file["path"].as_str().unwrap_or_default()),
+        (Some(hash), None) => return Err(ClientError::UnknownHash(hash)),
+        (None, _) => PathBuf::from(&args.file),
+    };
+
+    let peer = match (args.peer, args.dht) {
+        (Some(peer), _) => peer,
+        (None, Some(bootstrap)) => {
+            let dht = Dht::lookup_only();
+            dht.bootstrap(bootstrap)?;
+            let providers = dht.find_providers(dht::NodeId::for_path(&path));
+            *providers
+                .first()
+                .ok_or_else(|| ClientError::NoProvider(path.clone()))?
+        }
+        (None, None) => file
+            .and_then(|f| f["peers"][0].as_str())
+            .ok_or_else(|| ClientError::NoProvider(path.clone()))?
+            .parse()?,
+    };
+
+    let out = match args.out {
+        Some(out) => out,
+        None => PathBuf::from(
+            path.file_name()
+                .ok_or_else(|| ClientError::NoFileName(path.clone()))?,
+        ),
+    };
+    // Sent to stdout once complete, since only complete downloads are kept
+    let to_stdout = out == Path::new("-");
+    let dest = match to_stdout {
+        true => std::env::temp_dir().join(format!("p2prs-get-{}", std::process::id())),
+        false => out.clone(),
+    };
+    let events = Arc::new(Events::new());
+    let finished = events.subscribe();
+    let downloads = Downloads::new().with_events(events);
+    let options = DownloadOptions {
+        relay: args.relay,
+        ..DownloadOptions::default()
+    };
+    let download = downloads.start_with(path.clone(), peer, dest.clone(), options)?;
+    for event in finished {
+        if let Event::DownloadProgress { id, .. } = event
+            && id == download.id
+            && download.finished()
+        {
+            break;
+        }
+    }
+    if let DownloadState::Failed(e) = download.state() {
+        return Err(ClientError::Fatal(e));
+    }
+    let size = download.size.load(Ordering::Relaxed);
+    if to_stdout {
+        let copied = std::fs::File::open(&dest)
+            .and_then(|mut file| std::io::copy(&mut file, &mut std::io::stdout().lock()));
+        std::fs::remove_file(&dest)?;
+        copied?;
+        return Ok(());
+    }
+    if json {
+        println!(
+            "{}",
+            json!({ "path": path, "peer": peer.to_string(), "out": out, "size": size })
+        );
+    } else {
+        println!(
+            "{} ({size} bytes) from {peer} saved to {}",
+            path.display(),
+            out.display()
+        );
+    }
+    Ok(())
+}
+
+/// Shares every file under the share roots and serves them to peers, registering with every
+/// tracker, until SIGINT or SIGTERM or until serving fails, see [`ClientBuilder`]. See
+/// [`config`] for the settings and where they're read from.
+fn serve_file_main(args: ServeArgs, mut config: ClientConfig) -> Result<(), ClientError> {
+    config.apply_serve(args)?;
+    let mut builder = ClientBuilder::new()
+        .with_bind(config.file_server_addr())
+        .with_trackers(config.trackers())
+        .with_failover(config.serve.failover)
+        .with_limits(config.limits())
+        .with_control_socket(&config.control);
+    for dir in &config.serve.share {
+        builder = builder.with_share(dir);
+    }
+    if let Some(id) = config.identity()? {
+        builder = builder.with_identity(id);
+    }
+    if config.dht.enabled {
+        builder = builder.with_dht(config.dht.bootstrap.clone());
+    }
+    if config.lan.enabled {
+        builder = builder.with_lan(config.lan.group, config.lan_interval());
+    }
+    let client = Arc::new(builder.start()?);
+    let stopping = Arc::clone(&client);
+    ctrlc::set_handler(move || stopping.stop()).map_err(|e| ClientError::Fatal(e.to_string()))?;
+    client.wait()
+}
@@ -0,0 +1,230 @@
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+#[test]
+fn cli_parses_subcommands() {
+    use crate::cli::{Cli, Command};
+    use clap::Parser;
+
+    let cli = Cli::try_parse_from([
+        "client",
+        "serve",
+        "shared",
+        "--tracker",
+        "127.0.0.1:6969",
+        "--tracker",
+        "127.0.0.1:6970",
+        "--port",
+        "4000",
+        "--json",
+    ])
+    .unwrap();
+    assert!(cli.json);
+    let Command::Serve(args) = cli.command else {
+        panic!("expected serve");
+    };
+    assert_eq!(args.dirs, [PathBuf::from("shared")]);
+    assert_eq!(args.trackers.len(), 2);
+    assert_eq!(args.port, Some(4000));
+    assert_eq!(args.lan_group, None);
+
+    let cli = Cli::try_parse_from(["client", "get", "a.txt", "-o", "-"]).unwrap();
+    let Command::Get(args) = cli.command else {
+        panic!("expected get");
+    };
+    assert_eq!(args.file, "a.txt");
+    assert_eq!(args.out, Some(PathBuf::from("-")));
+
+    for bad in [
+        &["client", "get"][..],
+        &["client", "serve", "--tracker", "localhost"],
+        &["client", "fetch"],
+    ] {
+        let e = Cli::try_parse_from(bad).err().unwrap();
+        assert_eq!(e.exit_code(), 2);
+    }
+}
+
+#[test]
+fn search_ignores_case() {
+    let files = serde_json::json!([
+        { "path": "music/Song.mp3" },
+        { "path": "notes.txt" },
+        { "path": "SONGS.txt" },
+    ]);
+    let found: Vec<_> = crate::cli::search(&files, "song")
+        .iter()
+        .map(|f| f["path"].as_str().unwrap())
+        .collect();
+    assert_eq!(found, ["music/Song.mp3", "SONGS.txt"]);
+    assert_eq!(crate::cli::search(&files, "").len(), 3);
+}
+
+#[test]
+fn config_file_is_overridden_by_arguments() {
+    use crate::cli::{Cli, Command};
+    use crate::config::ClientConfig;
+    use clap::Parser;
+    use common::config::{ConfigError, parse};
+
+    let file = Path::new("client.toml");
+    let mut config: ClientConfig = parse(
+        r#"
+        identity_key = "identity"
+        [serve]
+        port = 4000
+        trackers = ["10.0.0.1:6969", "10.0.0.2:6969"]
+        failover = true
+        [upload]
+        slots = 2
+        rate = 1000000
+        [download]
+        max_running = 3
+        [lan]
+        interval_secs = 2
+        "#,
+        file,
+    )
+    .unwrap();
+    let cli = Cli::try_parse_from([
+        "client",
+        "serve",
+        "--port",
+        "5000",
+        "--lan",
+        "--upload-rate",
+        "2000000",
+    ])
+    .unwrap();
+    let Command::Serve(args) = cli.command else {
+        panic!("expected serve");
+    };
+    config.apply_serve(args).unwrap();
+    assert_eq!(config.file_server_addr(), "0.0.0.0:5000".parse().unwrap());
+    assert_eq!(config.trackers().len(), 2);
+    assert!(config.serve.failover);
+    assert!(config.lan.enabled);
+    assert_eq!(config.lan_interval(), Duration::from_secs(2));
+    assert_eq!(config.identity_key, Some(PathBuf::from("identity")));
+    let limits = config.limits();
+    assert_eq!(limits.max_uploads, Some(2));
+    assert_eq!(limits.upload_queue, p2p_client::UPLOAD_QUEUE);
+    assert_eq!(limits.upload_rate.map(u64::from), Some(2_000_000));
+    assert_eq!(limits.max_downloads, Some(3));
+    assert_eq!(limits.download_rate, None);
+
+    let defaults: ClientConfig = parse("", file).unwrap();
+    assert_eq!(defaults.trackers(), ["127.0.0.1:6969".parse().unwrap()]);
+
+    let invalid = |text: &str| parse::<ClientConfig>(text, file).and_then(|c| c.validate());
+    assert!(matches!(
+        invalid("[lan]\ninterval_secs = 0"),
+        Err(ConfigError::Invalid {
+            field: "lan.interval_secs",
+            ..
+        })
+    ));
+    assert!(matches!(
+        invalid("[upload]\nslots = 0"),
+        Err(ConfigError::Invalid {
+            field: "upload.slots",
+            ..
+        })
+    ));
+    assert!(matches!(
+        invalid("[log]\nlevel = \"loud\""),
+        Err(ConfigError::Invalid {
+            field: "log.level",
+            ..
+        })
+    ));
+    assert!(matches!(
+        invalid("[dht]\nbootstrap = [\"10.0.0.3:4000\"]"),
+        Err(ConfigError::Invalid {
+            field: "dht.bootstrap",
+            ..
+        })
+    ));
+    assert!(matches!(
+        invalid("[serve]\nprot = 4000"),
+        Err(ConfigError::Parse { .. })
+    ));
+}
+
+#[test]
+fn identity_key_is_kept_across_runs() {
+    use crate::config::ClientConfig;
+
+    let dir = std::env::temp_dir().join(format!("p2prs-identity-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let config = ClientConfig {
+        identity_key: Some(dir.join("identity")),
+        ..ClientConfig::default()
+    };
+    let created = config.identity().unwrap().unwrap();
+    assert_eq!(config.identity().unwrap(), Some(created));
+
+    std::fs::write(dir.join("identity"), "not hex").unwrap();
+    assert!(config.identity().is_err());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn tui_picks_files_and_draws_progress() {
+    use crate::tui::{Action, App, Pane, Swarm, human_size};
+    use ratatui::Terminal;
+    use ratatui::backend::TestBackend;
+    use ratatui::crossterm::event::KeyCode;
+    use serde_json::json;
+
+    let mut app = App::default();
+    app.update(Swarm {
+        peers: vec![json!({
+            "addr": "127.0.0.1:4000",
+            "files": [{ "path": "a.txt", "size": 2048 }, { "path": "b.txt", "size": 3 }],
+            "sources": ["tracker 127.0.0.1:6969"],
+        })],
+        files: vec![
+            json!({ "path": "a.txt", "hash": "ab12", "size": 2048, "peers": ["127.0.0.1:4000"] }),
+            json!({ "path": "b.txt", "hash": "cd34", "size": 3, "peers": ["127.0.0.1:4000"] }),
+        ],
+        uploads: vec![],
+        downloads: vec![json!({
+            "id": 7, "path": "a.txt", "peer": "127.0.0.1:4000", "received": 1024,
+            "state": "running", "priority": 1,
+        })],
+    });
+
+    assert_eq!(app.key(KeyCode::Down), Action::None);
+    assert_eq!(app.key(KeyCode::Down), Action::None);
+    assert_eq!(
+        app.key(KeyCode::Enter),
+        Action::Download(PathBuf::from("b.txt"))
+    );
+    assert_eq!(app.key(KeyCode::Tab), Action::None);
+    assert_eq!(app.pane, Pane::Downloads);
+    assert_eq!(app.key(KeyCode::Enter), Action::None);
+    assert_eq!(app.key(KeyCode::Char('c')), Action::Cancel(7));
+    assert_eq!(
+        app.key(KeyCode::Char('-')),
+        Action::Prioritize { id: 7, priority: 0 }
+    );
+    assert_eq!(app.key(KeyCode::Char('q')), Action::Quit);
+
+    let mut terminal = Terminal::new(TestBackend::new(120, 20)).unwrap();
+    terminal.draw(|frame| app.draw(frame)).unwrap();
+    let screen: String = terminal
+        .backend()
+        .buffer()
+        .content()
+        .iter()
+        .map(|cell| cell.symbol())
+        .collect();
+    assert!(screen.contains("127.0.0.1:4000  2 files"));
+    assert!(screen.contains("1 seeder"));
+    assert!(screen.contains("a.txt from 127.0.0.1:4000 (running)  1.0 KiB / 2.0 KiB"));
+
+    assert_eq!(human_size(3), "3 B");
+    assert_eq!(human_size(1536), "1.5 KiB");
+    assert_eq!(human_size(5 << 30), "5.0 GiB");
+}
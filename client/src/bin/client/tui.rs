@@ -0,0 +1,360 @@
+//! Interactive terminal UI following a serving client through its control socket: known
+//! peers, every file they share, and the uploads and downloads running.
+
+use p2p_client::{ClientError, control};
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, Gauge, List, ListItem, Paragraph, Row, Table, TableState};
+use ratatui::{DefaultTerminal, Frame};
+use serde_json::{Value, json};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+const REFRESH: Duration = Duration::from_millis(500);
+
+/// What the serving client last told us
+#[derive(Debug, Default)]
+pub struct Swarm {
+    pub peers: Vec<Value>,
+    pub files: Vec<Value>,
+    pub uploads: Vec<Value>,
+    pub downloads: Vec<Value>,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum Pane {
+    #[default]
+    Files,
+    Downloads,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Action {
+    None,
+    Quit,
+    Refresh,
+    Download(PathBuf),
+    Cancel(u64),
+    Prioritize { id: u64, priority: i64 },
+}
+
+#[derive(Default)]
+pub struct App {
+    pub swarm: Swarm,
+    pub pane: Pane,
+    selected_file: usize,
+    selected_download: usize,
+    /// Outcome of the last action, or the last error
+    pub status: String,
+}
+
+impl Swarm {
+    pub fn fetch(control_path: &Path) -> Result<Self, ClientError> {
+        let list = |method| {
+            control::call(control_path, method, Value::Null)
+                .map(|v| v.as_array().cloned().unwrap_or_default())
+        };
+        Ok(Self {
+            peers: list("peers")?,
+            files: list("files")?,
+            uploads: list("uploads")?,
+            downloads: list("downloads")?,
+        })
+    }
+
+    /// Size of the remote file at `path`, if a peer still shares it
+    fn size(&self, path: &Value) -> Option<u64> {
+        self.files
+            .iter()
+            .find(|f| f["path"] == *path)
+            .and_then(|f| f["size"].as_u64())
+    }
+}
+
+impl App {
+    pub fn update(&mut self, swarm: Swarm) {
+        self.selected_file = self.selected_file.min(swarm.files.len().saturating_sub(1));
+        self.selected_download = self
+            .selected_download
+            .min(swarm.downloads.len().saturating_sub(1));
+        self.swarm = swarm;
+    }
+
+    pub fn key(&mut self, code: KeyCode) -> Action {
+        let (selected, len) = match self.pane {
+            Pane::Files => (&mut self.selected_file, self.swarm.files.len()),
+            Pane::Downloads => (&mut self.selected_download, self.swarm.downloads.len()),
+        };
+        match code {
+            KeyCode::Char('q') | KeyCode::Esc => return Action::Quit,
+            KeyCode::Char('r') => return Action::Refresh,
+            KeyCode::Up | KeyCode::Char('k') => *selected = selected.saturating_sub(1),
+            KeyCode::Down | KeyCode::Char('j') => {
+                *selected = (*selected + 1).min(len.saturating_sub(1));
+            }
+            KeyCode::Tab | KeyCode::BackTab => {
+                self.pane = match self.pane {
+                    Pane::Files => Pane::Downloads,
+                    Pane::Downloads => Pane::Files,
+                };
+            }
+            KeyCode::Enter | KeyCode::Char('d') if self.pane == Pane::Files => {
+                if let Some(path) = self
+                    .swarm
+                    .files
+                    .get(*selected)
+                    .and_then(|f| f["path"].as_str())
+                {
+                    return Action::Download(PathBuf::from(path));
+                }
+            }
+            KeyCode::Char('c') | KeyCode::Delete if self.pane == Pane::Downloads => {
+                if let Some(id) = self
+                    .swarm
+                    .downloads
+                    .get(*selected)
+                    .and_then(|d| d["id"].as_u64())
+                {
+                    return Action::Cancel(id);
+                }
+            }
+            KeyCode::Char(key @ ('+' | '-')) if self.pane == Pane::Downloads => {
+                if let Some(download) = self.swarm.downloads.get(*selected)
+                    && let Some(id) = download["id"].as_u64()
+                {
+                    let step = if key == '+' { 1 } else { -1 };
+                    let priority = download["priority"].as_i64().unwrap_or_default() + step;
+                    return Action::Prioritize { id, priority };
+                }
+            }
+            _ => {}
+        }
+        Action::None
+    }
+
+    pub fn draw(&self, frame: &mut Frame) {
+        let [top, uploads, downloads, help] = Layout::vertical([
+            Constraint::Fill(1),
+            Constraint::Length(self.swarm.uploads.len().clamp(1, 6) as u16 + 2),
+            Constraint::Length(self.swarm.downloads.len().clamp(1, 8) as u16 + 2),
+            Constraint::Length(1),
+        ])
+        .areas(frame.area());
+        let [peers, files] =
+            Layout::horizontal([Constraint::Percentage(35), Constraint::Percentage(65)]).areas(top);
+        self.draw_peers(frame, peers);
+        self.draw_files(frame, files);
+        self.draw_uploads(frame, uploads);
+        self.draw_downloads(frame, downloads);
+        let keys = match self.pane {
+            Pane::Files => "↑↓ select  Enter download  Tab downloads  q quit",
+            Pane::Downloads => "↑↓ select  c cancel  +- priority  Tab files  q quit",
+        };
+        let help_line = format!("{keys}  {}", self.status);
+        frame.render_widget(Paragraph::new(help_line), help);
+    }
+
+    fn draw_peers(&self, frame: &mut Frame, area: Rect) {
+        let items = self.swarm.peers.iter().map(|p| {
+            let files = p["files"].as_array().map_or(0, Vec::len);
+            let sources: Vec<_> = p["sources"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter_map(Value::as_str)
+                .collect();
+            ListItem::new(vec![
+                Line::from(format!("{}  {files} files", str(&p["addr"]))),
+                Line::from(format!("  {}", sources.join(", "))).style(Style::new().dim()),
+            ])
+        });
+        let title = format!(" Peers ({}) ", self.swarm.peers.len());
+        frame.render_widget(List::new(items).block(Block::bordered().title(title)), area);
+    }
+
+    fn draw_files(&self, frame: &mut Frame, area: Rect) {
+        let rows = self.swarm.files.iter().map(|f| {
+            let seeders = f["peers"].as_array().map_or(0, Vec::len);
+            Row::new(vec![
+                str(&f["path"]).to_string(),
+                human_size(f["size"].as_u64().unwrap_or_default()),
+                format!(
+                    "{seeders} {}",
+                    if seeders == 1 { "seeder" } else { "seeders" }
+                ),
+                str(&f["hash"]).chars().take(12).collect(),
+            ])
+        });
+        let widths = [
+            Constraint::Fill(1),
+            Constraint::Length(10),
+            Constraint::Length(10),
+            Constraint::Length(12),
+        ];
+        let title = format!(" Files ({}) ", self.swarm.files.len());
+        let table = Table::new(rows, widths)
+            .header(Row::new(["Path", "Size", "Seeders", "Hash"]).style(Style::new().bold()))
+            .block(self.block(title, Pane::Files))
+            .row_highlight_style(Style::new().add_modifier(Modifier::REVERSED));
+        let mut state = TableState::default().with_selected(
+            (self.pane == Pane::Files && !self.swarm.files.is_empty())
+                .then_some(self.selected_file),
+        );
+        frame.render_stateful_widget(table, area, &mut state);
+    }
+
+    fn draw_uploads(&self, frame: &mut Frame, area: Rect) {
+        let block = Block::bordered().title(format!(" Uploads ({}) ", self.swarm.uploads.len()));
+        let inner = block.inner(area);
+        frame.render_widget(block, area);
+        for (upload, row) in self.swarm.uploads.iter().zip(rows(inner)) {
+            let sent = upload["sent"].as_u64().unwrap_or_default();
+            let size = upload["size"].as_u64().unwrap_or_default();
+            let peer = upload["peer"].as_str().unwrap_or("relay");
+            let label = format!("{} → {peer}", str(&upload["path"]));
+            frame.render_widget(progress(label, sent, Some(size), Color::Blue), row);
+        }
+    }
+
+    fn draw_downloads(&self, frame: &mut Frame, area: Rect) {
+        let title = format!(" Downloads ({}) ", self.swarm.downloads.len());
+        let block = self.block(title, Pane::Downloads);
+        let inner = block.inner(area);
+        frame.render_widget(block, area);
+        let downloads = self.swarm.downloads.iter().enumerate();
+        for ((i, download), row) in downloads.zip(rows(inner)) {
+            let received = download["received"].as_u64().unwrap_or_default();
+            let state = str(&download["state"]);
+            let size = match state {
+                "done" => Some(received),
+                _ => self.swarm.size(&download["path"]),
+            };
+            let selected = self.pane == Pane::Downloads && i == self.selected_download;
+            let label = format!(
+                "{}{} from {} ({state})",
+                if selected { "> " } else { "" },
+                str(&download["path"]),
+                str(&download["peer"]),
+            );
+            let color = match state {
+                "failed" | "cancelled" => Color::Red,
+                _ => Color::Green,
+            };
+            frame.render_widget(progress(label, received, size, color), row);
+        }
+    }
+
+    fn block(&self, title: String, pane: Pane) -> Block<'static> {
+        let block = Block::bordered().title(title);
+        if self.pane == pane {
+            block.border_style(Style::new().fg(Color::Yellow))
+        } else {
+            block
+        }
+    }
+}
+
+fn str(value: &Value) -> &str {
+    value.as_str().unwrap_or_default()
+}
+
+/// One line high rows filling `area`
+fn rows(area: Rect) -> impl Iterator<Item = Rect> {
+    (area.y..area.bottom()).map(move |y| Rect::new(area.x, y, area.width, 1))
+}
+
+fn progress(label: String, done: u64, total: Option<u64>, color: Color) -> Gauge<'static> {
+    let ratio = match total {
+        Some(0) => 1.0,
+        Some(total) => (done as f64 / total as f64).min(1.0),
+        None => 0.0,
+    };
+    let total = total.map_or_else(|| "?".to_string(), human_size);
+    Gauge::default()
+        .ratio(ratio)
+        .label(format!("{label}  {} / {total}", human_size(done)))
+        .gauge_style(Style::new().fg(color))
+}
+
+pub fn human_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{bytes} B");
+    }
+    let mut size = bytes as f64;
+    let mut unit = "B";
+    for next in UNITS {
+        if size < 1024.0 {
+            break;
+        }
+        size /= 1024.0;
+        unit = next;
+    }
+    format!("{size:.1} {unit}")
+}
+
+/// Runs the UI until the user quits or the serving client goes away
+pub fn run(control_path: &Path) -> Result<(), ClientError> {
+    // Fails before taking over the terminal when no client is serving
+    let swarm = Swarm::fetch(control_path)?;
+    let mut terminal = ratatui::init();
+    let result = event_loop(&mut terminal, control_path, swarm);
+    ratatui::restore();
+    result
+}
+
+fn event_loop(
+    terminal: &mut DefaultTerminal,
+    control_path: &Path,
+    swarm: Swarm,
+) -> Result<(), ClientError> {
+    let mut app = App::default();
+    app.update(swarm);
+    let mut refreshed = Instant::now();
+    loop {
+        terminal.draw(|frame| app.draw(frame))?;
+        let mut action = Action::None;
+        if event::poll(REFRESH.saturating_sub(refreshed.elapsed()))?
+            && let Event::Key(key) = event::read()?
+            && key.kind == KeyEventKind::Press
+        {
+            action = app.key(key.code);
+        }
+        let acted = action != Action::None;
+        let done = match action {
+            Action::None | Action::Refresh => Ok(None),
+            Action::Quit => return Ok(()),
+            Action::Download(path) => {
+                let dest = std::env::current_dir()?.join(path.file_name().unwrap_or_default());
+                let params = json!({ "path": path, "dest": dest });
+                control::call(control_path, "download.start", params).map(|_| {
+                    Some(format!(
+                        "Downloading {} to {}",
+                        path.display(),
+                        dest.display()
+                    ))
+                })
+            }
+            Action::Cancel(id) => {
+                control::call(control_path, "download.cancel", json!({ "id": id }))
+                    .map(|_| Some(format!("Cancelled download {id}")))
+            }
+            Action::Prioritize { id, priority } => {
+                let params = json!({ "id": id, "priority": priority });
+                control::call(control_path, "download.set", params)
+                    .map(|_| Some(format!("Download {id} now has priority {priority}")))
+            }
+        };
+        match done {
+            Ok(Some(status)) => app.status = status,
+            Ok(None) => {}
+            Err(e) => app.status = e.to_string(),
+        }
+        if acted || refreshed.elapsed() >= REFRESH {
+            app.update(Swarm::fetch(control_path)?);
+            refreshed = Instant::now();
+        }
+    }
+}
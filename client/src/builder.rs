@@ -0,0 +1,522 @@
+//! Setting up and running a client: [`ClientBuilder`] takes the settings and starts the
+//! client, the [`Client`] it returns shares files, follows what peers share and downloads it.
+
+use crate::control::{self, Control};
+use crate::dht::{self, Dht, NodeId};
+use crate::download::{Download, DownloadLimits, DownloadOptions, Downloads};
+use crate::events::{Event, Events};
+use crate::file_server::{FileServer, FileSystem, SimpleFileSystem};
+use crate::lan::LanDiscovery;
+use crate::pex::PeerExchange;
+use crate::tracker::{Peer, Peers, RemoteFile, TrackerLink, TrackerServerContext};
+use crate::upload::{Upload, UploadLimits};
+use crate::watch::{self, ShareWatcher};
+use crate::{ClientError, ipv4};
+use common::File;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::num::NonZeroU64;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{Receiver, Sender, channel};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How long uploads get to finish when stopping by default
+pub const DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// File requests waiting for an upload by default, when their number is limited
+pub const UPLOAD_QUEUE: usize = 16;
+
+#[derive(Debug, Clone)]
+pub struct Limits {
+    /// File requests wait in the queue while this many uploads are running
+    pub max_uploads: Option<usize>,
+    /// File requests waiting for an upload, more are answered that we're busy
+    pub upload_queue: usize,
+    /// Bytes per second sent by every upload together
+    pub upload_rate: Option<NonZeroU64>,
+    /// Bytes per second sent to each peer
+    pub peer_upload_rate: Option<NonZeroU64>,
+    /// Downloads wait in the queue while this many are running
+    pub max_downloads: Option<usize>,
+    /// Bytes per second received by every download together
+    pub download_rate: Option<NonZeroU64>,
+    /// How long the uploads running get to finish when stopping
+    pub drain_timeout: Duration,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_uploads: None,
+            upload_queue: UPLOAD_QUEUE,
+            upload_rate: None,
+            peer_upload_rate: None,
+            max_downloads: None,
+            download_rate: None,
+            drain_timeout: DRAIN_TIMEOUT,
+        }
+    }
+}
+
+/// Settings of a client to start.
+///
+/// By default the file server listens on a random port of 127.0.0.1, nothing is shared, no
+/// tracker is used and neither the DHT, the LAN nor a control socket are.
+pub struct ClientBuilder<FS: FileSystem = SimpleFileSystem> {
+    file_system: Option<FS>,
+    bind: SocketAddrV4,
+    share: Vec<PathBuf>,
+    watch: bool,
+    trackers: Vec<SocketAddrV4>,
+    failover: bool,
+    dht: Option<Vec<SocketAddrV4>>,
+    lan: Option<(SocketAddrV4, Duration)>,
+    identity: Option<NodeId>,
+    control: Option<PathBuf>,
+    limits: Limits,
+}
+
+impl Default for ClientBuilder {
+    fn default() -> Self {
+        Self {
+            file_system: None,
+            bind: SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0),
+            share: Vec::new(),
+            watch: true,
+            trackers: Vec::new(),
+            failover: false,
+            dht: None,
+            lan: None,
+            identity: None,
+            control: None,
+            limits: Limits::default(),
+        }
+    }
+}
+
+impl ClientBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl<FS: FileSystem + Send + Sync + 'static> ClientBuilder<FS> {
+    /// Serves the files of `file_system` instead, files under the share roots are added to it
+    pub fn with_file_system<Other: FileSystem>(self, file_system: Other) -> ClientBuilder<Other> {
+        ClientBuilder {
+            file_system: Some(file_system),
+            bind: self.bind,
+            share: self.share,
+            watch: self.watch,
+            trackers: self.trackers,
+            failover: self.failover,
+            dht: self.dht,
+            lan: self.lan,
+            identity: self.identity,
+            control: self.control,
+            limits: self.limits,
+        }
+    }
+    /// Where the file server listens, DHT requests and peer exchanges also arrive there
+    pub fn with_bind(mut self, addr: SocketAddrV4) -> Self {
+        self.bind = addr;
+        self
+    }
+    /// Shares every file under `dir`
+    pub fn with_share(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.share.push(dir.into());
+        self
+    }
+    /// Whether files added, changed or removed under the share roots are published once they
+    /// settle, see [`watch`]. On by default.
+    pub fn with_watch(mut self, watch: bool) -> Self {
+        self.watch = watch;
+        self
+    }
+    pub fn with_tracker(mut self, tracker: SocketAddrV4) -> Self {
+        self.trackers.push(tracker);
+        self
+    }
+    pub fn with_trackers(mut self, trackers: impl IntoIterator<Item = SocketAddrV4>) -> Self {
+        self.trackers.extend(trackers);
+        self
+    }
+    /// Only uses the first reachable tracker, falling over to the next ones in order when it
+    /// goes away, instead of registering with every tracker
+    pub fn with_failover(mut self, failover: bool) -> Self {
+        self.failover = failover;
+        self
+    }
+    /// Announces the shared files in the DHT, joined through every `bootstrap` node and every
+    /// peer learned from trackers
+    pub fn with_dht(mut self, bootstrap: Vec<SocketAddrV4>) -> Self {
+        self.dht = Some(bootstrap);
+        self
+    }
+    /// Finds peers through announcements to the multicast `group` every `interval`, by default
+    /// [`lan::GROUP`](crate::lan::GROUP) and [`lan::INTERVAL`](crate::lan::INTERVAL)
+    pub fn with_lan(mut self, group: SocketAddrV4, interval: Duration) -> Self {
+        self.lan = Some((group, interval));
+        self
+    }
+    /// Identity in the DHT and on the LAN, random when not given
+    pub fn with_identity(mut self, identity: NodeId) -> Self {
+        self.identity = Some(identity);
+        self
+    }
+    /// Answers JSON-RPC on the Unix socket at `path`, see [`control`]
+    pub fn with_control_socket(mut self, path: impl Into<PathBuf>) -> Self {
+        self.control = Some(path.into());
+        self
+    }
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Shares the files under the share roots and starts serving them, registering with a
+    /// tracker of every group in the background
+    pub fn start(self) -> Result<Client<FS>, ClientError> {
+        for dir in &self.share {
+            if !dir.is_dir() {
+                return Err(ClientError::NotADirectory(dir.clone()));
+            }
+        }
+        let events = Arc::new(Events::new());
+        let file_system = self.file_system.unwrap_or_else(FS::new);
+        let mut file_ctx = FileServer::from_file_system(self.bind, file_system)?
+            .with_events(Arc::clone(&events))
+            .with_upload_limits(UploadLimits {
+                slots: self.limits.max_uploads,
+                queue: self.limits.upload_queue,
+                rate: self.limits.upload_rate,
+                peer_rate: self.limits.peer_upload_rate,
+            });
+        for dir in &self.share {
+            watch::share_dir(&file_ctx.file_system, dir)?;
+        }
+        let watcher = self
+            .watch
+            .then(|| ShareWatcher::new(&self.share))
+            .transpose()?;
+        let provider = ipv4(file_ctx.server.local_addr()?)?;
+        let dht = self.dht.as_ref().map(|_| {
+            Arc::new(match self.identity {
+                Some(id) => Dht::with_id(id, provider),
+                None => Dht::new(provider),
+            })
+        });
+        if let Some(dht) = &dht {
+            file_ctx = file_ctx.with_dht(Arc::clone(dht));
+        }
+        let peers = Arc::new(Mutex::new(Peers::new().with_events(Arc::clone(&events))));
+        let pex = Arc::new(PeerExchange::new(provider, &peers));
+        let file_ctx = Arc::new(file_ctx.with_pex(Arc::clone(&pex)));
+        let downloads = Downloads::new()
+            .with_events(Arc::clone(&events))
+            .with_limits(DownloadLimits {
+                max_running: self.limits.max_downloads,
+                rate: self.limits.download_rate,
+            });
+        let downloads = Arc::new(downloads);
+        let (tx, rx) = channel();
+
+        if let Some(path) = &self.control {
+            let control_listener = control::bind(path)?;
+            let control = Arc::new(Control::new(&file_ctx, &peers, &downloads));
+            std::thread::spawn(move || control.serve(&control_listener));
+        }
+
+        if let Some((group, interval)) = self.lan {
+            let mut lan = LanDiscovery::new(group, Ipv4Addr::UNSPECIFIED, provider, &peers, &pex)?
+                .with_interval(interval);
+            if let Some(id) = self.identity {
+                lan = lan.with_identity(id);
+            }
+            let file_ctx = Arc::clone(&file_ctx);
+            std::thread::spawn(move || lan.run(|| file_ctx.file_system.list_files()));
+        }
+        {
+            let file_ctx = Arc::clone(&file_ctx);
+            std::thread::spawn(move || pex.run(|| file_ctx.file_system.list_files()));
+        }
+
+        if let (Some(dht), Some(bootstrap)) = (dht, self.dht) {
+            let peers = Arc::clone(&peers);
+            let file_ctx = Arc::clone(&file_ctx);
+            std::thread::spawn(move || {
+                loop {
+                    if dht.known_nodes() == 0 {
+                        let known = peers.lock().unwrap().addrs();
+                        for addr in bootstrap.iter().chain(&known) {
+                            if let Err(e) = dht.bootstrap(*addr) {
+                                tracing::warn!(node = %addr, error = %e, "failed to bootstrap DHT");
+                            }
+                        }
+                    }
+                    for file in file_ctx.file_system.list_files() {
+                        dht.announce(NodeId::for_path(&file.path), provider);
+                    }
+                    std::thread::sleep(if dht.known_nodes() == 0 {
+                        Duration::from_secs(10)
+                    } else {
+                        dht::PROVIDER_TTL / 2
+                    });
+                }
+            });
+        }
+        let groups = if self.failover {
+            vec![self.trackers]
+        } else {
+            self.trackers.into_iter().map(|t| vec![t]).collect()
+        };
+        let mut links = Vec::new();
+        for group in groups.into_iter().filter(|g| !g.is_empty()) {
+            // Subscribed before connecting, so no change goes unpublished
+            let changes = file_ctx.subscribe();
+            let mut track_ctx = TrackerServerContext::new(group, &file_ctx, &peers);
+            let link = track_ctx.link();
+            links.push(Arc::clone(&link));
+            {
+                let link = Arc::clone(&link);
+                let file_ctx = Arc::clone(&file_ctx);
+                std::thread::spawn(move || link.publish_files(&file_ctx, changes));
+            }
+
+            let span = tracing::info_span!("tracker", group = links.len());
+            std::thread::spawn(move || {
+                let _entered = span.enter();
+                while !link.is_disconnected() {
+                    track_ctx.check_server_messages();
+                }
+            });
+        }
+
+        if let Some(watcher) = watcher {
+            let file_ctx = Arc::clone(&file_ctx);
+            let watch_errors = tx.clone();
+            let events = Arc::clone(&events);
+            std::thread::spawn(move || {
+                if let Err(e) = watcher.run(&file_ctx) {
+                    tracing::error!(error = %e, "watching shared files failed");
+                    events.emit(Event::Error(e.to_string()));
+                    let _ = watch_errors.send(Stop::Failed(e));
+                }
+            });
+        }
+        {
+            let file_ctx = Arc::clone(&file_ctx);
+            std::thread::spawn(move || file_ctx.serve());
+        }
+        Ok(Client {
+            file_server: file_ctx,
+            peers,
+            downloads,
+            events,
+            links,
+            control: self.control,
+            drain_timeout: self.limits.drain_timeout,
+            stopping: AtomicBool::new(false),
+            stops: Mutex::new(tx),
+            stopped: Mutex::new(rx),
+        })
+    }
+}
+
+/// Why [`Client::wait`] returns
+enum Stop {
+    Requested,
+    Failed(ClientError),
+}
+
+/// A running client, see [`ClientBuilder`]
+pub struct Client<FS: FileSystem = SimpleFileSystem> {
+    file_server: Arc<FileServer<FS>>,
+    peers: Arc<Mutex<Peers>>,
+    downloads: Arc<Downloads>,
+    events: Arc<Events>,
+    links: Vec<Arc<TrackerLink>>,
+    control: Option<PathBuf>,
+    drain_timeout: Duration,
+    stopping: AtomicBool,
+    stops: Mutex<Sender<Stop>>,
+    stopped: Mutex<Receiver<Stop>>,
+}
+
+impl<FS: FileSystem + Send + Sync + 'static> Client<FS> {
+    /// Where the file server listens
+    pub fn addr(&self) -> Result<SocketAddrV4, ClientError> {
+        ipv4(self.file_server.server.local_addr()?)
+    }
+
+    /// Every known peer, by address
+    pub fn peers(&self) -> Vec<Peer> {
+        let peers = self.peers.lock().unwrap();
+        let mut addrs: Vec<_> = peers.addrs().into_iter().collect();
+        addrs.sort();
+        addrs
+            .into_iter()
+            .filter_map(|sock| peers.get_peer(sock).cloned())
+            .collect()
+    }
+
+    /// Every file peers share, by path
+    pub fn files(&self) -> Vec<RemoteFile> {
+        self.peers.lock().unwrap().files()
+    }
+
+    /// The files we share
+    pub fn shared(&self) -> Vec<File> {
+        self.file_server.file_system.list_files()
+    }
+
+    /// Shares the file at `path`, or every file under it, until unshared. Files under it aren't
+    /// watched unless it's under a share root.
+    pub fn share(&self, path: impl Into<PathBuf>) -> Result<(), ClientError> {
+        if watch::share_path(&self.file_server.file_system, path.into())? {
+            self.file_server.files_changed();
+        }
+        Ok(())
+    }
+
+    /// Stops sharing `path`, or every file under it, returning whether anything was shared
+    pub fn unshare(&self, path: &Path) -> bool {
+        let removed = self.file_server.file_system.remove_path(path);
+        if removed {
+            self.file_server.files_changed();
+        }
+        removed
+    }
+
+    /// Downloads `path` into `dest` in the background, from the first peer sharing it, or else
+    /// the first provider the DHT knows of, when enabled
+    pub fn download(
+        &self,
+        path: impl Into<PathBuf>,
+        dest: impl Into<PathBuf>,
+    ) -> Result<Arc<Download>, ClientError> {
+        let path = path.into();
+        let mut peer = self.peers.lock().unwrap().provider(&path);
+        if peer.is_none()
+            && let Some(dht) = &self.file_server.dht
+        {
+            let own = self.addr()?;
+            let providers = dht.find_providers(NodeId::for_path(&path));
+            peer = providers.into_iter().find(|p| *p != own);
+        }
+        let peer = peer.ok_or_else(|| ClientError::NoProvider(path.clone()))?;
+        self.download_from(peer, path, dest)
+    }
+
+    /// Downloads `path` into `dest` in the background, from the file server at `peer`, or
+    /// through the tracker it's connected to when it can't be connected to
+    pub fn download_from(
+        &self,
+        peer: SocketAddrV4,
+        path: impl Into<PathBuf>,
+        dest: impl Into<PathBuf>,
+    ) -> Result<Arc<Download>, ClientError> {
+        let options = DownloadOptions {
+            relay: self.peers.lock().unwrap().relay(peer),
+            ..DownloadOptions::default()
+        };
+        self.downloads
+            .start_with(path.into(), peer, dest.into(), options)
+    }
+
+    /// Every download started, by id
+    pub fn downloads(&self) -> Vec<Arc<Download>> {
+        self.downloads.list()
+    }
+
+    /// Stops download `id`, deleting what was received. Returns `false` for unknown ids.
+    pub fn cancel_download(&self, id: u64) -> bool {
+        self.downloads.cancel(id)
+    }
+
+    /// See [`Downloads::set_priority`]
+    pub fn set_download_priority(&self, id: u64, priority: i32) -> bool {
+        self.downloads.set_priority(id, priority)
+    }
+
+    /// See [`Downloads::set_rate`]
+    pub fn set_download_rate(&self, id: u64, rate: Option<NonZeroU64>) -> bool {
+        self.downloads.set_rate(id, rate)
+    }
+
+    /// Changes how many downloads run at once and how fast they receive all together
+    pub fn set_download_limits(&self, limits: DownloadLimits) {
+        self.downloads.set_limits(limits);
+    }
+
+    /// Every upload running, by id
+    pub fn uploads(&self) -> Vec<Arc<Upload>> {
+        self.file_server.uploads.list()
+    }
+
+    /// Receives every event from now on
+    pub fn subscribe(&self) -> Receiver<Event> {
+        self.events.subscribe()
+    }
+
+    /// Calls `callback` with every event from now on, see [`events`](crate::events)
+    pub fn on_event(&self, callback: impl Fn(&Event) + Send + Sync + 'static) {
+        self.events.on_event(callback);
+    }
+
+    /// Stops taking file requests, leaves every tracker and gives the uploads running the
+    /// drain timeout of the [`Limits`] to finish. Only the first call does anything.
+    pub fn stop(&self) {
+        if self.stopping.swap(true, Ordering::Relaxed) {
+            return;
+        }
+        tracing::info!("shutting down");
+        shutdown(&self.file_server, &self.links, self.drain_timeout);
+        if let Some(path) = &self.control {
+            let _ = std::fs::remove_file(path);
+        }
+        self.events.emit(Event::Stopped);
+        let _ = self.stops.lock().unwrap().send(Stop::Requested);
+    }
+
+    /// Blocks until the client is stopped, or stops it when tracking or watching the share
+    /// roots fails and returns why
+    pub fn wait(&self) -> Result<(), ClientError> {
+        let stop = self.stopped.lock().unwrap().recv();
+        match stop.expect("the client holds a sender") {
+            Stop::Requested => Ok(()),
+            Stop::Failed(e) => {
+                self.stop();
+                Err(e)
+            }
+        }
+    }
+}
+
+/// Stops taking file requests, leaves every tracker with a `Disconnect` and gives the uploads
+/// running `timeout` to finish
+fn shutdown<FS: FileSystem>(
+    file_server: &FileServer<FS>,
+    links: &[Arc<TrackerLink>],
+    timeout: Duration,
+) {
+    file_server.stop();
+    for link in links {
+        if let Err(e) = link.disconnect() {
+            tracing::warn!(error = %e, "failed to disconnect from a tracker");
+        }
+    }
+    let start = Instant::now();
+    loop {
+        let running = file_server.uploads.list().len();
+        if running == 0 {
+            return;
+        }
+        if start.elapsed() >= timeout {
+            tracing::warn!(running, "aborting uploads");
+            return;
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    }
+}
@@ -0,0 +1,430 @@
+//! Control of a running client through JSON-RPC 2.0 on a Unix socket, one request per line.
+//!
+//! Methods:
+//! - `status`
+//! - `peers`: every known peer, its files and where it was learned from
+//! - `files`: every file peers share, with the hash of its path and the peers sharing it
+//! - `shared`, `shared.add {path}`, `shared.remove {path}`: files we share, with the SHA-256
+//!   of their contents. Adding a directory shares every file under it.
+//! - `downloads`, `download.start {path, peer?, dest, priority?, rate?, relay?}`,
+//!   `download.cancel {id}`: downloads go through `relay`, or else the tracker the peer is
+//!   connected to, when the peer can't be connected to. Only the
+//!   [last finished](crate::download::KEEP_FINISHED) are listed.
+//! - `download.set {id, priority?, rate?}`: changes a download's priority, which only
+//!   matters while it's queued, or its rate in bytes per second, `null` for unlimited
+//! - `downloads.limits {max_running?, rate?}`: changes how many downloads run at once and
+//!   how fast they receive all together, `null` for unlimited, returning the limits.
+//!   Rates of 0 are invalid params.
+//! - Local paths, those of `shared.*` and `dest`, must be absolute since the client's working
+//!   directory means nothing to callers
+//! - `uploads`: files being sent to peers
+
+use crate::dht::NodeId;
+use crate::download::{Download, DownloadLimits, DownloadOptions, DownloadState, Downloads};
+use crate::file_server::{FileServer, FileSystem};
+use crate::tracker::Peers;
+use common::File;
+use serde::Deserialize;
+use serde::de::DeserializeOwned;
+use serde_json::{Value, json};
+use std::io::{BufRead, BufReader, Write};
+use std::net::SocketAddrV4;
+use std::num::NonZeroU64;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+/// Where the control socket is created by default
+pub fn default_socket_path() -> PathBuf {
+    std::env::var_os("XDG_RUNTIME_DIR")
+        .map_or_else(std::env::temp_dir, PathBuf::from)
+        .join("p2prs.sock")
+}
+
+/// Binds the control socket at `path`, replacing a socket left behind by a client that
+/// didn't exit cleanly
+pub fn bind(path: &Path) -> Result<UnixListener, std::io::Error> {
+    if UnixStream::connect(path).is_ok() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::AddrInUse,
+            format!("another client is listening on {}", path.display()),
+        ));
+    }
+    if path.exists() {
+        std::fs::remove_file(path)?;
+    }
+    UnixListener::bind(path)
+}
+
+/// Calls `method` on the client controlled through the socket at `path`
+pub fn call(path: &Path, method: &str, params: Value) -> Result<Value, crate::ClientError> {
+    let mut stream =
+        UnixStream::connect(path).map_err(|source| crate::ClientError::ControlUnavailable {
+            path: path.to_path_buf(),
+            source,
+        })?;
+    let request = json!({ "jsonrpc": "2.0", "id": 0, "method": method, "params": params });
+    writeln!(stream, "{request}")?;
+    let mut line = String::new();
+    BufReader::new(stream).read_line(&mut line)?;
+    let mut response: Value = serde_json::from_str(&line)?;
+    match response["error"]["message"].as_str() {
+        Some(message) => Err(crate::ClientError::Control(message.to_string())),
+        None => Ok(response["result"].take()),
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum RpcError {
+    #[error("Parse error: {0}")]
+    Parse(String),
+    #[error("Method not found: {0}")]
+    MethodNotFound(String),
+    #[error("Invalid params: {0}")]
+    InvalidParams(String),
+    #[error("{0}")]
+    Failed(String),
+}
+
+impl RpcError {
+    fn code(&self) -> i64 {
+        match self {
+            RpcError::Parse(_) => -32700,
+            RpcError::MethodNotFound(_) => -32601,
+            RpcError::InvalidParams(_) => -32602,
+            RpcError::Failed(_) => -32000,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct Request {
+    #[serde(default)]
+    id: Value,
+    method: String,
+    #[serde(default)]
+    params: Value,
+}
+
+#[derive(Deserialize)]
+struct PathParams {
+    path: PathBuf,
+}
+
+#[derive(Deserialize)]
+struct StartParams {
+    path: PathBuf,
+    peer: Option<SocketAddrV4>,
+    dest: PathBuf,
+    #[serde(default)]
+    priority: i32,
+    rate: Option<NonZeroU64>,
+    relay: Option<SocketAddrV4>,
+}
+
+#[derive(Deserialize)]
+struct CancelParams {
+    id: u64,
+}
+
+/// Settings left out are kept, those given as `null` are unlimited
+#[derive(Deserialize)]
+struct SetParams {
+    id: u64,
+    priority: Option<i32>,
+    #[serde(default, deserialize_with = "present")]
+    rate: Option<Option<NonZeroU64>>,
+}
+
+#[derive(Deserialize)]
+struct LimitsParams {
+    #[serde(default, deserialize_with = "present")]
+    max_running: Option<Option<usize>>,
+    #[serde(default, deserialize_with = "present")]
+    rate: Option<Option<NonZeroU64>>,
+}
+
+/// Tells a setting given as `null` apart from one left out
+fn present<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    T::deserialize(deserializer).map(Some)
+}
+
+fn absolute(path: PathBuf) -> Result<PathBuf, RpcError> {
+    match path.is_absolute() {
+        true => Ok(path),
+        false => Err(RpcError::InvalidParams(format!(
+            "{} isn't an absolute path",
+            path.display()
+        ))),
+    }
+}
+
+fn params<T: DeserializeOwned>(params: Value) -> Result<T, RpcError> {
+    serde_json::from_value(params).map_err(|e| RpcError::InvalidParams(e.to_string()))
+}
+
+fn files_json(files: &[File]) -> Value {
+    Value::from_iter(
+        files
+            .iter()
+            .map(|f| json!({ "path": f.path, "size": f.size })),
+    )
+}
+
+fn download_json(download: &Download) -> Value {
+    let (state, error) = match download.state() {
+        DownloadState::Queued => ("queued", None),
+        DownloadState::Running => ("running", None),
+        DownloadState::Done => ("done", None),
+        DownloadState::Cancelled => ("cancelled", None),
+        DownloadState::Failed(e) => ("failed", Some(e)),
+    };
+    json!({
+        "id": download.id,
+        "path": download.path,
+        "peer": download.peer.to_string(),
+        "dest": download.dest,
+        "received": download.received.load(Ordering::Relaxed),
+        "size": download.size.load(Ordering::Relaxed),
+        "priority": download.priority(),
+        "rate": download.rate(),
+        "state": state,
+        "error": error,
+    })
+}
+
+fn limits_json(limits: DownloadLimits) -> Value {
+    json!({ "max_running": limits.max_running, "rate": limits.rate })
+}
+
+pub struct Control<FS: FileSystem> {
+    file_server: Arc<FileServer<FS>>,
+    peers: Arc<Mutex<Peers>>,
+    downloads: Arc<Downloads>,
+    started: Instant,
+}
+
+impl<FS: FileSystem + Send + Sync + 'static> Control<FS> {
+    pub fn new(
+        file_server: &Arc<FileServer<FS>>,
+        peers: &Arc<Mutex<Peers>>,
+        downloads: &Arc<Downloads>,
+    ) -> Self {
+        Self {
+            file_server: Arc::clone(file_server),
+            peers: Arc::clone(peers),
+            downloads: Arc::clone(downloads),
+            started: Instant::now(),
+        }
+    }
+
+    /// Answers every connection to `listener`, each on its own thread, forever
+    pub fn serve(self: &Arc<Self>, listener: &UnixListener) {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    tracing::warn!(error = %e, "failed to accept control connection");
+                    continue;
+                }
+            };
+            let control = Arc::clone(self);
+            std::thread::spawn(move || {
+                if let Err(e) = control.handle_connection(stream) {
+                    tracing::warn!(error = %e, "control connection failed");
+                }
+            });
+        }
+    }
+
+    fn handle_connection(&self, mut stream: UnixStream) -> Result<(), std::io::Error> {
+        let reader = BufReader::new(stream.try_clone()?);
+        for line in reader.lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            writeln!(stream, "{}", self.handle_line(&line))?;
+        }
+        Ok(())
+    }
+
+    /// The response to the JSON-RPC request in `line`
+    pub fn handle_line(&self, line: &str) -> Value {
+        let (id, result) = match serde_json::from_str::<Request>(line) {
+            Ok(request) => (request.id, self.call(&request.method, request.params)),
+            Err(e) => (Value::Null, Err(RpcError::Parse(e.to_string()))),
+        };
+        match result {
+            Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+            Err(e) => json!({
+                "jsonrpc": "2.0",
+                "id": id,
+                "error": { "code": e.code(), "message": e.to_string() },
+            }),
+        }
+    }
+
+    fn call(&self, method: &str, params_value: Value) -> Result<Value, RpcError> {
+        let fs = &self.file_server.file_system;
+        Ok(match method {
+            "status" => self.status(),
+            "peers" => self.peers(),
+            "files" => self.files(),
+            "shared" => self.shared(),
+            "shared.add" => {
+                let PathParams { path } = params(params_value)?;
+                let changed = crate::watch::share_path(fs, absolute(path)?)
+                    .map_err(|e| RpcError::Failed(e.to_string()))?;
+                if changed {
+                    self.file_server.files_changed();
+                }
+                self.shared()
+            }
+            "shared.remove" => {
+                let PathParams { path } = params(params_value)?;
+                let path = absolute(path)?;
+                if !fs.remove_path(&path) {
+                    let path = path.display();
+                    return Err(RpcError::Failed(format!("{path} isn't shared")));
+                }
+                self.file_server.files_changed();
+                self.shared()
+            }
+            "downloads" => {
+                let downloads = self.downloads.list();
+                Value::from_iter(downloads.iter().map(|d| download_json(d)))
+            }
+            "download.start" => {
+                let StartParams {
+                    path,
+                    peer,
+                    dest,
+                    priority,
+                    rate,
+                    relay,
+                } = params(params_value)?;
+                let dest = absolute(dest)?;
+                let peer = match peer {
+                    Some(peer) => peer,
+                    None => self.peers.lock().unwrap().provider(&path).ok_or_else(|| {
+                        RpcError::Failed(crate::ClientError::NoProvider(path.clone()).to_string())
+                    })?,
+                };
+                let relay = relay.or_else(|| self.peers.lock().unwrap().relay(peer));
+                let options = DownloadOptions {
+                    priority,
+                    rate,
+                    relay,
+                };
+                let download = self.downloads.start_with(path, peer, dest, options);
+                download_json(&*download.map_err(|e| RpcError::Failed(e.to_string()))?)
+            }
+            "download.cancel" => {
+                let CancelParams { id } = params(params_value)?;
+                if !self.downloads.cancel(id) {
+                    return Err(RpcError::Failed(format!("No download with id {id}")));
+                }
+                Value::Null
+            }
+            "download.set" => {
+                let SetParams { id, priority, rate } = params(params_value)?;
+                let known = priority.is_none_or(|p| self.downloads.set_priority(id, p))
+                    && rate.is_none_or(|r| self.downloads.set_rate(id, r));
+                let download = self.downloads.list().into_iter().find(|d| d.id == id);
+                match download {
+                    Some(download) if known => download_json(&download),
+                    _ => return Err(RpcError::Failed(format!("No download with id {id}"))),
+                }
+            }
+            "downloads.limits" => {
+                let params_value = match params_value {
+                    Value::Null => json!({}),
+                    value => value,
+                };
+                let LimitsParams { max_running, rate } = params(params_value)?;
+                let mut limits = self.downloads.limits();
+                limits.max_running = max_running.unwrap_or(limits.max_running);
+                limits.rate = rate.unwrap_or(limits.rate);
+                self.downloads.set_limits(limits);
+                limits_json(limits)
+            }
+            "uploads" => self.uploads(),
+            method => return Err(RpcError::MethodNotFound(method.to_string())),
+        })
+    }
+
+    fn status(&self) -> Value {
+        let running = self
+            .downloads
+            .list()
+            .iter()
+            .filter(|d| d.state() == DownloadState::Running)
+            .count();
+        let file_server = self.file_server.server.local_addr().ok();
+        json!({
+            "file_server": file_server.map(|a| a.to_string()),
+            "uptime_secs": self.started.elapsed().as_secs(),
+            "peers": self.peers.lock().unwrap().addrs().len(),
+            "shared_files": self.file_server.file_system.list_files().len(),
+            "downloads_running": running,
+            "download_limits": limits_json(self.downloads.limits()),
+            "uploads_running": self.file_server.uploads.list().len(),
+            "uploads_queued": self.file_server.uploads.queued(),
+        })
+    }
+
+    fn shared(&self) -> Value {
+        let fs = &self.file_server.file_system;
+        Value::from_iter(fs.list_files().into_iter().map(|f| {
+            let sha256 = fs
+                .sha256(&f.path)
+                .map(|hash| hash.iter().map(|b| format!("{b:02x}")).collect::<String>());
+            json!({ "path": f.path, "size": f.size, "sha256": sha256 })
+        }))
+    }
+
+    fn uploads(&self) -> Value {
+        let uploads = self.file_server.uploads.list();
+        Value::from_iter(uploads.iter().map(|u| {
+            json!({
+                "id": u.id,
+                "peer": u.peer.map(|p| p.to_string()),
+                "path": u.path,
+                "size": u.size,
+                "sent": u.sent.load(Ordering::Relaxed),
+            })
+        }))
+    }
+
+    fn peers(&self) -> Value {
+        let peers = self.peers.lock().unwrap();
+        let mut addrs: Vec<_> = peers.addrs().into_iter().collect();
+        addrs.sort();
+        Value::from_iter(addrs.into_iter().filter_map(|sock| {
+            let peer = peers.get_peer(sock)?;
+            let mut sources: Vec<_> = peers.sources(sock).map(|s| s.to_string()).collect();
+            sources.sort();
+            Some(json!({
+                "addr": sock.to_string(),
+                "files": files_json(&peer.files),
+                "sources": sources,
+            }))
+        }))
+    }
+
+    fn files(&self) -> Value {
+        let files = self.peers.lock().unwrap().files();
+        Value::from_iter(files.into_iter().map(|file| {
+            let sharing: Vec<_> = file.peers.iter().map(ToString::to_string).collect();
+            let hash = NodeId::for_path(&file.path).to_string();
+            json!({ "path": file.path, "hash": hash, "size": file.size, "peers": sharing })
+        }))
+    }
+}
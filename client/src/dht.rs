@@ -0,0 +1,318 @@
+//! Trackerless peer discovery through a Kademlia-style distributed hash table.
+//!
+//! Every node stores "peer X provides key Y" records for the keys closest to its id and finds
+//! the nodes closest to a key by iteratively asking the closest nodes it knows about. Nodes
+//! answer requests on their file server's port, so any known peer can be used to bootstrap.
+//!
+//! Keys are the hash of a file's path ([`NodeId::for_path`]), not of its contents: every peer
+//! sharing a file under the same path provides the same key, whatever the file holds.
+
+use crate::ClientError;
+pub use common::dht::NodeId;
+use common::dht::{AddProvider, Contact, FindNode, FindProviders, Message, Nodes, Providers};
+use common::{AnyMessage, CommonError, read_msg, write_msg};
+use std::collections::{HashMap, HashSet};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, TcpListener, TcpStream};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+
+/// Bucket size, and how many nodes a provider record is stored on
+pub const K: usize = 20;
+/// How many nodes are queried in each round of a lookup
+const ALPHA: usize = 3;
+/// How long a provider record is kept, providers republish every half of it
+pub const PROVIDER_TTL: Duration = Duration::from_secs(60 * 60);
+const RPC_TIMEOUT: Duration = Duration::from_secs(2);
+/// Provider records kept for a single key, the newest win
+const MAX_PROVIDERS_PER_KEY: usize = K;
+/// Provider records kept for every key together, new keys are ignored beyond
+const MAX_PROVIDER_RECORDS: usize = 10_000;
+
+/// An id unlikely to be picked by anyone else, `salt` tells apart ids picked at once
+pub fn random_id(salt: &str) -> NodeId {
+    let nanos = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |d| d.as_nanos());
+    let seed = format!("{salt} {nanos} {}", std::process::id());
+    NodeId::hash(seed.as_bytes())
+}
+
+/// Contacts grouped by the highest bit in which their id differs from ours, least recently
+/// seen first
+struct RoutingTable {
+    own: NodeId,
+    buckets: Vec<Vec<Contact>>,
+}
+
+impl RoutingTable {
+    fn new(own: NodeId) -> Self {
+        Self {
+            own,
+            buckets: vec![Vec::new(); 256],
+        }
+    }
+
+    /// Marks `contact` as seen, full buckets keep their older contacts since nodes that have
+    /// been up for long tend to stay up. Contacts without an address are ignored.
+    fn insert(&mut self, contact: Contact) {
+        // Lookup-only nodes can't be reached
+        if contact.addr.ip().is_unspecified() || contact.addr.port() == 0 {
+            return;
+        }
+        let Some(bucket) = self.own.bucket(&contact.id) else {
+            return;
+        };
+        let bucket = &mut self.buckets[bucket];
+        if let Some(pos) = bucket.iter().position(|c| c.id == contact.id) {
+            bucket.remove(pos);
+            bucket.push(contact);
+        } else if bucket.len() < K {
+            bucket.push(contact);
+        }
+    }
+
+    fn remove(&mut self, addr: SocketAddrV4) {
+        for bucket in &mut self.buckets {
+            bucket.retain(|c| c.addr != addr);
+        }
+    }
+
+    fn closest(&self, target: NodeId, n: usize) -> Vec<Contact> {
+        let mut contacts: Vec<_> = self.buckets.iter().flatten().copied().collect();
+        contacts.sort_by_key(|c| c.id.distance(&target));
+        contacts.truncate(n);
+        contacts
+    }
+
+    fn len(&self) -> usize {
+        self.buckets.iter().map(Vec::len).sum()
+    }
+}
+
+pub struct Dht {
+    contact: Contact,
+    table: Mutex<RoutingTable>,
+    providers: Mutex<HashMap<NodeId, HashMap<SocketAddrV4, Instant>>>,
+}
+
+impl Dht {
+    /// A node reachable at `addr` with a random id
+    pub fn new(addr: SocketAddrV4) -> Self {
+        Self::with_id(random_id(&addr.to_string()), addr)
+    }
+
+    /// A node that only looks up, without an address so others don't add it to their routing
+    /// tables
+    pub fn lookup_only() -> Self {
+        Self::new(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0))
+    }
+
+    pub fn with_id(id: NodeId, addr: SocketAddrV4) -> Self {
+        Self {
+            contact: Contact { id, addr },
+            table: Mutex::new(RoutingTable::new(id)),
+            providers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn known_nodes(&self) -> usize {
+        self.table.lock().unwrap().len()
+    }
+
+    /// Answers requests arriving on `listener` forever
+    pub fn serve(&self, listener: &TcpListener) {
+        for stream in listener.incoming() {
+            let answered = stream
+                .map_err(CommonError::from)
+                .and_then(|mut stream| match read_msg(&mut stream)? {
+                    AnyMessage::Dht(msg) => self.respond(stream, msg),
+                    _ => Ok(()),
+                });
+            if let Err(e) = answered {
+                tracing::warn!(error = %e, "DHT request failed");
+            }
+        }
+    }
+
+    /// Answers a request read from `stream`
+    pub fn respond(&self, mut stream: TcpStream, msg: Message) -> Result<(), CommonError> {
+        self.table.lock().unwrap().insert(msg.sender());
+        let sender = self.contact;
+        let reply = match msg {
+            Message::FindNode(FindNode { target, .. }) => {
+                let nodes = self.table.lock().unwrap().closest(target, K);
+                Message::from(Nodes { sender, nodes })
+            }
+            Message::FindProviders(FindProviders { key, .. }) => {
+                let providers = self.local_providers(key);
+                let nodes = self.table.lock().unwrap().closest(key, K);
+                Message::from(Providers {
+                    sender,
+                    providers,
+                    nodes,
+                })
+            }
+            Message::AddProvider(AddProvider { key, provider, .. }) => {
+                self.store_provider(key, provider);
+                Message::from(Nodes {
+                    sender,
+                    nodes: Vec::new(),
+                })
+            }
+            Message::Nodes(_) | Message::Providers(_) => return Ok(()),
+        };
+        stream.set_write_timeout(Some(RPC_TIMEOUT))?;
+        write_msg(&mut stream, &reply)
+    }
+
+    fn store_provider(&self, key: NodeId, provider: SocketAddrV4) {
+        let mut providers = self.providers.lock().unwrap();
+        let known = providers
+            .get(&key)
+            .is_some_and(|records| records.contains_key(&provider));
+        if !known && providers.values().map(HashMap::len).sum::<usize>() >= MAX_PROVIDER_RECORDS {
+            let now = Instant::now();
+            providers.retain(|_, records| {
+                records.retain(|_, expires| *expires > now);
+                !records.is_empty()
+            });
+            if providers.values().map(HashMap::len).sum::<usize>() >= MAX_PROVIDER_RECORDS {
+                return tracing::debug!(%key, %provider, "too many provider records, ignoring");
+            }
+        }
+        let records = providers.entry(key).or_default();
+        if !known && records.len() >= MAX_PROVIDERS_PER_KEY {
+            let oldest = records.iter().min_by_key(|(_, expires)| **expires);
+            if let Some((&oldest, _)) = oldest {
+                records.remove(&oldest);
+            }
+        }
+        records.insert(provider, Instant::now() + PROVIDER_TTL);
+    }
+
+    fn local_providers(&self, key: NodeId) -> Vec<SocketAddrV4> {
+        let mut providers = self.providers.lock().unwrap();
+        let Some(records) = providers.get_mut(&key) else {
+            return Vec::new();
+        };
+        let now = Instant::now();
+        records.retain(|_, expires| *expires > now);
+        records.keys().copied().collect()
+    }
+
+    /// Sends `msg` to `addr` and waits for the answer, keeping the routing table up to date
+    fn rpc(&self, addr: SocketAddrV4, msg: Message) -> Result<Message, ClientError> {
+        let answer = (|| {
+            let mut stream = TcpStream::connect_timeout(&SocketAddr::V4(addr), RPC_TIMEOUT)?;
+            stream.set_read_timeout(Some(RPC_TIMEOUT))?;
+            stream.set_write_timeout(Some(RPC_TIMEOUT))?;
+            write_msg(&mut stream, &msg)?;
+            match read_msg(&mut stream).map_err(CommonError::from)? {
+                AnyMessage::Dht(answer) => Ok(answer),
+                m => Err(ClientError::UnexpectedMessage(Box::new(m))),
+            }
+        })();
+        let mut table = self.table.lock().unwrap();
+        match &answer {
+            Ok(answer) => table.insert(answer.sender()),
+            Err(_) => table.remove(addr),
+        }
+        answer
+    }
+
+    /// Joins the network through the node at `addr`
+    pub fn bootstrap(&self, addr: SocketAddrV4) -> Result<(), ClientError> {
+        let sender = self.contact;
+        if let Message::Nodes(Nodes { nodes, .. }) = self.rpc(
+            addr,
+            FindNode {
+                sender,
+                target: sender.id,
+            }
+            .into(),
+        )? {
+            let mut table = self.table.lock().unwrap();
+            nodes.into_iter().for_each(|c| table.insert(c));
+        }
+        self.find_nodes(sender.id);
+        Ok(())
+    }
+
+    /// Iteratively looks for the nodes closest to `target`. When `providers` is set, stops as
+    /// soon as a node knows of providers for `target` and returns them.
+    fn lookup(&self, target: NodeId, providers: bool) -> (Vec<Contact>, HashSet<SocketAddrV4>) {
+        let sender = self.contact;
+        let mut shortlist = self.table.lock().unwrap().closest(target, K);
+        let mut queried = HashSet::new();
+        let mut found = HashSet::new();
+        loop {
+            let round: Vec<_> = shortlist
+                .iter()
+                .filter(|c| !queried.contains(&c.id))
+                .take(ALPHA)
+                .copied()
+                .collect();
+            if round.is_empty() {
+                break;
+            }
+            for contact in round {
+                queried.insert(contact.id);
+                let request = if providers {
+                    FindProviders {
+                        sender,
+                        key: target,
+                    }
+                    .into()
+                } else {
+                    FindNode { sender, target }.into()
+                };
+                match self.rpc(contact.addr, request) {
+                    Ok(Message::Nodes(Nodes { nodes, .. })) => shortlist.extend(nodes),
+                    Ok(Message::Providers(p)) => {
+                        found.extend(p.providers);
+                        shortlist.extend(p.nodes);
+                    }
+                    Ok(_) => {}
+                    Err(_) => shortlist.retain(|c| c.id != contact.id),
+                }
+            }
+            shortlist.retain(|c| c.id != sender.id);
+            shortlist.sort_by_key(|c| c.id.distance(&target));
+            shortlist.dedup_by_key(|c| c.id);
+            shortlist.truncate(K);
+            if providers && !found.is_empty() {
+                break;
+            }
+        }
+        (shortlist, found)
+    }
+
+    pub fn find_nodes(&self, target: NodeId) -> Vec<Contact> {
+        self.lookup(target, false).0
+    }
+
+    /// Stores that the file server at `provider` has `key` on the nodes closest to it,
+    /// returning on how many nodes it was stored
+    pub fn announce(&self, key: NodeId, provider: SocketAddrV4) -> usize {
+        self.store_provider(key, provider);
+        let sender = self.contact;
+        self.find_nodes(key)
+            .into_iter()
+            .filter(|c| {
+                let msg = AddProvider {
+                    sender,
+                    key,
+                    provider,
+                };
+                self.rpc(c.addr, msg.into()).is_ok()
+            })
+            .count()
+    }
+
+    /// File servers known to provide `key`, see [`NodeId::for_path`]
+    pub fn find_providers(&self, key: NodeId) -> Vec<SocketAddrV4> {
+        let mut providers: HashSet<_> = self.local_providers(key).into_iter().collect();
+        providers.extend(self.lookup(key, true).1);
+        providers.into_iter().collect()
+    }
+}
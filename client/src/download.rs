@@ -0,0 +1,483 @@
+//! Downloads running in the background, which can be followed and cancelled while they run.
+//!
+//! Downloads wait in a queue while the maximum number of them is running, the one with the
+//! highest priority starting first, then the oldest. How fast they receive can be limited,
+//! all together and each on its own. Priorities and limits can be changed while they run.
+
+use crate::ClientError;
+use crate::events::{Event, Events};
+use common::rate::RateLimit;
+use common::{AnyMessage, CommonError, client, read_msg, server, write_msg};
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::net::{Shutdown, SocketAddrV4, TcpStream};
+use std::num::NonZeroU64;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicI32, AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+/// How often [`Event::DownloadProgress`] is sent while a download runs
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Longest sleep while throttled before checking whether the download was cancelled
+const THROTTLE_STEP: Duration = Duration::from_millis(50);
+
+/// Finished downloads kept listed, the oldest are forgotten beyond
+pub const KEEP_FINISHED: usize = 100;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DownloadState {
+    /// Waiting for other downloads to finish
+    Queued,
+    Running,
+    Done,
+    Cancelled,
+    Failed(String),
+}
+
+#[derive(Debug)]
+pub struct Download {
+    pub id: u64,
+    pub path: PathBuf,
+    pub peer: SocketAddrV4,
+    /// Tracker relaying to `peer` when it can't be connected to directly
+    pub relay: Option<SocketAddrV4>,
+    /// Where the file is written to
+    pub dest: PathBuf,
+    /// Bytes received so far
+    pub received: AtomicU64,
+    /// Size of the file, 0 until the peer starts sending it
+    pub size: AtomicU64,
+    pub state: Mutex<DownloadState>,
+    /// Among queued downloads, those with the highest priority start first
+    priority: AtomicI32,
+    /// Bytes per second this download receives at most
+    rate: Mutex<Option<RateLimit>>,
+    cancel: AtomicBool,
+    /// Shut down to cancel the download while it waits for data
+    conn: Mutex<Option<TcpStream>>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DownloadOptions {
+    pub priority: i32,
+    /// Bytes per second received, unlimited when `None`
+    pub rate: Option<NonZeroU64>,
+    /// Tracker to go through when the peer can't be connected to directly
+    pub relay: Option<SocketAddrV4>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DownloadLimits {
+    /// Downloads running at once, unlimited when `None`
+    pub max_running: Option<usize>,
+    /// Bytes per second received by every download together
+    pub rate: Option<NonZeroU64>,
+}
+
+/// Asks the file server at `peer` for `path`, through the tracker at `relay` if given, and
+/// returns the connection the file is sent over
+pub fn request_file(
+    peer: SocketAddrV4,
+    relay: Option<SocketAddrV4>,
+    path: PathBuf,
+) -> Result<TcpStream, ClientError> {
+    let mut stream = match relay {
+        Some(relay) => {
+            let mut stream = TcpStream::connect(relay)?;
+            let connect = client::RelayConnect { target: peer };
+            write_msg(&mut stream, &client::Message::from(connect))?;
+            stream
+        }
+        None => TcpStream::connect(peer)?,
+    };
+    let request = client::RequestFile { file: path };
+    write_msg(&mut stream, &client::Message::from(request))?;
+    Ok(stream)
+}
+
+/// Waits for the answer of the file server at `peer` to a request for `path`, which can be
+/// queued for a while, returning the size of the file about to be sent
+pub fn read_answer(
+    stream: &mut TcpStream,
+    peer: SocketAddrV4,
+    path: PathBuf,
+) -> Result<u64, ClientError> {
+    match read_msg(stream) {
+        Ok(AnyMessage::Client(client::Message::SendingFile(sending))) => Ok(sending.size),
+        Ok(AnyMessage::Client(client::Message::Busy(_))) => Err(ClientError::Busy(peer)),
+        Ok(AnyMessage::Server(server::Message::Refused(refused))) => {
+            tracing::debug!(%peer, path = %path.display(), reason = refused.reason, "refused");
+            Err(ClientError::Refused { peer, path })
+        }
+        Ok(m) => Err(ClientError::UnexpectedMessage(Box::new(m))),
+        Err(e) => Err(CommonError::from(e).into()),
+    }
+}
+
+impl Download {
+    pub fn state(&self) -> DownloadState {
+        self.state.lock().unwrap().clone()
+    }
+
+    /// Whether the download is done, cancelled or failed
+    pub fn finished(&self) -> bool {
+        !matches!(self.state(), DownloadState::Queued | DownloadState::Running)
+    }
+
+    pub fn priority(&self) -> i32 {
+        self.priority.load(Ordering::Relaxed)
+    }
+
+    /// Bytes per second this download receives at most
+    pub fn rate(&self) -> Option<NonZeroU64> {
+        limit_rate(&self.rate)
+    }
+
+    fn progress(&self, events: &Events) {
+        events.emit(Event::DownloadProgress {
+            id: self.id,
+            path: self.path.clone(),
+            received: self.received.load(Ordering::Relaxed),
+            state: self.state(),
+        });
+    }
+
+    fn run(&self, queue: &Queue, events: Option<&Events>) -> Result<DownloadState, ClientError> {
+        let mut stream = match (request_file(self.peer, None, self.path.clone()), self.relay) {
+            (Ok(stream), _) => stream,
+            (Err(e), Some(relay)) => {
+                tracing::info!(%relay, error = %e, "peer unreachable, going through the relay");
+                request_file(self.peer, Some(relay), self.path.clone())?
+            }
+            (Err(e), None) => return Err(e),
+        };
+        {
+            let mut conn = self.conn.lock().unwrap();
+            // Cancelled while connecting
+            if self.cancel.load(Ordering::Relaxed) {
+                stream.shutdown(Shutdown::Both)?;
+            }
+            *conn = Some(stream.try_clone()?);
+        }
+        let size = read_answer(&mut stream, self.peer, self.path.clone());
+        if self.cancel.load(Ordering::Relaxed) {
+            return Ok(DownloadState::Cancelled);
+        }
+        let size = size?;
+        self.size.store(size, Ordering::Relaxed);
+        let mut file = std::fs::File::create(&self.dest)?;
+        let mut buf = [0u8; 16 * 1024];
+        let mut reported = Instant::now();
+        loop {
+            let read = stream.read(&mut buf);
+            if self.cancel.load(Ordering::Relaxed) {
+                drop(file);
+                std::fs::remove_file(&self.dest)?;
+                return Ok(DownloadState::Cancelled);
+            }
+            let received = self.received.load(Ordering::Relaxed);
+            let n = match read {
+                Ok(0) if received == size => return Ok(DownloadState::Done),
+                Ok(0) => Err(ClientError::ShortTransfer {
+                    peer: self.peer,
+                    path: self.path.clone(),
+                    received,
+                    size,
+                }),
+                Ok(n) if received + n as u64 > size => Err(ClientError::Oversize {
+                    peer: self.peer,
+                    path: self.path.clone(),
+                    size,
+                }),
+                Ok(n) => file
+                    .write_all(&buf[..n])
+                    .map(|()| n)
+                    .map_err(ClientError::from),
+                Err(e) => Err(e.into()),
+            };
+            // Only complete files are kept
+            let n = match n {
+                Ok(n) => n,
+                Err(e) => {
+                    drop(file);
+                    std::fs::remove_file(&self.dest)?;
+                    return Err(e);
+                }
+            };
+            self.received.fetch_add(n as u64, Ordering::Relaxed);
+            if let Some(events) = events.filter(|_| reported.elapsed() >= PROGRESS_INTERVAL) {
+                self.progress(events);
+                reported = Instant::now();
+            }
+            self.throttle(queue, n as u64);
+        }
+    }
+
+    /// Waits for as long as the rate limits require after receiving `n` bytes, or until
+    /// cancelled
+    fn throttle(&self, queue: &Queue, n: u64) {
+        let take = |rate: &Mutex<Option<RateLimit>>| {
+            rate.lock()
+                .unwrap()
+                .as_mut()
+                .map_or(Duration::ZERO, |rate| rate.take(n))
+        };
+        let wait = take(&queue.rate).max(take(&self.rate));
+        let until = Instant::now() + wait;
+        while !self.cancel.load(Ordering::Relaxed) {
+            let left = until.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return;
+            }
+            std::thread::sleep(left.min(THROTTLE_STEP));
+        }
+    }
+}
+
+/// Downloads waiting for their turn, and the limits of those running
+#[derive(Default)]
+struct Queue {
+    state: Mutex<QueueState>,
+    changed: Condvar,
+    rate: Mutex<Option<RateLimit>>,
+}
+
+#[derive(Default)]
+struct QueueState {
+    max_running: Option<usize>,
+    running: usize,
+    queued: Vec<Arc<Download>>,
+}
+
+impl QueueState {
+    /// The queued download to start next
+    fn next(&self) -> Option<u64> {
+        let next = self
+            .queued
+            .iter()
+            .max_by_key(|d| (d.priority(), Reverse(d.id)));
+        next.map(|d| d.id)
+    }
+
+    fn may_start(&self, id: u64) -> bool {
+        self.max_running.is_none_or(|max| self.running < max) && self.next() == Some(id)
+    }
+}
+
+impl Queue {
+    /// Waits for `download`'s turn, returning `false` when it was cancelled while queued
+    fn wait_for_turn(&self, download: &Download) -> bool {
+        let state = self.state.lock().unwrap();
+        let mut state = self
+            .changed
+            .wait_while(state, |s| {
+                !download.cancel.load(Ordering::Relaxed) && !s.may_start(download.id)
+            })
+            .unwrap();
+        state.queued.retain(|d| d.id != download.id);
+        // The next download may start too
+        self.changed.notify_all();
+        if download.cancel.load(Ordering::Relaxed) {
+            return false;
+        }
+        state.running += 1;
+        *download.state.lock().unwrap() = DownloadState::Running;
+        true
+    }
+
+    fn finished(&self) {
+        self.state.lock().unwrap().running -= 1;
+        self.changed.notify_all();
+    }
+}
+
+/// Every download running or queued, and the [`KEEP_FINISHED`] last finished, by id
+#[derive(Default)]
+pub struct Downloads {
+    next_id: AtomicU64,
+    downloads: Mutex<HashMap<u64, Arc<Download>>>,
+    queue: Arc<Queue>,
+    events: Option<Arc<Events>>,
+}
+
+impl Downloads {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Tells `events` about the progress of downloads
+    pub fn with_events(mut self, events: Arc<Events>) -> Self {
+        self.events = Some(events);
+        self
+    }
+
+    pub fn with_limits(self, limits: DownloadLimits) -> Self {
+        self.set_limits(limits);
+        self
+    }
+
+    pub fn limits(&self) -> DownloadLimits {
+        DownloadLimits {
+            max_running: self.queue.state.lock().unwrap().max_running,
+            rate: limit_rate(&self.queue.rate),
+        }
+    }
+
+    /// Changes how many downloads run at once and how fast they receive all together, queued
+    /// downloads start right away if they may
+    pub fn set_limits(&self, limits: DownloadLimits) {
+        tracing::info!(max_running = ?limits.max_running, rate = ?limits.rate, "download limits changed");
+        *self.queue.rate.lock().unwrap() = limits.rate.map(rate_limit);
+        self.queue.state.lock().unwrap().max_running = limits.max_running;
+        self.queue.changed.notify_all();
+    }
+
+    /// Downloads `path` from the file server at `peer` into `dest` in the background
+    pub fn start(
+        &self,
+        path: PathBuf,
+        peer: SocketAddrV4,
+        dest: PathBuf,
+    ) -> Result<Arc<Download>, ClientError> {
+        self.start_with(path, peer, dest, DownloadOptions::default())
+    }
+
+    /// Downloads `path` like [`start`](Self::start), with a priority and rate limit of its own
+    pub fn start_with(
+        &self,
+        path: PathBuf,
+        peer: SocketAddrV4,
+        dest: PathBuf,
+        options: DownloadOptions,
+    ) -> Result<Arc<Download>, ClientError> {
+        let download = Arc::new(Download {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            path,
+            peer,
+            relay: options.relay,
+            dest,
+            received: AtomicU64::new(0),
+            size: AtomicU64::new(0),
+            state: Mutex::new(DownloadState::Queued),
+            priority: AtomicI32::new(options.priority),
+            rate: Mutex::new(options.rate.map(rate_limit)),
+            cancel: AtomicBool::new(false),
+            conn: Mutex::new(None),
+        });
+        {
+            let mut downloads = self.downloads.lock().unwrap();
+            let mut finished: Vec<_> = downloads
+                .values()
+                .filter(|d| d.finished())
+                .map(|d| d.id)
+                .collect();
+            if finished.len() > KEEP_FINISHED {
+                finished.sort_unstable();
+                for id in &finished[..finished.len() - KEEP_FINISHED] {
+                    downloads.remove(id);
+                }
+            }
+            downloads.insert(download.id, Arc::clone(&download));
+        }
+        let queue = Arc::clone(&self.queue);
+        queue
+            .state
+            .lock()
+            .unwrap()
+            .queued
+            .push(Arc::clone(&download));
+        let running = Arc::clone(&download);
+        let events = self.events.clone();
+        let span = tracing::info_span!(
+            "download",
+            id = download.id,
+            peer = %download.peer,
+            path = %download.path.display(),
+        );
+        std::thread::spawn(move || {
+            let _entered = span.enter();
+            let started = queue.wait_for_turn(&running);
+            if started {
+                tracing::info!("download started");
+                let state = running
+                    .run(&queue, events.as_deref())
+                    .unwrap_or_else(|e| DownloadState::Failed(e.to_string()));
+                let received = running.received.load(Ordering::Relaxed);
+                tracing::info!(?state, received, "download finished");
+                *running.state.lock().unwrap() = state;
+            } else {
+                tracing::info!("download cancelled while queued");
+                *running.state.lock().unwrap() = DownloadState::Cancelled;
+            }
+            if let Some(events) = &events {
+                running.progress(events);
+            }
+            // Only once told about, so downloads are told about in the order they finish
+            if started {
+                queue.finished();
+            }
+        });
+        Ok(download)
+    }
+
+    /// Stops download `id`, deleting what was received. Returns `false` for unknown ids.
+    pub fn cancel(&self, id: u64) -> bool {
+        match self.downloads.lock().unwrap().get(&id) {
+            Some(download) => {
+                download.cancel.store(true, Ordering::Relaxed);
+                if let Some(conn) = download.conn.lock().unwrap().as_ref() {
+                    let _ = conn.shutdown(Shutdown::Both);
+                }
+                self.queue.changed.notify_all();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Changes the priority of download `id`, which only matters while it's queued. Returns
+    /// `false` for unknown ids.
+    pub fn set_priority(&self, id: u64, priority: i32) -> bool {
+        match self.downloads.lock().unwrap().get(&id) {
+            Some(download) => {
+                download.priority.store(priority, Ordering::Relaxed);
+                self.queue.changed.notify_all();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Changes how fast download `id` receives, unlimited when `None`. Returns `false` for
+    /// unknown ids.
+    pub fn set_rate(&self, id: u64, rate: Option<NonZeroU64>) -> bool {
+        match self.downloads.lock().unwrap().get(&id) {
+            Some(download) => {
+                *download.rate.lock().unwrap() = rate.map(rate_limit);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn list(&self) -> Vec<Arc<Download>> {
+        let mut downloads: Vec<_> = self.downloads.lock().unwrap().values().cloned().collect();
+        downloads.sort_by_key(|d| d.id);
+        downloads
+    }
+}
+
+fn rate_limit(rate: NonZeroU64) -> RateLimit {
+    RateLimit::new(rate.get())
+}
+
+/// The rate of a limit made by [`rate_limit`]
+fn limit_rate(limit: &Mutex<Option<RateLimit>>) -> Option<NonZeroU64> {
+    let limit = limit.lock().unwrap();
+    limit
+        .as_ref()
+        .and_then(|limit| NonZeroU64::new(limit.rate()))
+}
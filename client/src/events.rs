@@ -0,0 +1,112 @@
+//! Events a running [`Client`](crate::Client) tells its subscribers about, through channels or
+//! callbacks.
+//!
+//! Events are delivered in the order they happened by a thread of their own, so callbacks can
+//! use the client but delay every event after theirs until they return.
+
+use crate::download::DownloadState;
+use crate::tracker::Peer;
+use std::net::{SocketAddr, SocketAddrV4};
+use std::path::PathBuf;
+use std::sync::mpsc::{Receiver, Sender, channel};
+use std::sync::{Arc, Mutex};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    /// A peer no source knew about became known
+    PeerJoined(Peer),
+    /// The files of a known peer changed
+    PeerUpdated(Peer),
+    /// No source knows about the peer anymore
+    PeerLeft(SocketAddrV4),
+    /// `peer` started sharing `path`
+    FileAvailable {
+        path: PathBuf,
+        size: u64,
+        peer: SocketAddrV4,
+    },
+    /// Sent while a download runs and once more when it's over
+    DownloadProgress {
+        id: u64,
+        path: PathBuf,
+        received: u64,
+        state: DownloadState,
+    },
+    UploadStarted {
+        id: u64,
+        /// `None` for uploads relayed through a tracker
+        peer: Option<SocketAddr>,
+        path: PathBuf,
+        size: u64,
+    },
+    /// The upload ended, `sent` is below the size when it was cut short
+    UploadFinished { id: u64, path: PathBuf, sent: u64 },
+    /// Files were shared or stopped being shared, or their contents changed
+    SharedFilesChanged,
+    /// Something failed in the background
+    Error(String),
+    /// The client stopped, no event follows
+    Stopped,
+}
+
+type Callback = Arc<dyn Fn(&Event) + Send + Sync>;
+
+#[derive(Default)]
+struct Subscribers {
+    channels: Vec<Sender<Event>>,
+    callbacks: Vec<Callback>,
+}
+
+/// Everyone subscribed to events, subscribers whose receiver was dropped are dropped on the
+/// next event
+pub struct Events {
+    queue: Sender<Event>,
+    subscribers: Arc<Mutex<Subscribers>>,
+}
+
+impl Default for Events {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Events {
+    /// Starts the thread delivering events, which stops once this is dropped
+    pub fn new() -> Self {
+        let (queue, queued) = channel::<Event>();
+        let subscribers = Arc::new(Mutex::new(Subscribers::default()));
+        let delivering = Arc::clone(&subscribers);
+        std::thread::spawn(move || {
+            for event in queued {
+                let callbacks = {
+                    let mut subscribers = delivering.lock().unwrap();
+                    subscribers
+                        .channels
+                        .retain(|subscriber| subscriber.send(event.clone()).is_ok());
+                    subscribers.callbacks.clone()
+                };
+                for callback in callbacks {
+                    callback(&event);
+                }
+            }
+        });
+        Self { queue, subscribers }
+    }
+
+    /// Receives every event from now on
+    pub fn subscribe(&self) -> Receiver<Event> {
+        let (tx, rx) = channel();
+        self.subscribers.lock().unwrap().channels.push(tx);
+        rx
+    }
+
+    /// Calls `callback` with every event from now on
+    pub fn on_event(&self, callback: impl Fn(&Event) + Send + Sync + 'static) {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        subscribers.callbacks.push(Arc::new(callback));
+    }
+
+    pub fn emit(&self, event: Event) {
+        let _ = self.queue.send(event);
+    }
+}
@@ -1,35 +1,185 @@
+use crate::dht::Dht;
+use crate::events::{Event, Events};
+use crate::pex::PeerExchange;
+use crate::upload::{ActiveUpload, UploadLimits, Uploads};
 use common::*;
-use std::io::Write;
-use std::net::{SocketAddrV4, TcpListener, TcpStream};
-use std::path::PathBuf;
+use sha2::{Digest, Sha256};
+use std::io::{Read, Write};
+use std::net::{SocketAddr, SocketAddrV4, TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{Receiver, Sender, channel};
+use std::sync::{Arc, Mutex};
 use std::thread::{Scope, ScopedJoinHandle};
+use std::time::Duration;
+
+/// Bytes of a file sent between reports of an upload's progress
+const SEND_CHUNK: u64 = 256 * 1024;
+/// How long a connection gets to send its request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// Connections served at once, uploads included, those beyond are answered with
+/// [`Busy`](client::Busy)
+const MAX_CONNECTIONS: usize = 256;
 
 impl<FS: FileSystem> FileServer<FS> {
     pub fn new(addr: SocketAddrV4) -> Result<Self, std::io::Error> {
-        let server = TcpListener::bind(addr)?;
-        server.set_nonblocking(true)?;
+        Self::from_file_system(addr, FS::new())
+    }
+    /// Serves the files of `file_system` on `addr`
+    pub fn from_file_system(addr: SocketAddrV4, file_system: FS) -> Result<Self, std::io::Error> {
         Ok(Self {
-            server,
-            file_system: FS::new(),
+            server: TcpListener::bind(addr)?,
+            file_system,
+            dht: None,
+            pex: None,
+            changes: Mutex::new(Vec::new()),
+            uploads: Uploads::new(),
+            events: None,
+            stopped: AtomicBool::new(false),
+            connections: AtomicUsize::new(0),
         })
     }
-    pub fn check_serve(&self) -> Option<Result<FS::FileRecord<'_>, CommonError>> {
-        match self.server.accept() {
-            Ok((mut stream, _)) => match read_msg(&mut stream) {
-                Ok(AnyMessage::Client(client::Message::RequestFile(f))) => {
-                    Some(Ok(self.file_system.make_request(stream, f.file)))
+    /// Also answers DHT requests arriving on the file server's port
+    pub fn with_dht(mut self, dht: Arc<Dht>) -> Self {
+        self.dht = Some(dht);
+        self
+    }
+    /// Also answers peer exchanges arriving on the file server's port
+    pub fn with_pex(mut self, pex: Arc<PeerExchange>) -> Self {
+        self.pex = Some(pex);
+        self
+    }
+    /// Tells `events` about uploads, changes to the shared files and requests that failed
+    pub fn with_events(mut self, events: Arc<Events>) -> Self {
+        self.uploads = self.uploads.with_events(Arc::clone(&events));
+        self.events = Some(events);
+        self
+    }
+    /// Limits how many uploads run at once, how many requests wait for them and how fast
+    /// they send
+    pub fn with_upload_limits(mut self, limits: UploadLimits) -> Self {
+        self.uploads = self.uploads.with_limits(limits);
+        self
+    }
+    pub fn files_changed(&self) {
+        self.changes
+            .lock()
+            .unwrap()
+            .retain(|changes| changes.send(()).is_ok());
+        if let Some(events) = &self.events {
+            events.emit(Event::SharedFilesChanged);
+        }
+    }
+    /// Receives a message every time the shared files change
+    pub fn subscribe(&self) -> Receiver<()> {
+        let (tx, rx) = channel();
+        self.changes.lock().unwrap().push(tx);
+        rx
+    }
+    /// Stops taking new requests, the uploads running carry on
+    pub fn stop(&self) {
+        self.stopped.store(true, Ordering::Relaxed);
+    }
+    fn handle(
+        &self,
+        mut stream: TcpStream,
+        peer: Option<SocketAddr>,
+    ) -> Result<Option<FS::FileRecord<'_>>, CommonError> {
+        stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+        match read_msg(&mut stream)? {
+            AnyMessage::Client(client::Message::RequestFile(f)) => {
+                let files = self.file_system.list_files();
+                let Some(file) = files.into_iter().find(|file| file.path == f.file) else {
+                    tracing::warn!(path = %f.file.display(), "refusing to send a file that isn't shared");
+                    let reason = "this file isn't shared".to_string();
+                    write_msg(
+                        &mut stream,
+                        &server::Message::from(server::Refused { reason }),
+                    )?;
+                    return Ok(None);
+                };
+                let Some(slot) = self.uploads.slot() else {
+                    tracing::warn!(path = %f.file.display(), "too many uploads running and queued, answering busy");
+                    write_msg(&mut stream, &client::Message::from(client::Busy))?;
+                    return Ok(None);
+                };
+                if self.stopped.load(Ordering::Relaxed) {
+                    tracing::info!(path = %f.file.display(), "stopped while waiting for a slot, answering busy");
+                    write_msg(&mut stream, &client::Message::from(client::Busy))?;
+                    return Ok(None);
+                }
+                let sending = client::SendingFile { size: file.size };
+                write_msg(&mut stream, &client::Message::from(sending))?;
+                let upload = self.uploads.start(slot, peer, file.path, file.size);
+                Ok(Some(self.file_system.make_request(stream, f.file, upload)))
+            }
+            AnyMessage::Client(client::Message::PeerExchange(m)) => {
+                if let Some(pex) = &self.pex {
+                    pex.respond(stream, m, self.file_system.list_files())?;
                 }
-                Ok(..) => None,
-                Err(e) => Some(Err(CommonError::Deserialize(e))),
-            },
-            Err(e) => Some(Err(CommonError::IO(e))),
+                Ok(None)
+            }
+            AnyMessage::Dht(m) => {
+                if let Some(dht) = &self.dht {
+                    dht.respond(stream, m)?;
+                }
+                Ok(None)
+            }
+            _ => Ok(None),
         }
     }
 }
 
+impl<FS: FileSystem + Send + Sync + 'static> FileServer<FS> {
+    /// Accepts connections until stopped, serving each on its own thread
+    pub fn serve(self: &Arc<Self>) {
+        for accepted in self.server.incoming() {
+            if self.stopped.load(Ordering::Relaxed) {
+                return;
+            }
+            match accepted.and_then(|stream| Ok((stream.peer_addr()?, stream))) {
+                Ok((peer, stream)) => self.serve_connection(stream, Some(peer)),
+                Err(e) => tracing::warn!(error = %e, "failed to accept a connection"),
+            }
+        }
+    }
+    /// Serves `stream`, opened to a relay for a peer that can't reach us directly, like an
+    /// accepted connection
+    pub fn accept_relayed(self: &Arc<Self>, stream: TcpStream) {
+        if !self.stopped.load(Ordering::Relaxed) {
+            self.serve_connection(stream, None);
+        }
+    }
+    fn serve_connection(self: &Arc<Self>, mut stream: TcpStream, peer: Option<SocketAddr>) {
+        if self.connections.fetch_add(1, Ordering::Relaxed) >= MAX_CONNECTIONS {
+            self.connections.fetch_sub(1, Ordering::Relaxed);
+            tracing::warn!(peer = ?peer, "too many connections, answering busy");
+            let _ = write_msg(&mut stream, &client::Message::from(client::Busy));
+            return;
+        }
+        let server = Arc::clone(self);
+        let span = tracing::debug_span!("request", peer = ?peer);
+        std::thread::spawn(move || {
+            let _entered = span.enter();
+            match server.handle(stream, peer) {
+                Ok(Some(request)) => request.send_file(),
+                Ok(None) => {}
+                Err(e) => {
+                    tracing::warn!(error = %e, "failed to serve a request");
+                    if let Some(events) = &server.events {
+                        events.emit(Event::Error(format!("Failed to serve a request: {e}")));
+                    }
+                }
+            }
+            server.connections.fetch_sub(1, Ordering::Relaxed);
+        });
+    }
+}
+
 pub trait FSRequest<'srv, FS>: Sized + 'srv {
     fn send_file(self);
 
+    #[allow(dead_code)]
     fn send_file_scoped_thread<'env, 'scope>(
         self,
         s: &'scope Scope<'scope, 'env>,
@@ -53,12 +203,34 @@ pub trait FileSystem: Sized {
         Self: 's;
     fn new() -> Self;
     fn list_files(&self) -> Vec<File>;
-    fn make_request<'s>(&self, stream: TcpStream, path: PathBuf) -> Self::FileRecord<'s>;
+    /// Starts sharing the file at `path`, or takes in its new contents, returning whether it
+    /// wasn't shared or its contents changed
+    fn add_path(&self, path: PathBuf) -> Result<bool, std::io::Error>;
+    /// Stops sharing `path`, or every file under it, returning whether anything was shared
+    fn remove_path(&self, path: &Path) -> bool;
+    /// SHA-256 of the contents of the shared file at `path`
+    fn sha256(&self, path: &Path) -> Option<[u8; 32]>;
+    /// Prepares sending the shared file at `path`, reporting progress to `upload`
+    fn make_request<'s>(
+        &self,
+        stream: TcpStream,
+        path: PathBuf,
+        upload: ActiveUpload,
+    ) -> Self::FileRecord<'s>;
 }
 
 pub struct FileServer<FS: FileSystem> {
     pub server: TcpListener,
     pub file_system: FS,
+    pub dht: Option<Arc<Dht>>,
+    pub pex: Option<Arc<PeerExchange>>,
+    pub uploads: Uploads,
+    events: Option<Arc<Events>>,
+    /// Told whenever the shared files change, trackers are sent the new list when they do
+    changes: Mutex<Vec<Sender<()>>>,
+    stopped: AtomicBool,
+    /// Connections being served, see [`MAX_CONNECTIONS`]
+    connections: AtomicUsize,
 }
 
 //pub struct FileRequest<'s, FS: FileSystem> {
@@ -68,34 +240,115 @@ pub struct FileServer<FS: FileSystem> {
 //}
 
 pub struct SimpleFileSystem {
-    files: Vec<File>,
+    files: Mutex<Vec<SharedFile>>,
+}
+struct SharedFile {
+    file: File,
+    sha256: [u8; 32],
 }
 pub struct SimpleFileRequest {
     stream: TcpStream,
     path: PathBuf,
+    upload: ActiveUpload,
 }
 
 impl FSRequest<'_, SimpleFileSystem> for SimpleFileRequest {
     fn send_file(mut self) {
-        let str = std::fs::read_to_string(self.path).unwrap();
-        self.stream.write_all(str.as_bytes()).unwrap();
+        let size = self.upload.size();
+        let sent = std::fs::File::open(&self.path)
+            .and_then(|mut file| send_file(&mut file, size, &mut self.stream, &self.upload));
+        if let Err(e) = sent {
+            tracing::warn!(path = %self.path.display(), error = %e, "failed to send file");
+        }
+    }
+}
+
+/// Sends the next `size` bytes of `file`, as advertised with [`client::SendingFile`], to `out`
+/// in chunks, reporting each to `upload`, which waits between them for as long as the rate
+/// limits require. Fails when `file` ends before, since it changed after being advertised.
+///
+/// Chunks sent to a `TcpStream` don't go through user space: `std::io::copy` uses
+/// `sendfile(2)` on Linux. Other writers, like encrypting or compressing ones, or when the
+/// kernel can't, are sent buffered copies.
+pub fn send_file(
+    file: &mut std::fs::File,
+    size: u64,
+    out: &mut impl Write,
+    upload: &ActiveUpload,
+) -> Result<(), std::io::Error> {
+    let mut sent = 0;
+    while sent < size {
+        let n = std::io::copy(&mut file.take(SEND_CHUNK.min(size - sent)), out)?;
+        if n == 0 {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+        sent += n;
+        upload.sent(n);
     }
+    Ok(())
 }
 
 impl FileSystem for SimpleFileSystem {
     type FileRecord<'s> = SimpleFileRequest;
     fn new() -> Self {
         Self {
-            files: vec![File {
-                path: PathBuf::from("hi.txt"),
-                size: 3,
-            }],
+            files: Mutex::new(Vec::new()),
         }
     }
     fn list_files(&self) -> Vec<File> {
-        self.files.clone()
+        let files = self.files.lock().unwrap();
+        files.iter().map(|f| f.file.clone()).collect()
+    }
+    fn add_path(&self, path: PathBuf) -> Result<bool, std::io::Error> {
+        let mut file = std::fs::File::open(&path)?;
+        let mut hasher = Sha256::new();
+        let mut buf = [0; 64 * 1024];
+        let mut size = 0;
+        loop {
+            let n = file.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buf[..n]);
+            size += n as u64;
+        }
+        let shared = SharedFile {
+            file: File { path, size },
+            sha256: hasher.finalize().into(),
+        };
+        let mut files = self.files.lock().unwrap();
+        match files.iter_mut().find(|f| f.file.path == shared.file.path) {
+            Some(f) if f.file == shared.file && f.sha256 == shared.sha256 => Ok(false),
+            Some(f) => {
+                *f = shared;
+                Ok(true)
+            }
+            None => {
+                files.push(shared);
+                Ok(true)
+            }
+        }
+    }
+    fn remove_path(&self, path: &Path) -> bool {
+        let mut files = self.files.lock().unwrap();
+        let before = files.len();
+        files.retain(|f| !f.file.path.starts_with(path));
+        files.len() != before
     }
-    fn make_request<'s>(&self, stream: TcpStream, path: PathBuf) -> Self::FileRecord<'s> {
-        SimpleFileRequest { stream, path }
+    fn sha256(&self, path: &Path) -> Option<[u8; 32]> {
+        let files = self.files.lock().unwrap();
+        files.iter().find(|f| f.file.path == path).map(|f| f.sha256)
+    }
+    fn make_request<'s>(
+        &self,
+        stream: TcpStream,
+        path: PathBuf,
+        upload: ActiveUpload,
+    ) -> Self::FileRecord<'s> {
+        SimpleFileRequest {
+            stream,
+            path,
+            upload,
+        }
     }
 }
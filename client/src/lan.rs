@@ -0,0 +1,210 @@
+//! Tracker-free peer discovery on a LAN.
+//!
+//! Every client multicasts a small [`LanAnnounce`] to a group and listens for the others'.
+//! Peers are kept while they keep announcing, and whenever a peer's file list digest changes
+//! its files are fetched with a [`PeerExchange`](crate::pex).
+
+use crate::pex::PeerExchange;
+use crate::tracker::{Peer, PeerSource, Peers};
+use common::client::{self, LanAnnounce};
+use common::dht::NodeId;
+use common::{AnyMessage, CommonError, File, read_msg, write_msg_d};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Multicast group announcements are sent to by default
+pub const GROUP: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(239, 255, 42, 99), 6971);
+/// How often we announce ourselves by default, peers are forgotten after missing three
+pub const INTERVAL: Duration = Duration::from_secs(5);
+
+/// What we know of a peer that announced itself
+struct Announced {
+    digest: [u8; 32],
+    last_seen: Instant,
+}
+
+pub struct LanDiscovery {
+    identity: NodeId,
+    /// Our file server
+    own: SocketAddrV4,
+    group: SocketAddrV4,
+    interval: Duration,
+    socket: UdpSocket,
+    peers: Arc<Mutex<Peers>>,
+    pex: Arc<PeerExchange>,
+    announced: Arc<Mutex<HashMap<SocketAddrV4, Announced>>>,
+}
+
+impl LanDiscovery {
+    /// Joins `group` on `interface`, sending to it also through `interface`. A group that
+    /// isn't a multicast address is treated as a broadcast address.
+    pub fn new(
+        group: SocketAddrV4,
+        interface: Ipv4Addr,
+        own: SocketAddrV4,
+        peers: &Arc<Mutex<Peers>>,
+        pex: &Arc<PeerExchange>,
+    ) -> Result<Self, std::io::Error> {
+        let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+        // Several clients on the same host share the group's port
+        socket.set_reuse_address(true)?;
+        socket.set_reuse_port(true)?;
+        socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, group.port())).into())?;
+        if group.ip().is_multicast() {
+            socket.join_multicast_v4(group.ip(), &interface)?;
+            socket.set_multicast_if_v4(&interface)?;
+            socket.set_multicast_loop_v4(true)?;
+        } else {
+            socket.set_broadcast(true)?;
+        }
+
+        Ok(Self {
+            identity: crate::dht::random_id(&format!("lan {own}")),
+            own,
+            group,
+            interval: INTERVAL,
+            socket: socket.into(),
+            peers: Arc::clone(peers),
+            pex: Arc::clone(pex),
+            announced: Arc::default(),
+        })
+    }
+
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Announces with `identity` instead of a random one, clients sharing it ignore each other
+    pub fn with_identity(mut self, identity: NodeId) -> Self {
+        self.identity = identity;
+        self
+    }
+
+    fn announce(&self, own_files: &[File]) -> Result<(), CommonError> {
+        let msg = client::Message::from(LanAnnounce {
+            identity: self.identity,
+            sock: self.own,
+            digest: LanAnnounce::digest(own_files),
+        });
+        let mut datagram = Vec::new();
+        write_msg_d(&mut datagram, &msg)?;
+        self.socket.send_to(&datagram, self.group)?;
+        Ok(())
+    }
+
+    /// Records an announcement received from `from`, fetching the peer's files in the
+    /// background when they changed
+    fn receive(&self, datagram: &[u8], from: SocketAddrV4, own_files: Vec<File>) {
+        // {msg_type}:u8 {size}:u64, checked before the size is trusted
+        let declared = datagram
+            .get(1..9)
+            .map(|s| u64::from_le_bytes(s.try_into().unwrap()));
+        if declared.is_none_or(|size| size > (datagram.len() - 9) as u64) {
+            return tracing::warn!(%from, len = datagram.len(), "truncated LAN announcement");
+        }
+        let announce = match read_msg(&mut &datagram[..]) {
+            Ok(AnyMessage::Client(client::Message::LanAnnounce(a))) => a,
+            Ok(m) => return tracing::warn!(%from, msg = ?m, "unexpected LAN message"),
+            Err(e) => return tracing::warn!(%from, error = %e, "bad LAN announcement"),
+        };
+        if announce.identity == self.identity {
+            return;
+        }
+        let sock = if announce.sock.ip().is_unspecified() {
+            SocketAddrV4::new(*from.ip(), announce.sock.port())
+        } else {
+            announce.sock
+        };
+        // Peers only announce themselves, never someone else
+        if sock.ip() != from.ip() {
+            return tracing::warn!(%from, announced = %sock, "LAN announcement for another host");
+        }
+
+        let changed = {
+            let mut announced = self.announced.lock().unwrap();
+            let last_seen = Instant::now();
+            let old = announced.insert(
+                sock,
+                Announced {
+                    digest: announce.digest,
+                    last_seen,
+                },
+            );
+            old.is_none_or(|old| old.digest != announce.digest)
+        };
+        if !changed {
+            return add_peer(&self.peers, sock);
+        }
+        let peers = Arc::clone(&self.peers);
+        let pex = Arc::clone(&self.pex);
+        let announced = Arc::clone(&self.announced);
+        std::thread::spawn(move || match pex.exchange(sock, own_files) {
+            Ok(()) => add_peer(&peers, sock),
+            Err(e) => {
+                tracing::warn!(peer = %sock, error = %e, "failed to fetch the files of a LAN peer");
+                // Retried on its next announcement
+                announced.lock().unwrap().remove(&sock);
+            }
+        });
+    }
+
+    /// Forgets peers that missed three announcements
+    fn expire(&self) {
+        let max_age = self.interval * 3;
+        let mut announced = self.announced.lock().unwrap();
+        let mut peers = self.peers.lock().unwrap();
+        announced.retain(|sock, a| {
+            let alive = a.last_seen.elapsed() <= max_age;
+            if !alive {
+                peers.remove_peer(PeerSource::Lan, *sock);
+            }
+            alive
+        });
+    }
+
+    /// Announces ourselves every interval and listens for other peers forever
+    pub fn run(&self, own_files: impl Fn() -> Vec<File>) {
+        let mut buf = [0u8; 1024];
+        loop {
+            if let Err(e) = self.announce(&own_files()) {
+                tracing::warn!(error = %e, "failed to announce on the LAN");
+            }
+            let next = Instant::now() + self.interval;
+            while let Some(left) = next.checked_duration_since(Instant::now())
+                && !left.is_zero()
+            {
+                if let Err(e) = self.socket.set_read_timeout(Some(left)) {
+                    tracing::warn!(error = %e, "LAN discovery stopped");
+                    return;
+                }
+                match self.socket.recv_from(&mut buf) {
+                    Ok((n, SocketAddr::V4(from))) => self.receive(&buf[..n], from, own_files()),
+                    Ok((_, SocketAddr::V6(_))) => {}
+                    Err(e)
+                        if matches!(
+                            e.kind(),
+                            std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+                        ) => {}
+                    Err(e) => tracing::warn!(error = %e, "failed to receive LAN announcement"),
+                }
+            }
+            self.expire();
+        }
+    }
+}
+
+/// Keeps `sock` known as a LAN peer, with the files last exchanged with it
+fn add_peer(peers: &Mutex<Peers>, sock: SocketAddrV4) {
+    let mut peers = peers.lock().unwrap();
+    let files = peers.get_peer(sock).map(|p| p.files.clone());
+    let peer = Peer {
+        sock,
+        files: files.unwrap_or_default(),
+        updated: Instant::now(),
+    };
+    peers.add_peer(PeerSource::Lan, peer);
+}
@@ -0,0 +1,100 @@
+//! Peer to peer file sharing client: shares files with peers found through trackers, the DHT,
+//! the LAN and peer exchange, and downloads the files they share.
+//!
+//! ```no_run
+//! use p2p_client::ClientBuilder;
+//!
+//! let client = ClientBuilder::new()
+//!     .with_share("shared")
+//!     .with_tracker("127.0.0.1:6969".parse()?)
+//!     .start()?;
+//! let events = client.subscribe();
+//! for file in client.files() {
+//!     println!("{} ({} bytes) from {:?}", file.path.display(), file.size, file.peers);
+//! }
+//! client.download("shared/song.mp3", "song.mp3")?;
+//! # drop(events);
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+
+use common::*;
+use std::net::{SocketAddr, SocketAddrV4};
+use std::path::PathBuf;
+
+mod builder;
+pub use builder::{Client, ClientBuilder, DRAIN_TIMEOUT, Limits, UPLOAD_QUEUE};
+
+pub mod control;
+pub mod dht;
+pub mod download;
+pub mod events;
+pub mod file_server;
+pub mod lan;
+pub mod pex;
+pub mod tracker;
+pub mod upload;
+pub mod watch;
+
+#[cfg(test)]
+mod test;
+
+#[derive(Debug, thiserror::Error)]
+pub enum ClientError {
+    #[error(transparent)]
+    IO(#[from] std::io::Error),
+    #[error(transparent)]
+    Lib(#[from] CommonError),
+    #[error(transparent)]
+    AddrParseError(#[from] std::net::AddrParseError),
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+    #[error(transparent)]
+    Config(#[from] common::config::ConfigError),
+    #[error(transparent)]
+    Watch(#[from] notify::Error),
+    #[error("Unexpected message {0:?}")]
+    UnexpectedMessage(Box<AnyMessage>),
+    #[error("No peer provides {0:?}")]
+    NoProvider(PathBuf),
+    #[error("No peer shares a file whose path hashes to {0}")]
+    UnknownHash(dht::NodeId),
+    #[error("{0:?} has no file name")]
+    NoFileName(PathBuf),
+    #[error("Only IPv4 is supported, got {0}")]
+    NotIpv4(SocketAddr),
+    #[error("{} isn't a directory", .0.display())]
+    NotADirectory(PathBuf),
+    #[error("{0} is busy sending files, try again later")]
+    Busy(SocketAddrV4),
+    #[error("{peer} refused to send {path:?}")]
+    Refused { peer: SocketAddrV4, path: PathBuf },
+    #[error("{peer} sent {received} of the {size} bytes of {path:?}")]
+    ShortTransfer {
+        peer: SocketAddrV4,
+        path: PathBuf,
+        received: u64,
+        size: u64,
+    },
+    #[error("{peer} sent more than the {size} bytes of {path:?}")]
+    Oversize {
+        peer: SocketAddrV4,
+        path: PathBuf,
+        size: u64,
+    },
+    #[error("No client is serving on {path:?}: {source}")]
+    ControlUnavailable {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("Serving client failed: {0}")]
+    Control(String),
+    #[error("{0}")]
+    Fatal(String),
+}
+
+pub fn ipv4(addr: SocketAddr) -> Result<SocketAddrV4, ClientError> {
+    match addr {
+        SocketAddr::V4(addr) => Ok(addr),
+        SocketAddr::V6(_) => Err(ClientError::NotIpv4(addr)),
+    }
+}
@@ -0,0 +1,161 @@
+//! Peer exchange (PEX), peers directly telling each other about the peers they know.
+//!
+//! Every exchange sends the peers we know with how long ago each was last known to be alive,
+//! and the other peer answers with its own. Peers keep being found while trackers are down,
+//! and peers learned this way are forgotten once nobody has seen them alive for a while.
+
+use crate::ClientError;
+use crate::tracker::{Peer, PeerSource, Peers};
+use common::client::{self, ExchangedPeer};
+use common::{AnyMessage, CommonError, File, read_msg, write_msg};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How often peers are exchanged
+const INTERVAL: Duration = Duration::from_secs(60);
+/// How many peers are exchanged with every round
+const FANOUT: usize = 3;
+/// Peers not seen alive for this long are neither exchanged nor kept
+pub const MAX_AGE: Duration = Duration::from_secs(10 * 60);
+/// Most peers sent in one exchange, freshest first
+const MAX_PEERS: usize = 200;
+const TIMEOUT: Duration = Duration::from_secs(2);
+
+pub struct PeerExchange {
+    /// Our file server
+    own: SocketAddrV4,
+    peers: Arc<Mutex<Peers>>,
+}
+
+impl PeerExchange {
+    pub fn new(own: SocketAddrV4, peers: &Arc<Mutex<Peers>>) -> Self {
+        Self {
+            own,
+            peers: Arc::clone(peers),
+        }
+    }
+
+    fn message(&self, own_files: Vec<File>) -> client::PeerExchange {
+        let mut known = self.peers.lock().unwrap().liveness();
+        known.retain(|(p, age)| p.sock != self.own && *age <= MAX_AGE);
+        known.sort_by_key(|(_, age)| *age);
+        known.truncate(MAX_PEERS - 1);
+        let own = ExchangedPeer {
+            sock: self.own,
+            file_list: own_files,
+            age_ms: 0,
+        };
+        let known = known.into_iter().map(|(p, age)| ExchangedPeer {
+            sock: p.sock,
+            file_list: p.files,
+            age_ms: age.as_millis() as u64,
+        });
+        client::PeerExchange {
+            sender: self.own,
+            peers: std::iter::once(own).chain(known).collect(),
+        }
+    }
+
+    /// Replaces what the peer whose file server is at `source` told us before, file servers
+    /// listening on every interface are reached through `from`, the IP `msg` came from
+    fn merge(&self, msg: client::PeerExchange, from: Ipv4Addr, source: SocketAddrV4) {
+        let resolve = |sock: SocketAddrV4| match sock.ip().is_unspecified() {
+            true => SocketAddrV4::new(from, sock.port()),
+            false => sock,
+        };
+        let now = Instant::now();
+        let peers = msg.peers.into_iter().filter_map(|p| {
+            let age = Duration::from_millis(p.age_ms);
+            (p.sock != self.own && age <= MAX_AGE).then(|| Peer {
+                sock: resolve(p.sock),
+                files: p.file_list,
+                updated: now.checked_sub(age).unwrap_or(now),
+            })
+        });
+        self.peers
+            .lock()
+            .unwrap()
+            .replace(PeerSource::Exchange(source), peers);
+    }
+
+    /// Answers an exchange started by another peer. What it tells is only kept when its file
+    /// server is on the IP the exchange came from, so no peer speaks for another.
+    pub fn respond(
+        &self,
+        mut stream: TcpStream,
+        msg: client::PeerExchange,
+        own_files: Vec<File>,
+    ) -> Result<(), CommonError> {
+        let from = match stream.peer_addr()? {
+            SocketAddr::V4(from) => *from.ip(),
+            SocketAddr::V6(_) => Ipv4Addr::UNSPECIFIED,
+        };
+        let reply = self.message(own_files);
+        let sender = match msg.sender.ip().is_unspecified() {
+            true => SocketAddrV4::new(from, msg.sender.port()),
+            false => msg.sender,
+        };
+        if *sender.ip() == from {
+            self.merge(msg, from, sender);
+        } else {
+            tracing::warn!(%from, claimed = %msg.sender, "ignoring peer exchange sent for another host");
+        }
+        stream.set_write_timeout(Some(TIMEOUT))?;
+        write_msg(&mut stream, &client::Message::from(reply))
+    }
+
+    /// Exchanges known peers with the peer whose file server is at `addr`, forgetting what it
+    /// told us before when it can't be reached
+    pub fn exchange(&self, addr: SocketAddrV4, own_files: Vec<File>) -> Result<(), ClientError> {
+        let answer = (|| {
+            let mut stream = TcpStream::connect_timeout(&SocketAddr::V4(addr), TIMEOUT)?;
+            stream.set_read_timeout(Some(TIMEOUT))?;
+            stream.set_write_timeout(Some(TIMEOUT))?;
+            write_msg(&mut stream, &client::Message::from(self.message(own_files)))?;
+            match read_msg(&mut stream).map_err(CommonError::from)? {
+                AnyMessage::Client(client::Message::PeerExchange(answer)) => Ok(answer),
+                m => Err(ClientError::UnexpectedMessage(Box::new(m))),
+            }
+        })();
+        match answer {
+            Ok(answer) => {
+                self.merge(answer, *addr.ip(), addr);
+                Ok(())
+            }
+            Err(e) => {
+                self.peers.lock().unwrap().clear(PeerSource::Exchange(addr));
+                Err(e)
+            }
+        }
+    }
+
+    /// Exchanges with a few known peers every [`INTERVAL`] forever, going through all of them
+    /// in turn
+    pub fn run(&self, own_files: impl Fn() -> Vec<File>) {
+        let mut round = 0;
+        loop {
+            let mut addrs: Vec<_> = {
+                let mut peers = self.peers.lock().unwrap();
+                peers.expire_exchanged(MAX_AGE);
+                peers
+                    .addrs()
+                    .into_iter()
+                    .filter(|a| *a != self.own)
+                    .collect()
+            };
+            addrs.sort();
+            if !addrs.is_empty() {
+                let start = round * FANOUT % addrs.len();
+                addrs.rotate_left(start);
+            }
+            for addr in addrs.into_iter().take(FANOUT) {
+                if let Err(e) = self.exchange(addr, own_files()) {
+                    tracing::warn!(peer = %addr, error = %e, "peer exchange failed");
+                }
+            }
+            round += 1;
+            std::thread::sleep(INTERVAL);
+        }
+    }
+}
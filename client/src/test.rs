@@ -0,0 +1,1458 @@
+use crate::control::{self, Control};
+use crate::dht::{Dht, NodeId};
+use crate::download::Downloads;
+use crate::file_server::{FileServer, FileSystem, SimpleFileSystem};
+use crate::ipv4;
+use crate::lan::LanDiscovery;
+use crate::pex::{self, PeerExchange};
+use crate::tracker::{Peer, PeerSource, Peers};
+use common::File;
+use std::net::{Ipv4Addr, SocketAddrV4, TcpListener};
+use std::num::NonZeroU64;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+fn peer(sock: &str, files: &[&str], updated: Instant) -> Peer {
+    Peer {
+        sock: sock.parse().unwrap(),
+        files: files
+            .iter()
+            .map(|f| File {
+                path: PathBuf::from(f),
+                size: 0,
+            })
+            .collect(),
+        updated,
+    }
+}
+
+/// Polls `done` until it holds, failing the test as `what` after 10 seconds
+fn wait_for(what: &str, done: &dyn Fn() -> bool) {
+    let start = Instant::now();
+    while !done() {
+        assert!(start.elapsed() < Duration::from_secs(10), "{what}");
+        std::thread::sleep(Duration::from_millis(50));
+    }
+}
+
+#[test]
+fn peer_stays_while_any_source_knows_it() {
+    let a = PeerSource::Tracker("127.0.0.1:6969".parse().unwrap());
+    let b = PeerSource::Tracker("127.0.0.1:6970".parse().unwrap());
+    let sock: SocketAddrV4 = "10.0.0.1:4000".parse().unwrap();
+    let mut peers = Peers::new();
+    let now = Instant::now();
+
+    peers.add_peer(a, peer("10.0.0.1:4000", &["x"], now));
+    peers.add_peer(b, peer("10.0.0.1:4000", &["x"], now));
+    assert_eq!(peers.sources(sock).count(), 2);
+
+    peers.remove_peer(a, sock);
+    assert_eq!(peers.sources(sock).collect::<Vec<_>>(), vec![b]);
+    assert!(peers.get_peer(sock).is_some());
+
+    peers.clear(b);
+    assert!(peers.get_peer(sock).is_none());
+}
+
+#[test]
+fn freshest_source_wins() {
+    let a = PeerSource::Tracker("127.0.0.1:6969".parse().unwrap());
+    let b = PeerSource::Tracker("127.0.0.1:6970".parse().unwrap());
+    let sock: SocketAddrV4 = "10.0.0.1:4000".parse().unwrap();
+    let mut peers = Peers::new();
+    let now = Instant::now();
+
+    peers.add_peer(a, peer("10.0.0.1:4000", &["old"], now));
+    peers.update_peer(
+        b,
+        peer("10.0.0.1:4000", &["new"], now + Duration::from_secs(1)),
+    );
+    assert_eq!(
+        peers.get_peer(sock).unwrap().files[0].path,
+        PathBuf::from("new")
+    );
+
+    peers.replace(
+        a,
+        [peer(
+            "10.0.0.1:4000",
+            &["newest"],
+            now + Duration::from_secs(2),
+        )],
+    );
+    assert_eq!(
+        peers.get_peer(sock).unwrap().files[0].path,
+        PathBuf::from("newest")
+    );
+}
+
+#[test]
+fn peers_tell_about_changes_once_every_source_agrees() {
+    use crate::events::{Event, Events};
+
+    let a = PeerSource::Tracker("127.0.0.1:6969".parse().unwrap());
+    let b = PeerSource::Lan;
+    let sock: SocketAddrV4 = "10.0.0.1:4000".parse().unwrap();
+    let events = Arc::new(Events::new());
+    let received = events.subscribe();
+    let mut peers = Peers::new().with_events(Arc::clone(&events));
+    let now = Instant::now();
+
+    peers.add_peer(a, peer("10.0.0.1:4000", &["x"], now));
+    // Known already, with the same files
+    peers.add_peer(b, peer("10.0.0.1:4000", &["x"], now));
+    peers.replace(
+        a,
+        [peer(
+            "10.0.0.1:4000",
+            &["x", "y"],
+            now + Duration::from_secs(1),
+        )],
+    );
+    peers.clear(a);
+    peers.remove_peer(b, sock);
+
+    let file = |path: &str| Event::FileAvailable {
+        path: PathBuf::from(path),
+        size: 0,
+        peer: sock,
+    };
+    let expected = [
+        Event::PeerJoined(peer("10.0.0.1:4000", &["x"], now)),
+        file("x"),
+        Event::PeerUpdated(peer(
+            "10.0.0.1:4000",
+            &["x", "y"],
+            now + Duration::from_secs(1),
+        )),
+        file("y"),
+        // Back to what the LAN knows
+        Event::PeerUpdated(peer("10.0.0.1:4000", &["x"], now)),
+        Event::PeerLeft(sock),
+    ];
+    for event in expected {
+        assert_eq!(received.recv_timeout(Duration::from_secs(2)), Ok(event));
+    }
+    assert!(received.recv_timeout(Duration::from_millis(50)).is_err());
+}
+
+fn start_dht_node() -> (Arc<Dht>, SocketAddrV4) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = ipv4(listener.local_addr().unwrap()).unwrap();
+    let dht = Arc::new(Dht::new(addr));
+    let serving = Arc::clone(&dht);
+    std::thread::spawn(move || serving.serve(&listener));
+    (dht, addr)
+}
+
+#[test]
+fn dht_finds_providers_among_many_nodes() {
+    let nodes: Vec<_> = (0..40).map(|_| start_dht_node()).collect();
+    let (_, entry) = nodes[0];
+    for (node, _) in &nodes[1..] {
+        node.bootstrap(entry).unwrap();
+    }
+
+    let key = NodeId::for_path(Path::new("artifact.tar"));
+    let provider: SocketAddrV4 = "127.0.0.1:40000".parse().unwrap();
+    assert!(nodes[13].0.announce(key, provider) > 0);
+    for (node, _) in &nodes {
+        assert_eq!(node.find_providers(key), vec![provider]);
+    }
+    let missing = NodeId::for_path(Path::new("missing.tar"));
+    assert!(nodes[27].0.find_providers(missing).is_empty());
+}
+
+#[test]
+fn dht_lookups_only_leave_no_trace() {
+    let (node, addr) = start_dht_node();
+    let key = NodeId::for_path(Path::new("artifact.tar"));
+    let provider: SocketAddrV4 = "127.0.0.1:40001".parse().unwrap();
+    node.announce(key, provider);
+
+    let lookup = Dht::lookup_only();
+    lookup.bootstrap(addr).unwrap();
+    assert_eq!(lookup.find_providers(key), [provider]);
+    assert_eq!(node.known_nodes(), 0);
+}
+
+#[test]
+fn dht_keeps_the_newest_providers_of_a_key() {
+    let (node, _) = start_dht_node();
+    let key = NodeId::for_path(Path::new("popular.tar"));
+    let providers: Vec<SocketAddrV4> = (0..crate::dht::K as u16 + 10)
+        .map(|i| SocketAddrV4::new(Ipv4Addr::LOCALHOST, 41000 + i))
+        .collect();
+    for &provider in &providers {
+        node.announce(key, provider);
+        // Records expiring at the same instant would be evicted in any order
+        std::thread::sleep(Duration::from_millis(1));
+    }
+    let mut found = node.find_providers(key);
+    found.sort();
+    assert_eq!(found, providers[10..]);
+}
+
+#[test]
+fn peer_exchange_shares_fresh_peers() {
+    let tracker = PeerSource::Tracker("127.0.0.1:6969".parse().unwrap());
+    let stale = Instant::now() - pex::MAX_AGE - Duration::from_secs(1);
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let b_addr = ipv4(listener.local_addr().unwrap()).unwrap();
+    let b_peers = Arc::new(Mutex::new(Peers::new()));
+    b_peers
+        .lock()
+        .unwrap()
+        .add_peer(tracker, peer("10.0.0.2:4000", &["y"], Instant::now()));
+    let b = PeerExchange::new(b_addr, &b_peers);
+    std::thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        match common::read_msg(&mut stream).unwrap() {
+            common::AnyMessage::Client(common::client::Message::PeerExchange(m)) => {
+                b.respond(stream, m, Vec::new()).unwrap()
+            }
+            m => panic!("unexpected message {m:?}"),
+        }
+    });
+
+    let a_addr: SocketAddrV4 = "127.0.0.1:1".parse().unwrap();
+    let a_peers = Arc::new(Mutex::new(Peers::new()));
+    {
+        let mut peers = a_peers.lock().unwrap();
+        let other = PeerSource::Exchange("10.0.0.9:4000".parse().unwrap());
+        peers.add_peer(tracker, peer("10.0.0.1:4000", &["x"], stale));
+        peers.add_peer(other, peer("10.0.0.3:4000", &["gone"], stale));
+    }
+    let a = PeerExchange::new(a_addr, &a_peers);
+    a.exchange(b_addr, Vec::new()).unwrap();
+
+    let a_peers = a_peers.lock().unwrap();
+    let learned: Vec<_> = a_peers.sources(b_addr).collect();
+    assert_eq!(learned, vec![PeerSource::Exchange(b_addr)]);
+    assert!(a_peers.get_peer("10.0.0.2:4000".parse().unwrap()).is_some());
+
+    // Tracked peers are alive no matter when they were registered, stale exchanged ones aren't
+    let b_peers = b_peers.lock().unwrap();
+    assert!(b_peers.get_peer(a_addr).is_some());
+    let x = b_peers.get_peer("10.0.0.1:4000".parse().unwrap()).unwrap();
+    assert!(x.updated.elapsed() < pex::MAX_AGE);
+    assert!(b_peers.get_peer("10.0.0.3:4000".parse().unwrap()).is_none());
+}
+
+#[test]
+fn peer_exchanges_only_speak_for_their_own_host() {
+    use common::client::{self, ExchangedPeer};
+
+    let peers = Arc::new(Mutex::new(Peers::new()));
+    let (_, addr) = start_pex_node(&peers, &[]);
+    let exchange = |sender: &str| {
+        let mut stream = std::net::TcpStream::connect(addr).unwrap();
+        let msg = client::PeerExchange {
+            sender: sender.parse().unwrap(),
+            peers: vec![ExchangedPeer {
+                sock: "10.0.0.5:4000".parse().unwrap(),
+                file_list: Vec::new(),
+                age_ms: 0,
+            }],
+        };
+        common::write_msg(&mut stream, &client::Message::from(msg)).unwrap();
+        common::read_msg(&mut stream).unwrap();
+    };
+
+    // Claims to be another peer, whose list it would replace
+    exchange("10.0.0.9:4000");
+    assert!(peers.lock().unwrap().addrs().is_empty());
+    exchange("127.0.0.1:4000");
+    let peers = peers.lock().unwrap();
+    let sources: Vec<_> = peers.sources("10.0.0.5:4000".parse().unwrap()).collect();
+    assert_eq!(
+        sources,
+        [PeerSource::Exchange("127.0.0.1:4000".parse().unwrap())]
+    );
+}
+
+/// Answers peer exchanges for `files` on a new listener, returning its address
+fn start_pex_node(peers: &Arc<Mutex<Peers>>, files: &[&str]) -> (Arc<PeerExchange>, SocketAddrV4) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = ipv4(listener.local_addr().unwrap()).unwrap();
+    let pex = Arc::new(PeerExchange::new(addr, peers));
+    let files = peer("0.0.0.0:0", files, Instant::now()).files;
+    let serving = Arc::clone(&pex);
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            if let Ok(common::AnyMessage::Client(common::client::Message::PeerExchange(m))) =
+                common::read_msg(&mut stream)
+            {
+                let _ = serving.respond(stream, m, files.clone());
+            }
+        }
+    });
+    (pex, addr)
+}
+
+#[test]
+fn lan_peers_find_each_other() {
+    let group: SocketAddrV4 = "239.255.42.99:46971".parse().unwrap();
+    let nodes: Vec<_> = [&["a.txt"][..], &["b.txt", "bb.txt"]]
+        .into_iter()
+        .map(|files| {
+            let peers = Arc::new(Mutex::new(Peers::new()));
+            let (pex, addr) = start_pex_node(&peers, files);
+            let lan = LanDiscovery::new(group, Ipv4Addr::LOCALHOST, addr, &peers, &pex)
+                .unwrap()
+                .with_interval(Duration::from_millis(100));
+            let own = peer("0.0.0.0:0", files, Instant::now()).files;
+            std::thread::spawn(move || lan.run(|| own.clone()));
+            (peers, addr)
+        })
+        .collect();
+
+    let deadline = Instant::now() + Duration::from_secs(5);
+    for (i, (peers, _)) in nodes.iter().enumerate() {
+        let (_, other) = nodes[1 - i];
+        loop {
+            let found = peers.lock().unwrap().get_peer(other).cloned();
+            let sources: Vec<_> = peers.lock().unwrap().sources(other).collect();
+            if let Some(found) = found
+                && sources.contains(&PeerSource::Lan)
+            {
+                assert_eq!(found.files.len(), 2 - i);
+                break;
+            }
+            assert!(Instant::now() < deadline, "{other} wasn't discovered");
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        assert!(peers.lock().unwrap().get_peer(nodes[i].1).is_none());
+    }
+}
+
+/// Sends one JSON-RPC request to the control socket at `path`, returning the response
+fn rpc(path: &Path, method: &str, params: serde_json::Value) -> serde_json::Value {
+    use std::io::{BufRead, BufReader, Write};
+    let mut stream = std::os::unix::net::UnixStream::connect(path).unwrap();
+    let request =
+        serde_json::json!({ "jsonrpc": "2.0", "id": 7, "method": method, "params": params });
+    writeln!(stream, "{request}").unwrap();
+    let mut line = String::new();
+    BufReader::new(stream).read_line(&mut line).unwrap();
+    let response: serde_json::Value = serde_json::from_str(&line).unwrap();
+    assert_eq!(response["id"], 7);
+    response
+}
+
+#[test]
+fn control_socket_shares_and_downloads() {
+    let dir = std::env::temp_dir().join(format!("p2prs-control-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let shared = dir.join("shared.txt");
+    std::fs::write(&shared, "shared contents").unwrap();
+
+    let file_server =
+        Arc::new(FileServer::<SimpleFileSystem>::new("127.0.0.1:0".parse().unwrap()).unwrap());
+    let changes = file_server.subscribe();
+    let serving = Arc::clone(&file_server);
+    std::thread::spawn(move || serving.serve());
+    let server_addr = ipv4(file_server.server.local_addr().unwrap()).unwrap();
+
+    let peers = Arc::new(Mutex::new(Peers::new()));
+    let socket = dir.join("control.sock");
+    let listener = control::bind(&socket).unwrap();
+    let downloads = Arc::new(Downloads::new());
+    let control = Arc::new(Control::new(&file_server, &peers, &downloads));
+    std::thread::spawn(move || control.serve(&listener));
+
+    let added = rpc(&socket, "shared.add", serde_json::json!({ "path": shared }));
+    assert!(
+        added["result"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .any(|f| f["path"] == shared.to_str().unwrap())
+    );
+    assert!(changes.try_recv().is_ok());
+    // Directories share every file under them
+    std::fs::create_dir_all(dir.join("sub")).unwrap();
+    std::fs::write(dir.join("sub/nested.txt"), "nested").unwrap();
+    let added = rpc(
+        &socket,
+        "shared.add",
+        serde_json::json!({ "path": dir.join("sub") }),
+    );
+    assert_eq!(added["result"].as_array().unwrap().len(), 2);
+    assert!(
+        rpc(
+            &socket,
+            "shared.remove",
+            serde_json::json!({ "path": dir.join("sub") })
+        )["error"]
+            .is_null()
+    );
+    changes.try_iter().for_each(drop);
+
+    // We learn about ourselves, as another peer would
+    let source = PeerSource::Tracker("127.0.0.1:6969".parse().unwrap());
+    let files = file_server.file_system.list_files();
+    peers.lock().unwrap().add_peer(
+        source,
+        Peer {
+            sock: server_addr,
+            files,
+            updated: Instant::now(),
+        },
+    );
+    let listed = rpc(&socket, "files", serde_json::Value::Null);
+    let listed = listed["result"].as_array().unwrap();
+    assert!(
+        listed
+            .iter()
+            .any(|f| f["path"] == shared.to_str().unwrap()
+                && f["peers"][0] == server_addr.to_string())
+    );
+    assert_eq!(
+        rpc(&socket, "status", serde_json::Value::Null)["result"]["peers"],
+        1
+    );
+
+    let dest = dir.join("downloaded.txt");
+    let started = rpc(
+        &socket,
+        "download.start",
+        serde_json::json!({ "path": shared, "dest": dest }),
+    );
+    let id = started["result"]["id"].clone();
+    let deadline = Instant::now() + Duration::from_secs(5);
+    loop {
+        let downloads = rpc(&socket, "downloads", serde_json::Value::Null);
+        let download = &downloads["result"][0];
+        assert_eq!(download["id"], id);
+        if download["state"] == "done" {
+            break;
+        }
+        assert_eq!(download["state"], "running", "{download}");
+        assert!(Instant::now() < deadline);
+        std::thread::sleep(Duration::from_millis(20));
+    }
+    assert_eq!(std::fs::read_to_string(&dest).unwrap(), "shared contents");
+
+    assert_eq!(
+        rpc(
+            &socket,
+            "shared.remove",
+            serde_json::json!({ "path": shared })
+        )["error"],
+        serde_json::Value::Null
+    );
+    assert_eq!(
+        rpc(
+            &socket,
+            "shared.remove",
+            serde_json::json!({ "path": shared })
+        )["error"]["code"],
+        -32000
+    );
+    assert_eq!(
+        rpc(&socket, "download.cancel", serde_json::json!({}))["error"]["code"],
+        -32602
+    );
+    let limits = rpc(
+        &socket,
+        "downloads.limits",
+        serde_json::json!({ "max_running": 2 }),
+    );
+    assert_eq!(
+        limits["result"],
+        serde_json::json!({ "max_running": 2, "rate": null })
+    );
+    let limits = rpc(&socket, "downloads.limits", serde_json::Value::Null);
+    assert_eq!(limits["result"]["max_running"], 2);
+    for (method, params) in [
+        ("downloads.limits", serde_json::json!({ "rate": 0 })),
+        ("download.set", serde_json::json!({ "id": 0, "rate": 0 })),
+        (
+            "download.start",
+            serde_json::json!({ "path": "a.txt", "dest": dest, "rate": 0 }),
+        ),
+        // Relative to the working directory of the client, not the caller's
+        ("shared.add", serde_json::json!({ "path": "shared.txt" })),
+        (
+            "download.start",
+            serde_json::json!({ "path": "a.txt", "dest": "a.txt" }),
+        ),
+    ] {
+        assert_eq!(rpc(&socket, method, params)["error"]["code"], -32602);
+    }
+    assert_eq!(
+        rpc(
+            &socket,
+            "download.set",
+            serde_json::json!({ "id": 42, "priority": 1 })
+        )["error"]["code"],
+        -32000
+    );
+    assert_eq!(
+        rpc(&socket, "nope", serde_json::Value::Null)["error"]["code"],
+        -32601
+    );
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn uploads_are_listed_while_running() {
+    let uploads = crate::upload::Uploads::new();
+    let upload = uploads.start(uploads.slot().unwrap(), None, PathBuf::from("a.txt"), 10);
+    upload.sent(4);
+    let listed = uploads.list();
+    assert_eq!(listed.len(), 1);
+    assert_eq!(listed[0].sent.load(std::sync::atomic::Ordering::Relaxed), 4);
+    drop(upload);
+    assert!(uploads.list().is_empty());
+}
+
+#[test]
+fn uploads_wait_for_a_slot_in_order() {
+    use crate::upload::{UploadLimits, Uploads};
+
+    let uploads = Arc::new(Uploads::new().with_limits(UploadLimits {
+        slots: Some(1),
+        queue: 2,
+        ..UploadLimits::default()
+    }));
+    let running = uploads.slot().unwrap();
+    let (tx, rx) = std::sync::mpsc::channel();
+    for i in 0..2 {
+        let waiting = Arc::clone(&uploads);
+        let tx = tx.clone();
+        std::thread::spawn(move || {
+            let slot = waiting.slot().unwrap();
+            tx.send(i).unwrap();
+            std::thread::sleep(Duration::from_millis(50));
+            drop(slot);
+        });
+        while uploads.queued() <= i {
+            std::thread::sleep(Duration::from_millis(5));
+        }
+    }
+    // The queue is full
+    assert!(uploads.slot().is_none());
+    assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+    drop(running);
+    assert_eq!(rx.recv_timeout(Duration::from_secs(2)), Ok(0));
+    assert_eq!(rx.recv_timeout(Duration::from_secs(2)), Ok(1));
+}
+
+#[test]
+fn uploads_are_rate_limited() {
+    use crate::upload::{UploadLimits, Uploads};
+
+    let uploads = Uploads::new().with_limits(UploadLimits {
+        rate: NonZeroU64::new(10_000),
+        peer_rate: NonZeroU64::new(2_000),
+        ..UploadLimits::default()
+    });
+    let peer = "127.0.0.1:4000".parse().ok();
+    // A second's worth is sent right away
+    let started = Instant::now();
+    let upload = uploads.start(uploads.slot().unwrap(), peer, PathBuf::from("a.txt"), 3000);
+    upload.sent(2_000);
+    assert!(started.elapsed() < Duration::from_millis(200));
+    // The peer's limit is the lower one
+    upload.sent(1_000);
+    assert!(started.elapsed() >= Duration::from_millis(400));
+    // Relayed uploads are only subject to the total
+    let started = Instant::now();
+    let relayed = uploads.start(uploads.slot().unwrap(), None, PathBuf::from("a.txt"), 3000);
+    relayed.sent(3_000);
+    assert!(started.elapsed() < Duration::from_millis(200));
+}
+
+#[test]
+fn busy_file_servers_say_so() {
+    use crate::ClientError;
+    use crate::download::{read_answer, request_file};
+    use crate::upload::UploadLimits;
+    use std::io::Read;
+
+    let dir = std::env::temp_dir().join(format!("p2prs-busy-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let shared = dir.join("shared.txt");
+    std::fs::write(&shared, "shared contents").unwrap();
+    let file_server = Arc::new(
+        FileServer::<SimpleFileSystem>::new("127.0.0.1:0".parse().unwrap())
+            .unwrap()
+            .with_upload_limits(UploadLimits {
+                slots: Some(1),
+                queue: 1,
+                ..UploadLimits::default()
+            }),
+    );
+    file_server.file_system.add_path(shared.clone()).unwrap();
+    let serving = Arc::clone(&file_server);
+    std::thread::spawn(move || serving.serve());
+    let server_addr = ipv4(file_server.server.local_addr().unwrap()).unwrap();
+    let get = |path: &Path| -> Result<String, ClientError> {
+        let mut stream = request_file(server_addr, None, path.to_path_buf())?;
+        let size = read_answer(&mut stream, server_addr, path.to_path_buf())?;
+        let mut contents = String::new();
+        stream.read_to_string(&mut contents)?;
+        assert_eq!(size, contents.len() as u64);
+        Ok(contents)
+    };
+
+    assert_eq!(get(&shared).unwrap(), "shared contents");
+    assert!(matches!(
+        get(&dir.join("missing")),
+        Err(ClientError::Refused { .. })
+    ));
+
+    let taken = file_server.uploads.slot().unwrap();
+    std::thread::scope(|s| {
+        let waiting = s.spawn(|| get(&shared));
+        while file_server.uploads.queued() == 0 {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert!(matches!(get(&shared), Err(ClientError::Busy(addr)) if addr == server_addr));
+        // Stopped while waiting for the slot
+        file_server.stop();
+        drop(taken);
+        assert!(matches!(waiting.join().unwrap(), Err(ClientError::Busy(_))));
+    });
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+/// Serves the files written to `dir` as `name` and `contents`
+fn start_file_server(
+    dir: &Path,
+    files: &[(&str, &[u8])],
+) -> (Arc<FileServer<SimpleFileSystem>>, SocketAddrV4) {
+    std::fs::create_dir_all(dir).unwrap();
+    let file_server =
+        Arc::new(FileServer::<SimpleFileSystem>::new("127.0.0.1:0".parse().unwrap()).unwrap());
+    for (name, contents) in files {
+        std::fs::write(dir.join(name), contents).unwrap();
+        file_server.file_system.add_path(dir.join(name)).unwrap();
+    }
+    let serving = Arc::clone(&file_server);
+    std::thread::spawn(move || serving.serve());
+    let addr = ipv4(file_server.server.local_addr().unwrap()).unwrap();
+    (file_server, addr)
+}
+
+/// Waits for `download` to finish, returning how long it took
+fn wait_finished(download: &crate::download::Download) -> Duration {
+    let started = Instant::now();
+    while !download.finished() {
+        assert!(
+            started.elapsed() < Duration::from_secs(5),
+            "the download never finished"
+        );
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    started.elapsed()
+}
+
+#[test]
+fn short_transfers_fail_and_leave_no_file() {
+    use crate::download::DownloadState;
+    use common::{client, read_msg, write_msg};
+    use std::io::Write;
+
+    let dir = std::env::temp_dir().join(format!("p2prs-short-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    // Promises more than it sends, then less
+    let sent: [&[u8]; 2] = [b"only ten b", &[0; 110]];
+    let peer = TcpListener::bind("127.0.0.1:0").unwrap();
+    let peer_addr = ipv4(peer.local_addr().unwrap()).unwrap();
+    std::thread::spawn(move || {
+        for bytes in sent {
+            let (mut conn, _) = peer.accept().unwrap();
+            read_msg(&mut conn).unwrap();
+            write_msg(
+                &mut conn,
+                &client::Message::from(client::SendingFile { size: 100 }),
+            )
+            .unwrap();
+            conn.write_all(bytes).unwrap();
+        }
+    });
+    let downloads = Downloads::new();
+    for expected in ["10 of the 100 bytes", "more than the 100 bytes"] {
+        let download = downloads
+            .start(PathBuf::from("big.bin"), peer_addr, dir.join("big.bin"))
+            .unwrap();
+        wait_finished(&download);
+        match download.state() {
+            DownloadState::Failed(e) => assert!(e.contains(expected), "{e}"),
+            state => panic!("unexpected state {state:?}"),
+        }
+        assert!(!dir.join("big.bin").exists());
+    }
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn only_the_last_finished_downloads_are_kept() {
+    use crate::download::KEEP_FINISHED;
+
+    // Nobody listens there once the listener is dropped
+    let unreachable = ipv4(
+        TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap(),
+    )
+    .unwrap();
+    let downloads = Downloads::new();
+    let start = || {
+        let dest = std::env::temp_dir().join("p2prs-never-written");
+        downloads
+            .start(PathBuf::from("a.txt"), unreachable, dest)
+            .unwrap()
+    };
+    for _ in 0..KEEP_FINISHED + 5 {
+        wait_finished(&start());
+    }
+    let last = start();
+    let listed = downloads.list();
+    assert_eq!(listed.len(), KEEP_FINISHED + 1);
+    assert_eq!(listed[0].id, 5);
+    assert_eq!(listed[KEEP_FINISHED].id, last.id);
+}
+
+#[test]
+fn unreachable_peers_are_downloaded_from_through_the_relay() {
+    use crate::download::{DownloadOptions, DownloadState};
+    use common::{AnyMessage, client, read_msg, write_msg};
+    use std::io::Write;
+
+    let dir = std::env::temp_dir().join(format!("p2prs-relayed-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    // Nobody listens there once the listener is dropped
+    let unreachable = ipv4(
+        TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap(),
+    )
+    .unwrap();
+    // Stands for the tracker and the peer it relays to
+    let relay = TcpListener::bind("127.0.0.1:0").unwrap();
+    let relay_addr = ipv4(relay.local_addr().unwrap()).unwrap();
+    std::thread::spawn(move || {
+        let (mut conn, _) = relay.accept().unwrap();
+        let AnyMessage::Client(client::Message::RelayConnect(connect)) =
+            read_msg(&mut conn).unwrap()
+        else {
+            panic!("expected RelayConnect");
+        };
+        assert_eq!(connect.target, unreachable);
+        read_msg(&mut conn).unwrap();
+        write_msg(
+            &mut conn,
+            &client::Message::from(client::SendingFile { size: 7 }),
+        )
+        .unwrap();
+        conn.write_all(b"relayed").unwrap();
+    });
+    let downloads = Downloads::new();
+    let options = DownloadOptions {
+        relay: Some(relay_addr),
+        ..DownloadOptions::default()
+    };
+    let download = downloads
+        .start_with(
+            PathBuf::from("a.txt"),
+            unreachable,
+            dir.join("a.txt"),
+            options,
+        )
+        .unwrap();
+    wait_finished(&download);
+    assert_eq!(download.state(), DownloadState::Done);
+    assert_eq!(std::fs::read(dir.join("a.txt")).unwrap(), b"relayed");
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn downloads_wait_in_the_queue_by_priority() {
+    use crate::download::{DownloadLimits, DownloadOptions, DownloadState};
+    use crate::events::{Event, Events};
+
+    let dir = std::env::temp_dir().join(format!("p2prs-queue-{}", std::process::id()));
+    let (_server, server_addr) = start_file_server(&dir, &[("a.txt", b"contents")]);
+    let events = Arc::new(Events::new());
+    let finished = events.subscribe();
+    let downloads = Downloads::new()
+        .with_events(Arc::clone(&events))
+        .with_limits(DownloadLimits {
+            max_running: Some(1),
+            rate: None,
+        });
+    // Never answers, keeping its download running
+    let silent = TcpListener::bind("127.0.0.1:0").unwrap();
+    let silent_addr = ipv4(silent.local_addr().unwrap()).unwrap();
+    let stuck = downloads
+        .start(dir.join("a.txt"), silent_addr, dir.join("stuck"))
+        .unwrap();
+    let _held = silent.accept().unwrap();
+    let start = |dest: &str, priority| {
+        let options = DownloadOptions {
+            priority,
+            ..DownloadOptions::default()
+        };
+        downloads
+            .start_with(dir.join("a.txt"), server_addr, dir.join(dest), options)
+            .unwrap()
+    };
+    let low = start("low", 0);
+    let high = start("high", 0);
+    assert!(downloads.set_priority(high.id, 5));
+    assert!(!downloads.set_priority(42, 5));
+    std::thread::sleep(Duration::from_millis(50));
+    assert_eq!(stuck.state(), DownloadState::Running);
+    assert_eq!(low.state(), DownloadState::Queued);
+    assert_eq!(high.state(), DownloadState::Queued);
+
+    // One more may run, the highest priority goes first
+    downloads.set_limits(DownloadLimits {
+        max_running: Some(2),
+        rate: None,
+    });
+    wait_finished(&low);
+    let done: Vec<_> = finished
+        .iter()
+        .filter_map(|e| match e {
+            Event::DownloadProgress {
+                id,
+                state: DownloadState::Done,
+                ..
+            } => Some(id),
+            _ => None,
+        })
+        .take(2)
+        .collect();
+    assert_eq!(done, [high.id, low.id]);
+    assert_eq!(std::fs::read(dir.join("high")).unwrap(), b"contents");
+    assert_eq!(stuck.state(), DownloadState::Running);
+    assert!(downloads.cancel(stuck.id));
+    wait_finished(&stuck);
+    assert_eq!(stuck.state(), DownloadState::Cancelled);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn downloads_are_rate_limited() {
+    use crate::download::{DownloadLimits, DownloadOptions, DownloadState};
+
+    let dir = std::env::temp_dir().join(format!("p2prs-throttle-{}", std::process::id()));
+    let contents = [7u8; 1500];
+    let (_server, server_addr) = start_file_server(&dir, &[("a.bin", &contents)]);
+    let downloads = Downloads::new().with_limits(DownloadLimits {
+        max_running: None,
+        rate: NonZeroU64::new(1000),
+    });
+    let path = dir.join("a.bin");
+    // A second's worth arrives right away, the rest half a second later
+    let download = downloads
+        .start(path.clone(), server_addr, dir.join("total"))
+        .unwrap();
+    assert!(wait_finished(&download) >= Duration::from_millis(400));
+    assert_eq!(download.state(), DownloadState::Done);
+    assert_eq!(std::fs::read(dir.join("total")).unwrap(), contents);
+
+    downloads.set_limits(DownloadLimits::default());
+    let options = DownloadOptions {
+        rate: NonZeroU64::new(1000),
+        ..DownloadOptions::default()
+    };
+    let download = downloads
+        .start_with(path.clone(), server_addr, dir.join("own"), options)
+        .unwrap();
+    assert_eq!(download.rate(), NonZeroU64::new(1000));
+    assert!(wait_finished(&download) >= Duration::from_millis(400));
+    assert_eq!(download.state(), DownloadState::Done);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn files_are_sent_whatever_the_writer() {
+    use crate::file_server::send_file;
+    use crate::upload::Uploads;
+    use std::io::Read;
+
+    let dir = std::env::temp_dir().join(format!("p2prs-send-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("big.bin");
+    // Several chunks, the last one partial
+    let contents: Vec<u8> = (0..600 * 1024).map(|i| (i % 251) as u8).collect();
+    std::fs::write(&path, &contents).unwrap();
+    let uploads = Uploads::new();
+    let size = contents.len() as u64;
+
+    // Zero-copy to a socket
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut sending = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let receiving = std::thread::spawn(move || {
+        let mut received = Vec::new();
+        listener
+            .accept()
+            .unwrap()
+            .0
+            .read_to_end(&mut received)
+            .unwrap();
+        received
+    });
+    let upload = uploads.start(uploads.slot().unwrap(), None, path.clone(), size);
+    let mut file = std::fs::File::open(&path).unwrap();
+    send_file(&mut file, size, &mut sending, &upload).unwrap();
+    drop(sending);
+    assert_eq!(receiving.join().unwrap(), contents);
+    assert_eq!(
+        uploads.list()[0]
+            .sent
+            .load(std::sync::atomic::Ordering::Relaxed),
+        size
+    );
+    drop(upload);
+
+    // Buffered copies to anything else
+    let upload = uploads.start(uploads.slot().unwrap(), None, path.clone(), size);
+    let mut file = std::fs::File::open(&path).unwrap();
+    let mut copied = Vec::new();
+    send_file(&mut file, size, &mut copied, &upload).unwrap();
+    assert_eq!(copied, contents);
+    drop(upload);
+
+    // Only what was advertised, even when the file changed since
+    let upload = uploads.start(uploads.slot().unwrap(), None, path.clone(), 1000);
+    let mut file = std::fs::File::open(&path).unwrap();
+    let mut copied = Vec::new();
+    send_file(&mut file, 1000, &mut copied, &upload).unwrap();
+    assert_eq!(copied, contents[..1000]);
+    let mut file = std::fs::File::open(&path).unwrap();
+    let sent = send_file(&mut file, size + 1, &mut Vec::new(), &upload);
+    assert_eq!(sent.unwrap_err().kind(), std::io::ErrorKind::UnexpectedEof);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn watched_share_follows_the_directory() {
+    use crate::watch::{self, ShareWatcher};
+
+    let dir = std::env::temp_dir().join(format!("p2prs-watch-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(dir.join("sub")).unwrap();
+    std::fs::write(dir.join("a.txt"), "a").unwrap();
+    let server = Arc::new(
+        FileServer::<SimpleFileSystem>::new(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0)).unwrap(),
+    );
+    let fs = &server.file_system;
+    assert!(watch::share_dir(fs, &dir).unwrap());
+    assert!(!watch::share_dir(fs, &dir).unwrap());
+    let changes = server.subscribe();
+    let watcher = ShareWatcher::new(std::slice::from_ref(&dir)).unwrap();
+    {
+        let server = Arc::clone(&server);
+        std::thread::spawn(move || watcher.run(&server));
+    }
+    let b = dir.join("sub").join("b.txt");
+    std::fs::write(&b, "b").unwrap();
+    wait_for("b.txt shared", &|| fs.sha256(&b).is_some());
+    assert!(changes.try_iter().count() > 0);
+
+    let a = dir.join("a.txt");
+    let before = fs.sha256(&a).unwrap();
+    std::fs::write(&a, "changed").unwrap();
+    wait_for("a.txt hashed again", &|| fs.sha256(&a) != Some(before));
+    assert!(changes.try_iter().count() > 0);
+    let size = fs
+        .list_files()
+        .into_iter()
+        .find(|f| f.path == a)
+        .unwrap()
+        .size;
+    assert_eq!(size, 7);
+
+    std::fs::remove_dir_all(dir.join("sub")).unwrap();
+    wait_for("sub unshared", &|| fs.sha256(&b).is_none());
+    assert_eq!(fs.list_files().len(), 1);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn backoff_doubles_up_to_its_cap_until_reset() {
+    use crate::tracker::Backoff;
+
+    let ms = Duration::from_millis;
+    let mut backoff = Backoff::new(ms(100), ms(500));
+    let delays: Vec<_> = (0..5).map(|_| backoff.next_delay()).collect();
+    assert_eq!(delays, [ms(100), ms(200), ms(400), ms(500), ms(500)]);
+    backoff.reset();
+    assert_eq!(backoff.next_delay(), ms(100));
+    assert_eq!(backoff.next_delay(), ms(200));
+}
+
+#[test]
+fn clients_register_again_when_the_tracker_restarts() {
+    use crate::tracker::{Backoff, TrackerServerContext};
+    use common::{AnyMessage, client, read_msg, server, write_msg};
+
+    let dir = std::env::temp_dir().join(format!("p2prs-restart-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("kept.txt"), "kept").unwrap();
+    let file_server = Arc::new(
+        FileServer::<SimpleFileSystem>::new(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0)).unwrap(),
+    );
+    file_server
+        .file_system
+        .add_path(dir.join("kept.txt"))
+        .unwrap();
+    let tracker = TcpListener::bind("127.0.0.1:0").unwrap();
+    let tracker_addr = ipv4(tracker.local_addr().unwrap()).unwrap();
+    let peers = Arc::new(Mutex::new(Peers::new()));
+    let ms = Duration::from_millis;
+    let mut track = TrackerServerContext::new(vec![tracker_addr], &file_server, &peers)
+        .with_backoff(Backoff::new(ms(10), ms(50)));
+    let link = track.link();
+    std::thread::spawn(move || {
+        while !track.link().is_disconnected() {
+            track.check_server_messages();
+        }
+    });
+    let other = peer("127.0.0.1:47001", &["other.txt"], Instant::now());
+    let registered = |tracker: &TcpListener| {
+        let (mut conn, _) = tracker.accept().unwrap();
+        conn.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        let AnyMessage::Client(client::Message::Connect(connect)) = read_msg(&mut conn).unwrap()
+        else {
+            panic!("expected Connect");
+        };
+        assert_eq!(connect.file_list, file_server.file_system.list_files());
+        let snapshot = server::PeerSnapshot {
+            peers: vec![server::PeerInfo {
+                sock: other.sock,
+                file_list: other.files.clone(),
+            }],
+        };
+        write_msg(&mut conn, &server::Message::from(snapshot)).unwrap();
+        wait_for("snapshot applied", &|| {
+            peers.lock().unwrap().get_peer(other.sock).is_some()
+        });
+        conn
+    };
+    let conn = registered(&tracker);
+
+    // The tracker goes down, and the peers it told about with it
+    drop(conn);
+    drop(tracker);
+    wait_for("tracker peers dropped", &|| {
+        peers.lock().unwrap().addrs().is_empty()
+    });
+    // Back on the same address, the client registers again with every shared file
+    let tracker = TcpListener::bind(tracker_addr).unwrap();
+    let mut conn = registered(&tracker);
+
+    link.disconnect().unwrap();
+    assert!(matches!(
+        read_msg(&mut conn).unwrap(),
+        AnyMessage::Client(client::Message::Disconnect(_))
+    ));
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn silent_trackers_are_reconnected() {
+    use crate::tracker::{Backoff, TrackerServerContext};
+    use common::{AnyMessage, client, read_msg, server, write_msg};
+
+    let file_server = Arc::new(
+        FileServer::<SimpleFileSystem>::new(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0)).unwrap(),
+    );
+    let tracker = TcpListener::bind("127.0.0.1:0").unwrap();
+    let tracker_addr = ipv4(tracker.local_addr().unwrap()).unwrap();
+    let peers = Arc::new(Mutex::new(Peers::new()));
+    let ms = Duration::from_millis;
+    let mut track = TrackerServerContext::new(vec![tracker_addr], &file_server, &peers)
+        .with_backoff(Backoff::new(ms(10), ms(50)))
+        .with_read_timeout(ms(200));
+    let link = track.link();
+    std::thread::spawn(move || {
+        while !track.link().is_disconnected() {
+            track.check_server_messages();
+        }
+    });
+    let other = peer("127.0.0.1:47101", &["other.txt"], Instant::now());
+    let (mut conn, _) = tracker.accept().unwrap();
+    conn.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+    assert!(matches!(
+        read_msg(&mut conn).unwrap(),
+        AnyMessage::Client(client::Message::Connect(_))
+    ));
+    let snapshot = server::PeerSnapshot {
+        peers: vec![server::PeerInfo {
+            sock: other.sock,
+            file_list: other.files.clone(),
+        }],
+    };
+    write_msg(&mut conn, &server::Message::from(snapshot)).unwrap();
+    wait_for("snapshot applied", &|| {
+        peers.lock().unwrap().get_peer(other.sock).is_some()
+    });
+
+    // The connection stays open but the tracker stops talking
+    let (mut again, _) = tracker.accept().unwrap();
+    assert!(peers.lock().unwrap().addrs().is_empty());
+    again
+        .set_read_timeout(Some(Duration::from_secs(2)))
+        .unwrap();
+    assert!(matches!(
+        read_msg(&mut again).unwrap(),
+        AnyMessage::Client(client::Message::Connect(_))
+    ));
+    link.disconnect().unwrap();
+    drop(conn);
+}
+
+#[test]
+fn trackers_sending_garbage_are_reconnected() {
+    use crate::tracker::{Backoff, TrackerServerContext};
+    use common::{AnyMessage, client, read_msg};
+    use std::io::Write;
+
+    let file_server = Arc::new(
+        FileServer::<SimpleFileSystem>::new(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0)).unwrap(),
+    );
+    let tracker = TcpListener::bind("127.0.0.1:0").unwrap();
+    let tracker_addr = ipv4(tracker.local_addr().unwrap()).unwrap();
+    let peers = Arc::new(Mutex::new(Peers::new()));
+    let ms = Duration::from_millis;
+    let mut track = TrackerServerContext::new(vec![tracker_addr], &file_server, &peers)
+        .with_backoff(Backoff::new(ms(10), ms(50)));
+    let link = track.link();
+    std::thread::spawn(move || {
+        while !track.link().is_disconnected() {
+            track.check_server_messages();
+        }
+    });
+    let registered = || {
+        let (mut conn, _) = tracker.accept().unwrap();
+        conn.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        assert!(matches!(
+            read_msg(&mut conn).unwrap(),
+            AnyMessage::Client(client::Message::Connect(_))
+        ));
+        conn
+    };
+    // An unknown message type, on a connection left open
+    let mut first = registered();
+    first.write_all(&[200]).unwrap();
+    let _second = registered();
+    link.disconnect().unwrap();
+}
+
+#[test]
+fn tracker_link_publishes_files_and_leaves() {
+    use crate::tracker::TrackerServerContext;
+    use common::{AnyMessage, client, read_msg, server, write_msg};
+
+    let tracker = TcpListener::bind("127.0.0.1:0").unwrap();
+    let tracker_addr = ipv4(tracker.local_addr().unwrap()).unwrap();
+    let file_server = Arc::new(
+        FileServer::<SimpleFileSystem>::new(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0)).unwrap(),
+    );
+    {
+        let serving = Arc::clone(&file_server);
+        std::thread::spawn(move || serving.serve());
+    }
+    let peers = Arc::new(Mutex::new(Peers::new()));
+    let changes = file_server.subscribe();
+    let mut track = TrackerServerContext::new(vec![tracker_addr], &file_server, &peers);
+    let link = track.link();
+    {
+        let link = Arc::clone(&link);
+        let file_server = Arc::clone(&file_server);
+        std::thread::spawn(move || link.publish_files(&file_server, changes));
+    }
+    let running = std::thread::spawn(move || {
+        while !track.link().is_disconnected() {
+            track.check_server_messages();
+        }
+    });
+    let (mut conn, _) = tracker.accept().unwrap();
+    conn.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+    assert!(matches!(
+        read_msg(&mut conn).unwrap(),
+        AnyMessage::Client(client::Message::Connect(_))
+    ));
+    write_msg(&mut conn, &server::Message::from(server::Ping)).unwrap();
+    assert!(matches!(
+        read_msg(&mut conn).unwrap(),
+        AnyMessage::Client(client::Message::Pong(_))
+    ));
+
+    // Published right away, without waiting for the tracker to say anything
+    let dir = std::env::temp_dir().join(format!("p2prs-publish-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("new.txt"), "new").unwrap();
+    file_server
+        .file_system
+        .add_path(dir.join("new.txt"))
+        .unwrap();
+    file_server.files_changed();
+    match read_msg(&mut conn).unwrap() {
+        AnyMessage::Client(client::Message::UpdateFiles(u)) => {
+            assert_eq!(u.file_list.len(), 1);
+        }
+        m => panic!("unexpected message {m:?}"),
+    }
+
+    link.disconnect().unwrap();
+    assert!(matches!(
+        read_msg(&mut conn).unwrap(),
+        AnyMessage::Client(client::Message::Disconnect(_))
+    ));
+    assert!(read_msg(&mut conn).is_err());
+    // The tracker thread stops instead of reconnecting
+    running.join().unwrap();
+    tracker.set_nonblocking(true).unwrap();
+    assert!(tracker.accept().is_err());
+
+    file_server.stop();
+    let server_addr = ipv4(file_server.server.local_addr().unwrap()).unwrap();
+    // The connection is closed without an answer
+    let answered = crate::download::request_file(server_addr, None, dir.join("new.txt")).and_then(
+        |mut stream| crate::download::read_answer(&mut stream, server_addr, dir.join("new.txt")),
+    );
+    assert!(answered.is_err());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+/// Skips events until one matches `wanted`
+fn next_event(
+    events: &std::sync::mpsc::Receiver<crate::events::Event>,
+    wanted: impl Fn(&crate::events::Event) -> bool,
+) -> crate::events::Event {
+    loop {
+        let event = events.recv_timeout(Duration::from_secs(2)).unwrap();
+        if wanted(&event) {
+            return event;
+        }
+    }
+}
+
+#[test]
+fn embedded_clients_find_providers_through_the_dht() {
+    use crate::ClientBuilder;
+
+    let dir = std::env::temp_dir().join(format!("p2prs-dht-download-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(dir.join("a")).unwrap();
+    std::fs::create_dir_all(dir.join("b")).unwrap();
+    std::fs::write(dir.join("a/song.mp3"), "not really a song").unwrap();
+    let start = |share: &str, bootstrap: Vec<SocketAddrV4>| {
+        ClientBuilder::new()
+            .with_share(dir.join(share))
+            .with_watch(false)
+            .with_dht(bootstrap)
+            .start()
+            .unwrap()
+    };
+    let a = start("a", vec![]);
+    let b = start("b", vec![a.addr().unwrap()]);
+
+    // No tracker nor known peer, only the DHT
+    let song = dir.join("a/song.mp3");
+    let deadline = Instant::now() + Duration::from_secs(5);
+    let download = loop {
+        match b.download(&song, dir.join("song.mp3")) {
+            Ok(download) => break download,
+            Err(crate::ClientError::NoProvider(_)) => {}
+            Err(e) => panic!("{e}"),
+        }
+        assert!(Instant::now() < deadline, "the DHT never found a");
+        std::thread::sleep(Duration::from_millis(50));
+    };
+    assert_eq!(download.peer, a.addr().unwrap());
+    wait_for("download finished", &|| download.finished());
+    assert_eq!(
+        std::fs::read_to_string(dir.join("song.mp3")).unwrap(),
+        "not really a song"
+    );
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn embedded_clients_download_from_each_other() {
+    use crate::events::Event;
+    use crate::{ClientBuilder, download::DownloadState};
+    use common::{AnyMessage, client, read_msg, server, write_msg};
+
+    let dir = std::env::temp_dir().join(format!("p2prs-embedded-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(dir.join("a")).unwrap();
+    std::fs::create_dir_all(dir.join("b")).unwrap();
+    std::fs::write(dir.join("a/song.mp3"), "not really a song").unwrap();
+
+    let tracker = TcpListener::bind("127.0.0.1:0").unwrap();
+    let tracker_addr = ipv4(tracker.local_addr().unwrap()).unwrap();
+    let start = |share: &str| {
+        ClientBuilder::new()
+            .with_share(dir.join(share))
+            .with_watch(false)
+            .with_tracker(tracker_addr)
+            .start()
+            .unwrap()
+    };
+    let a = start("a");
+    let b = start("b");
+    let events = b.subscribe();
+    let uploads = Arc::new(Mutex::new(Vec::new()));
+    {
+        let uploads = Arc::clone(&uploads);
+        a.on_event(move |event| {
+            if let Event::UploadStarted { .. } | Event::UploadFinished { .. } = event {
+                uploads.lock().unwrap().push(event.clone());
+            }
+        });
+    }
+    assert!(matches!(
+        ClientBuilder::new()
+            .with_share(dir.join("a/song.mp3"))
+            .start(),
+        Err(crate::ClientError::NotADirectory(_))
+    ));
+
+    // The tracker tells each client about the other
+    let mut registered = Vec::new();
+    for _ in 0..2 {
+        let (mut conn, _) = tracker.accept().unwrap();
+        let AnyMessage::Client(client::Message::Connect(connect)) = read_msg(&mut conn).unwrap()
+        else {
+            panic!("expected Connect");
+        };
+        let sock = SocketAddrV4::new(Ipv4Addr::LOCALHOST, connect.serve_port);
+        registered.push((
+            conn,
+            server::PeerInfo {
+                sock,
+                file_list: connect.file_list,
+            },
+        ));
+    }
+    // a first, b second
+    registered.sort_by_key(|(_, peer)| peer.sock != a.addr().unwrap());
+    for i in 0..2 {
+        let other = registered[1 - i].1.clone();
+        let snapshot = server::PeerSnapshot { peers: vec![other] };
+        write_msg(&mut registered[i].0, &server::Message::from(snapshot)).unwrap();
+    }
+
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while b.files().is_empty() {
+        assert!(Instant::now() < deadline, "b never learned about a's files");
+        std::thread::sleep(Duration::from_millis(20));
+    }
+    let song = dir.join("a/song.mp3");
+    let files = b.files();
+    assert_eq!(files[0].path, song);
+    assert_eq!(files[0].peers, [a.addr().unwrap()]);
+    assert_eq!(b.peers()[0].sock, a.addr().unwrap());
+    assert!(matches!(
+        b.download(dir.join("missing"), dir.join("b/missing")),
+        Err(crate::ClientError::NoProvider(_))
+    ));
+
+    let download = b.download(&song, dir.join("song.mp3")).unwrap();
+    while !download.finished() {
+        assert!(Instant::now() < deadline, "the download never finished");
+        std::thread::sleep(Duration::from_millis(20));
+    }
+    assert_eq!(download.state(), DownloadState::Done);
+    assert_eq!(
+        std::fs::read(dir.join("song.mp3")).unwrap(),
+        std::fs::read(&song).unwrap()
+    );
+    let a_addr = a.addr().unwrap();
+    next_event(
+        &events,
+        |e| matches!(e, Event::PeerJoined(p) if p.sock == a_addr),
+    );
+    next_event(
+        &events,
+        |e| matches!(e, Event::FileAvailable { path, size: 17, peer } if *path == song && *peer == a_addr),
+    );
+    next_event(&events, |e| {
+        matches!(
+            e,
+            Event::DownloadProgress {
+                received: 17,
+                state: DownloadState::Done,
+                ..
+            }
+        )
+    });
+    while uploads.lock().unwrap().len() < 2 {
+        assert!(Instant::now() < deadline, "a never finished uploading");
+        std::thread::sleep(Duration::from_millis(20));
+    }
+    assert!(matches!(
+        &uploads.lock().unwrap()[..],
+        [
+            Event::UploadStarted { size: 17, .. },
+            Event::UploadFinished { sent: 17, .. },
+        ]
+    ));
+
+    std::fs::write(dir.join("b/notes.txt"), "notes").unwrap();
+    b.share(dir.join("b/notes.txt")).unwrap();
+    assert_eq!(b.shared().len(), 1);
+    next_event(&events, |e| *e == Event::SharedFilesChanged);
+    // Published to the tracker
+    registered[1]
+        .0
+        .set_read_timeout(Some(Duration::from_secs(2)))
+        .unwrap();
+    assert!(matches!(
+        read_msg(&mut registered[1].0).unwrap(),
+        AnyMessage::Client(client::Message::UpdateFiles(_))
+    ));
+
+    b.stop();
+    b.wait().unwrap();
+    next_event(&events, |e| *e == Event::Stopped);
+    assert!(matches!(
+        read_msg(&mut registered[1].0).unwrap(),
+        AnyMessage::Client(client::Message::Disconnect(_))
+    ));
+    a.stop();
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn clients_start_without_waiting_for_trackers() {
+    use crate::ClientBuilder;
+
+    let dir = std::env::temp_dir().join(format!("p2prs-no-tracker-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    // Nobody listens there once the listener is dropped
+    let down = ipv4(
+        TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap(),
+    )
+    .unwrap();
+    let started = Instant::now();
+    let client = ClientBuilder::new()
+        .with_share(&dir)
+        .with_watch(false)
+        .with_tracker(down)
+        .start()
+        .unwrap();
+    assert!(started.elapsed() < Duration::from_secs(1));
+    client.stop();
+    client.wait().unwrap();
+}
@@ -1,37 +1,246 @@
-use crate::ClientError;
+use crate::events::{Event, Events};
 
 use super::file_server::{FileServer, FileSystem};
 use common::*;
-use std::collections::HashMap;
-use std::net::{SocketAddrV4, TcpStream};
-use std::sync::Arc;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::net::{Shutdown, SocketAddrV4, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Receiver;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Peer {
-    sock: SocketAddrV4,
-    pub _files: Vec<File>,
+    pub sock: SocketAddrV4,
+    pub files: Vec<File>,
+    pub updated: Instant,
+}
+
+/// A file peers share, with every peer sharing it by address
+#[derive(Debug, Clone, PartialEq)]
+pub struct RemoteFile {
+    pub path: PathBuf,
+    pub size: u64,
+    pub peers: Vec<SocketAddrV4>,
 }
 
+/// Where knowledge about a peer came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PeerSource {
+    Tracker(SocketAddrV4),
+    /// Through [peer exchange](crate::pex) with the peer whose file server is at this address
+    Exchange(SocketAddrV4),
+    /// Announced itself on the [LAN](crate::lan)
+    Lan,
+}
+
+impl std::fmt::Display for PeerSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PeerSource::Tracker(addr) => write!(f, "tracker {addr}"),
+            PeerSource::Exchange(addr) => write!(f, "exchange {addr}"),
+            PeerSource::Lan => write!(f, "lan"),
+        }
+    }
+}
+
+/// Every known peer, kept separately for each source they were learned from so one source
+/// forgetting a peer doesn't drop it while others still know about it
 #[derive(Default)]
 pub struct Peers {
-    full: HashMap<SocketAddrV4, Peer>,
+    by_source: HashMap<PeerSource, HashMap<SocketAddrV4, Peer>>,
+    events: Option<Arc<Events>>,
 }
 
 impl Peers {
-    fn new() -> Self {
+    pub fn new() -> Self {
         Self::default()
     }
-    fn add_peer(&mut self, peer: Peer) -> Option<Peer> {
-        self.full.insert(peer.sock, peer)
+    /// Tells `events` about peers joining, changing and leaving
+    pub fn with_events(mut self, events: Arc<Events>) -> Self {
+        self.events = Some(events);
+        self
+    }
+    pub fn add_peer(&mut self, source: PeerSource, peer: Peer) -> Option<Peer> {
+        let before = self.views([peer.sock]);
+        let old = self
+            .by_source
+            .entry(source)
+            .or_default()
+            .insert(peer.sock, peer);
+        self.changed(before);
+        old
+    }
+    pub fn update_peer(&mut self, source: PeerSource, new_peer: Peer) {
+        self.add_peer(source, new_peer);
+    }
+    pub fn remove_peer(&mut self, source: PeerSource, sock: SocketAddrV4) -> Option<Peer> {
+        let before = self.views([sock]);
+        let removed = self.by_source.get_mut(&source)?.remove(&sock);
+        self.changed(before);
+        removed
+    }
+    pub fn clear(&mut self, source: PeerSource) {
+        let before = self.views(self.source_addrs(source));
+        self.by_source.remove(&source);
+        self.changed(before);
+    }
+    pub fn replace(&mut self, source: PeerSource, peers: impl IntoIterator<Item = Peer>) {
+        let peers: HashMap<_, _> = peers.into_iter().map(|p| (p.sock, p)).collect();
+        let affected = self.source_addrs(source).chain(peers.keys().copied());
+        let before = self.views(affected.collect::<Vec<_>>());
+        self.by_source.insert(source, peers);
+        self.changed(before);
+    }
+    fn source_addrs(&self, source: PeerSource) -> impl Iterator<Item = SocketAddrV4> + '_ {
+        self.by_source
+            .get(&source)
+            .into_iter()
+            .flat_map(|peers| peers.keys().copied())
+    }
+    /// How every peer of `socks` is seen before a change, when there's anyone to tell about it
+    fn views(
+        &self,
+        socks: impl IntoIterator<Item = SocketAddrV4>,
+    ) -> Vec<(SocketAddrV4, Option<Peer>)> {
+        if self.events.is_none() {
+            return Vec::new();
+        }
+        let socks: HashSet<_> = socks.into_iter().collect();
+        socks
+            .into_iter()
+            .map(|sock| (sock, self.get_peer(sock).cloned()))
+            .collect()
+    }
+    /// Tells about the peers whose view changed since `before`
+    fn changed(&self, before: Vec<(SocketAddrV4, Option<Peer>)>) {
+        let Some(events) = &self.events else {
+            return;
+        };
+        for (sock, before) in before {
+            let after = self.get_peer(sock);
+            let known_files = match (&before, after) {
+                (None, Some(peer)) => {
+                    events.emit(Event::PeerJoined(peer.clone()));
+                    &[][..]
+                }
+                (Some(before), Some(peer)) if before.files != peer.files => {
+                    events.emit(Event::PeerUpdated(peer.clone()));
+                    &before.files[..]
+                }
+                (Some(_), None) => {
+                    events.emit(Event::PeerLeft(sock));
+                    continue;
+                }
+                _ => continue,
+            };
+            let peer = after.expect("the peer is known");
+            for file in &peer.files {
+                if !known_files.iter().any(|f| f.path == file.path) {
+                    events.emit(Event::FileAvailable {
+                        path: file.path.clone(),
+                        size: file.size,
+                        peer: sock,
+                    });
+                }
+            }
+        }
+    }
+    /// The most recently updated view of `sock` among all sources
+    pub fn get_peer(&self, sock: SocketAddrV4) -> Option<&Peer> {
+        self.by_source
+            .values()
+            .filter_map(|peers| peers.get(&sock))
+            .max_by_key(|p| p.updated)
+    }
+    /// Every peer known from any source
+    pub fn addrs(&self) -> HashSet<SocketAddrV4> {
+        self.by_source
+            .values()
+            .flat_map(HashMap::keys)
+            .copied()
+            .collect()
+    }
+    /// Every file known peers share, by path
+    pub fn files(&self) -> Vec<RemoteFile> {
+        let mut files: BTreeMap<&Path, RemoteFile> = BTreeMap::new();
+        let addrs = self.addrs();
+        for peer in addrs.iter().filter_map(|sock| self.get_peer(*sock)) {
+            for file in &peer.files {
+                let entry = files.entry(&file.path).or_insert_with(|| RemoteFile {
+                    path: file.path.clone(),
+                    size: file.size,
+                    peers: Vec::new(),
+                });
+                entry.peers.push(peer.sock);
+            }
+        }
+        let mut files: Vec<_> = files.into_values().collect();
+        for file in &mut files {
+            file.peers.sort();
+        }
+        files
+    }
+    /// The first peer, by address, sharing `path`
+    pub fn provider(&self, path: &Path) -> Option<SocketAddrV4> {
+        let mut addrs: Vec<_> = self.addrs().into_iter().collect();
+        addrs.sort();
+        addrs.into_iter().find(|sock| {
+            self.get_peer(*sock)
+                .is_some_and(|p| p.files.iter().any(|f| f.path == path))
+        })
     }
-    fn update_peer(&mut self, new_peer: Peer) {
-        self.full.insert(new_peer.sock, new_peer);
+    /// A tracker `sock` is connected to, which can relay to it
+    pub fn relay(&self, sock: SocketAddrV4) -> Option<SocketAddrV4> {
+        let mut trackers = self.sources(sock).filter_map(|source| match source {
+            PeerSource::Tracker(tracker) => Some(tracker),
+            _ => None,
+        });
+        trackers.next()
     }
-    fn remove_peer(&mut self, sock: SocketAddrV4) -> Option<Peer> {
-        self.full.remove(&sock)
+    /// Sources that currently know about `sock`
+    pub fn sources(&self, sock: SocketAddrV4) -> impl Iterator<Item = PeerSource> + '_ {
+        self.by_source
+            .iter()
+            .filter(move |(_, peers)| peers.contains_key(&sock))
+            .map(|(source, _)| *source)
     }
-    fn _get_peer(&mut self, sock: SocketAddrV4) -> Option<&Peer> {
-        self.full.get(&sock)
+    /// Every known peer with how long ago it was last known to be alive, peers a tracker
+    /// currently knows about are alive now
+    pub fn liveness(&self) -> Vec<(Peer, Duration)> {
+        self.addrs()
+            .into_iter()
+            .filter_map(|sock| {
+                let peer = self.get_peer(sock)?.clone();
+                let tracked = self
+                    .sources(sock)
+                    .any(|s| matches!(s, PeerSource::Tracker(_)));
+                let age = if tracked {
+                    Duration::ZERO
+                } else {
+                    peer.updated.elapsed()
+                };
+                Some((peer, age))
+            })
+            .collect()
+    }
+    /// Forgets peers learned through peer exchange that weren't seen alive for `max_age`
+    pub fn expire_exchanged(&mut self, max_age: Duration) {
+        let exchanged: Vec<_> = self
+            .by_source
+            .iter()
+            .filter(|(source, _)| matches!(source, PeerSource::Exchange(_)))
+            .flat_map(|(_, peers)| peers.keys().copied())
+            .collect();
+        let before = self.views(exchanged);
+        for (source, peers) in &mut self.by_source {
+            if matches!(source, PeerSource::Exchange(_)) {
+                peers.retain(|_, p| p.updated.elapsed() <= max_age);
+            }
+        }
+        self.by_source.retain(|_, peers| !peers.is_empty());
+        self.changed(before);
     }
 }
 
@@ -39,7 +248,18 @@ impl From<server::RegisterPeer> for Peer {
     fn from(server::RegisterPeer { sock, file_list }: server::RegisterPeer) -> Self {
         Peer {
             sock,
-            _files: file_list,
+            files: file_list,
+            updated: Instant::now(),
+        }
+    }
+}
+
+impl From<server::PeerInfo> for Peer {
+    fn from(server::PeerInfo { sock, file_list }: server::PeerInfo) -> Self {
+        Peer {
+            sock,
+            files: file_list,
+            updated: Instant::now(),
         }
     }
 }
@@ -48,58 +268,336 @@ impl From<server::UpdatePeer> for Peer {
     fn from(server::UpdatePeer { sock, file_list }: server::UpdatePeer) -> Self {
         Peer {
             sock,
-            _files: file_list,
+            files: file_list,
+            updated: Instant::now(),
+        }
+    }
+}
+
+/// Exponential backoff between reconnection attempts
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    current: Duration,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self::new(Duration::from_millis(250), Duration::from_secs(30))
+    }
+}
+
+impl Backoff {
+    pub fn new(initial: Duration, max: Duration) -> Self {
+        Self {
+            initial,
+            max,
+            current: initial,
+        }
+    }
+    /// Returns the delay to wait before the next attempt and doubles it, up to `max`
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.current;
+        self.current = std::cmp::min(self.current * 2, self.max);
+        delay
+    }
+    pub fn reset(&mut self) {
+        self.current = self.initial;
+    }
+}
+
+/// Sending half of the connection to a tracker, through which the shared files are published
+/// and the client can leave while the tracker thread is waiting for messages
+#[derive(Default)]
+pub struct TrackerLink {
+    stream: Mutex<Option<TcpStream>>,
+    disconnected: AtomicBool,
+}
+
+impl TrackerLink {
+    fn send(&self, msg: &client::Message) -> Result<(), CommonError> {
+        match self.stream.lock().unwrap().as_mut() {
+            Some(stream) => write_msg(stream, msg),
+            None => Err(std::io::Error::from(std::io::ErrorKind::NotConnected).into()),
+        }
+    }
+
+    /// Sends `Connect` through `stream` and keeps sending through it from now on, unless we
+    /// already left
+    fn connect<FS: FileSystem>(
+        &self,
+        stream: &TcpStream,
+        file_server: &FileServer<FS>,
+    ) -> Result<(), CommonError> {
+        let mut current = self.stream.lock().unwrap();
+        if self.is_disconnected() {
+            return Err(std::io::Error::from(std::io::ErrorKind::NotConnected).into());
+        }
+        let mut stream = stream.try_clone()?;
+        // Listed while holding the lock, so the tracker never gets an older list after a newer
+        let connect = client::Connect {
+            serve_port: file_server.server.local_addr()?.port(),
+            file_list: file_server.file_system.list_files(),
+        };
+        write_msg(&mut stream, &client::Message::from(connect))?;
+        *current = Some(stream);
+        Ok(())
+    }
+
+    /// Sends the tracker [`client::UpdateFiles`] every time `changes` says the shared files
+    /// changed, until we leave
+    pub fn publish_files<FS: FileSystem>(
+        &self,
+        file_server: &FileServer<FS>,
+        changes: Receiver<()>,
+    ) {
+        while changes.recv().is_ok() {
+            // Several changes in a row are published at once
+            changes.try_iter().for_each(drop);
+            if self.is_disconnected() {
+                return;
+            }
+            let mut current = self.stream.lock().unwrap();
+            let Some(stream) = current.as_mut() else {
+                continue;
+            };
+            let file_list = file_server.file_system.list_files();
+            let update = client::Message::from(client::UpdateFiles { file_list });
+            if let Err(e) = write_msg(stream, &update) {
+                // The tracker thread reconnects, sending the files with `Connect`
+                tracing::debug!(error = %e, "failed to publish files");
+            }
         }
     }
+
+    /// Tells the tracker we're leaving with [`client::Disconnect`] and closes the connection,
+    /// the tracker thread then stops instead of reconnecting
+    pub fn disconnect(&self) -> Result<(), CommonError> {
+        let mut current = self.stream.lock().unwrap();
+        self.disconnected.store(true, Ordering::Relaxed);
+        let Some(mut stream) = current.take() else {
+            return Ok(());
+        };
+        let sent = write_msg(&mut stream, &client::Message::from(client::Disconnect));
+        let _ = stream.shutdown(Shutdown::Both);
+        sent
+    }
+
+    pub fn is_disconnected(&self) -> bool {
+        self.disconnected.load(Ordering::Relaxed)
+    }
 }
 
+/// Trackers ping every 5 seconds by default, a connection missing three pings in a row is
+/// half-open
+const READ_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Connection to one tracker, falling over to the next address of `trackers` (in priority
+/// order) when the current one can't be reached
 pub struct TrackerServerContext<FS: FileSystem> {
-    peers: Peers,
-    server: TcpStream,
+    peers: Arc<Mutex<Peers>>,
+    trackers: Vec<SocketAddrV4>,
+    tracker_addr: SocketAddrV4,
+    server: Option<TcpStream>,
+    link: Arc<TrackerLink>,
     file_server: Arc<FileServer<FS>>,
+    backoff: Backoff,
+    read_timeout: Duration,
 }
 
-impl<FS: FileSystem> TrackerServerContext<FS> {
-    fn handle_message(&mut self, msg: AnyMessage) {
+impl<FS: FileSystem + Send + Sync + 'static> TrackerServerContext<FS> {
+    fn source(&self) -> PeerSource {
+        PeerSource::Tracker(self.tracker_addr)
+    }
+
+    fn handle_message(&mut self, msg: AnyMessage) -> Result<(), CommonError> {
+        tracing::debug!(tracker = %self.tracker_addr, msg = ?msg, "received message");
+        let source = self.source();
+        let mut peers = self.peers.lock().unwrap();
         match msg {
             AnyMessage::Server(server::Message::RegisterPeer(p)) => {
-                self.peers.add_peer(p.into());
+                peers.add_peer(source, p.into());
             }
             AnyMessage::Server(server::Message::UpdatePeer(p)) => {
-                self.peers.update_peer(p.into());
+                peers.update_peer(source, p.into());
             }
             AnyMessage::Server(server::Message::UnregisterPeer(p)) => {
-                self.peers.remove_peer(p.sock).unwrap();
+                peers.remove_peer(source, p.sock);
+            }
+            AnyMessage::Server(server::Message::PeerSnapshot(s)) => {
+                peers.replace(source, s.peers.into_iter().map(Peer::from));
+                // Registered, so the tracker is willing to serve us again
+                self.backoff.reset();
+            }
+            AnyMessage::Server(server::Message::Ping(_)) => {
+                self.link.send(&client::Message::from(client::Pong))?;
+            }
+            AnyMessage::Server(server::Message::Shutdown(_)) => {
+                tracing::info!(tracker = %self.tracker_addr, "tracker is shutting down");
+                return Err(std::io::Error::from(std::io::ErrorKind::ConnectionAborted).into());
             }
-            AnyMessage::Client(client::Message::RequestFile(f)) => {
-                todo!("Serve file to {f:?}")
+            AnyMessage::Server(server::Message::Refused(server::Refused { reason })) => {
+                tracing::warn!(tracker = %self.tracker_addr, reason, "tracker refused us");
+                return Err(std::io::Error::from(std::io::ErrorKind::ConnectionAborted).into());
+            }
+            AnyMessage::Server(server::Message::RelayIncoming(server::RelayIncoming {
+                session,
+            })) => {
+                // Connected back on its own thread, a failed relay only loses that download
+                let tracker = self.tracker_addr;
+                let file_server = Arc::clone(&self.file_server);
+                std::thread::spawn(move || {
+                    let relayed = (|| {
+                        let mut relayed = TcpStream::connect(tracker)?;
+                        let accept = client::Message::from(client::RelayAccept { session });
+                        write_msg(&mut relayed, &accept)?;
+                        Ok::<_, CommonError>(relayed)
+                    })();
+                    match relayed {
+                        Ok(relayed) => file_server.accept_relayed(relayed),
+                        Err(e) => {
+                            tracing::warn!(%tracker, session, error = %e, "failed to accept a relayed download")
+                        }
+                    }
+                });
+            }
+            m => {
+                tracing::warn!(tracker = %self.tracker_addr, msg = ?m, "ignoring unexpected message");
+            }
+        }
+        Ok(())
+    }
+
+    /// Connection to the first tracker of `trackers` that answers, made by the first
+    /// [`check_server_messages`](Self::check_server_messages) so the caller never waits for it
+    ///
+    /// # Panics
+    ///
+    /// When `trackers` is empty
+    pub fn new(
+        trackers: Vec<SocketAddrV4>,
+        fsrv: &Arc<FileServer<FS>>,
+        peers: &Arc<Mutex<Peers>>,
+    ) -> Self {
+        Self {
+            peers: Arc::clone(peers),
+            tracker_addr: trackers[0],
+            trackers,
+            server: None,
+            link: Arc::default(),
+            file_server: Arc::clone(fsrv),
+            backoff: Backoff::default(),
+            read_timeout: READ_TIMEOUT,
+        }
+    }
+
+    pub fn with_backoff(mut self, backoff: Backoff) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    /// Reconnects when the tracker says nothing for `timeout`, a few of its Ping intervals
+    pub fn with_read_timeout(mut self, timeout: Duration) -> Self {
+        self.read_timeout = timeout;
+        self
+    }
+
+    pub fn link(&self) -> Arc<TrackerLink> {
+        Arc::clone(&self.link)
+    }
+
+    /// Connects to the highest priority tracker that answers and sends it `Connect`, waiting
+    /// with exponential backoff between attempts until one does or we leave
+    fn connect(&mut self) {
+        while let Some((addr, stream)) =
+            connect_with_backoff(&self.trackers, &mut self.backoff, &self.link)
+        {
+            self.tracker_addr = addr;
+            let connected = stream
+                .set_read_timeout(Some(self.read_timeout))
+                .map_err(CommonError::from)
+                .and_then(|()| self.link.connect(&stream, &self.file_server));
+            match connected {
+                Ok(()) => {
+                    self.server = Some(stream);
+                    return;
+                }
+                Err(_) if self.link.is_disconnected() => return,
+                Err(e) => {
+                    tracing::warn!(tracker = %addr, error = %e, "failed to register with tracker");
+                    std::thread::sleep(self.backoff.next_delay());
+                }
             }
-            m => panic!("can't handle msg {m:?}"),
         }
     }
 
-    pub fn new(srv: SocketAddrV4, fsrv: &Arc<FileServer<FS>>) -> Result<Self, ClientError> {
-        let track_server = std::net::TcpStream::connect(srv).unwrap();
-        //track_server.set_nonblocking(true)?;
+    /// Reconnects to the highest priority tracker that answers. The backoff is only reset
+    /// once a tracker registers us, so trackers refusing us with [`server::Refused`] are
+    /// retried less and less often.
+    ///
+    /// The peers learned from the previous tracker are dropped, since the tracker answers the
+    /// new `Connect` with a [`server::PeerSnapshot`] of every peer it still knows about.
+    fn reconnect(&mut self) {
+        tracing::warn!(tracker = %self.tracker_addr, "lost the tracker, reconnecting");
+        self.peers.lock().unwrap().clear(self.source());
+        if let Some(server) = self.server.take() {
+            let _ = server.shutdown(Shutdown::Both);
+        }
+        if self.link.is_disconnected() {
+            return;
+        }
+        std::thread::sleep(self.backoff.next_delay());
+        self.connect();
+    }
 
-        let file_server = Arc::clone(fsrv);
-        let mut slf = Self {
-            peers: Peers::new(),
-            server: track_server,
-            file_server,
+    /// Waits for the next message from the tracker and handles it, connecting first when not
+    /// connected yet and reconnecting when the connection breaks or the tracker sends
+    /// something we can't make sense of
+    pub fn check_server_messages(&mut self) {
+        if self.server.is_none() {
+            self.connect();
+        }
+        let Some(server) = self.server.as_mut() else {
+            return;
         };
-        let connect_msg = client::Message::Connect(client::Connect {
-            serve_port: fsrv.server.local_addr()?.port(),
-            file_list: slf.file_server.file_system.list_files(),
-        });
-        write_msg(&mut slf.server, &connect_msg)?;
-        Ok(slf)
+        let handled = read_msg(server)
+            .map_err(CommonError::from)
+            .and_then(|m| self.handle_message(m));
+        match handled {
+            Ok(()) => {}
+            Err(_) if self.link.is_disconnected() => {}
+            Err(e) if e.is_disconnect() => self.reconnect(),
+            Err(e) => {
+                tracing::warn!(tracker = %self.tracker_addr, error = %e, "bad message from tracker");
+                self.reconnect();
+            }
+        }
     }
+}
 
-    pub fn check_server_messages(&mut self) -> Result<(), ClientError> {
-        if let Some(m) = read_msg_nb(&mut self.server).map_err(CommonError::from)? {
-            self.handle_message(m);
+/// Tries every address in order, waiting with `backoff` after each round where none answered,
+/// until one does or `link` is disconnected
+fn connect_with_backoff(
+    addrs: &[SocketAddrV4],
+    backoff: &mut Backoff,
+    link: &TrackerLink,
+) -> Option<(SocketAddrV4, TcpStream)> {
+    while !link.is_disconnected() {
+        for &addr in addrs {
+            match TcpStream::connect(addr) {
+                Ok(stream) => {
+                    tracing::info!(tracker = %addr, "connected to tracker");
+                    return Some((addr, stream));
+                }
+                Err(e) => {
+                    tracing::warn!(tracker = %addr, error = %e, "failed to connect to tracker")
+                }
+            }
         }
-        Ok(())
+        let delay = backoff.next_delay();
+        tracing::warn!(?delay, "no tracker reachable, retrying");
+        std::thread::sleep(delay);
     }
+    None
 }
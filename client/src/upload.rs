@@ -0,0 +1,266 @@
+//! Files being sent to peers, which can be followed while they're sent.
+//!
+//! Uploads take one of a limited number of slots, requests arriving while every slot is taken
+//! wait in a queue for one in the order they arrived. How fast uploads send can be limited,
+//! all together and to each peer's IP.
+
+use crate::events::{Event, Events};
+use common::rate::RateLimit;
+use std::collections::{HashMap, VecDeque};
+use std::net::{IpAddr, SocketAddr};
+use std::num::NonZeroU64;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+/// Per-peer rate limits kept before forgetting those of idle peers
+const TRACKED_PEERS: usize = 1024;
+
+#[derive(Debug, Clone, Default)]
+pub struct UploadLimits {
+    /// Uploads running at once, unlimited when `None`
+    pub slots: Option<usize>,
+    /// Requests waiting for a slot, those arriving while the queue is full are answered
+    /// with [`Busy`](common::client::Busy)
+    pub queue: usize,
+    /// Bytes per second sent by every upload together
+    pub rate: Option<NonZeroU64>,
+    /// Bytes per second sent to each peer IP, relayed uploads are only subject to `rate`
+    pub peer_rate: Option<NonZeroU64>,
+}
+
+#[derive(Debug)]
+pub struct Upload {
+    pub id: u64,
+    /// `None` for uploads relayed through a tracker
+    pub peer: Option<SocketAddr>,
+    pub path: PathBuf,
+    pub size: u64,
+    /// Bytes sent so far
+    pub sent: AtomicU64,
+}
+
+/// Every upload running, by id
+#[derive(Default)]
+pub struct Uploads {
+    next_id: AtomicU64,
+    active: Arc<Mutex<HashMap<u64, Arc<Upload>>>>,
+    slots: Arc<Slots>,
+    rates: Arc<Rates>,
+    events: Option<Arc<Events>>,
+}
+
+#[derive(Default)]
+struct Slots {
+    max: Option<usize>,
+    queue: usize,
+    state: Mutex<SlotState>,
+    freed: Condvar,
+}
+
+#[derive(Default)]
+struct SlotState {
+    taken: usize,
+    /// Tickets of the requests waiting, in the order they arrived
+    waiting: VecDeque<u64>,
+    next_ticket: u64,
+}
+
+#[derive(Default)]
+struct Rates {
+    total: Option<Mutex<RateLimit>>,
+    peer_rate: Option<NonZeroU64>,
+    peers: Mutex<HashMap<IpAddr, RateLimit>>,
+}
+
+/// An upload slot, given back when dropped
+pub struct Slot {
+    slots: Arc<Slots>,
+}
+
+/// Keeps an upload listed, and its slot taken, until dropped
+pub struct ActiveUpload {
+    upload: Arc<Upload>,
+    active: Arc<Mutex<HashMap<u64, Arc<Upload>>>>,
+    rates: Arc<Rates>,
+    events: Option<Arc<Events>>,
+    _slot: Slot,
+}
+
+impl Uploads {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Tells `events` when uploads start and finish
+    pub fn with_events(mut self, events: Arc<Events>) -> Self {
+        self.events = Some(events);
+        self
+    }
+
+    pub fn with_limits(mut self, limits: UploadLimits) -> Self {
+        self.slots = Arc::new(Slots {
+            max: limits.slots,
+            queue: limits.queue,
+            ..Slots::default()
+        });
+        self.rates = Arc::new(Rates {
+            total: limits
+                .rate
+                .map(|rate| Mutex::new(RateLimit::new(rate.get()))),
+            peer_rate: limits.peer_rate,
+            ..Rates::default()
+        });
+        self
+    }
+
+    /// Takes a slot, waiting in the queue for one while they're all taken. Returns `None`
+    /// right away when the queue is full too.
+    pub fn slot(&self) -> Option<Slot> {
+        let slots = &self.slots;
+        let mut state = slots.state.lock().unwrap();
+        let Some(max) = slots.max else {
+            state.taken += 1;
+            return Some(Slot {
+                slots: Arc::clone(slots),
+            });
+        };
+        if state.taken >= max || !state.waiting.is_empty() {
+            if state.waiting.len() >= slots.queue {
+                return None;
+            }
+            let ticket = state.next_ticket;
+            state.next_ticket += 1;
+            state.waiting.push_back(ticket);
+            tracing::debug!(queued = state.waiting.len(), "waiting for an upload slot");
+            state = slots
+                .freed
+                .wait_while(state, |s| {
+                    s.taken >= max || s.waiting.front() != Some(&ticket)
+                })
+                .unwrap();
+            state.waiting.pop_front();
+            // The next request waiting may get a slot too
+            slots.freed.notify_all();
+        }
+        state.taken += 1;
+        Some(Slot {
+            slots: Arc::clone(slots),
+        })
+    }
+
+    /// Requests waiting for a slot
+    pub fn queued(&self) -> usize {
+        self.slots.state.lock().unwrap().waiting.len()
+    }
+
+    pub fn start(
+        &self,
+        slot: Slot,
+        peer: Option<SocketAddr>,
+        path: PathBuf,
+        size: u64,
+    ) -> ActiveUpload {
+        let upload = Arc::new(Upload {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            peer,
+            path,
+            size,
+            sent: AtomicU64::new(0),
+        });
+        self.active
+            .lock()
+            .unwrap()
+            .insert(upload.id, Arc::clone(&upload));
+        tracing::info!(
+            id = upload.id,
+            peer = ?upload.peer,
+            path = %upload.path.display(),
+            size = upload.size,
+            "upload started"
+        );
+        if let Some(events) = &self.events {
+            events.emit(Event::UploadStarted {
+                id: upload.id,
+                peer: upload.peer,
+                path: upload.path.clone(),
+                size: upload.size,
+            });
+        }
+        ActiveUpload {
+            upload,
+            active: Arc::clone(&self.active),
+            rates: Arc::clone(&self.rates),
+            events: self.events.clone(),
+            _slot: slot,
+        }
+    }
+
+    pub fn list(&self) -> Vec<Arc<Upload>> {
+        let mut uploads: Vec<_> = self.active.lock().unwrap().values().cloned().collect();
+        uploads.sort_by_key(|u| u.id);
+        uploads
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.slots.state.lock().unwrap().taken -= 1;
+        self.slots.freed.notify_all();
+    }
+}
+
+impl Rates {
+    /// Takes `n` bytes sent to `peer` from the buckets, returning how long to wait before
+    /// sending more
+    fn take(&self, peer: Option<IpAddr>, n: u64) -> Duration {
+        let total = self
+            .total
+            .as_ref()
+            .map_or(Duration::ZERO, |total| total.lock().unwrap().take(n));
+        let (Some(rate), Some(ip)) = (self.peer_rate, peer) else {
+            return total;
+        };
+        let mut peers = self.peers.lock().unwrap();
+        if !peers.contains_key(&ip) && peers.len() >= TRACKED_PEERS {
+            peers.retain(|_, limit| limit.idle() < Duration::from_secs(1));
+        }
+        let limit = peers
+            .entry(ip)
+            .or_insert_with(|| RateLimit::new(rate.get()));
+        total.max(limit.take(n))
+    }
+}
+
+impl ActiveUpload {
+    /// Bytes to send, as advertised to the peer
+    pub fn size(&self) -> u64 {
+        self.upload.size
+    }
+
+    /// Counts `n` more bytes sent, then waits for as long as the rate limits require before
+    /// more can be sent
+    pub fn sent(&self, n: u64) {
+        self.upload.sent.fetch_add(n, Ordering::Relaxed);
+        let wait = self.rates.take(self.upload.peer.map(|p| p.ip()), n);
+        if !wait.is_zero() {
+            std::thread::sleep(wait);
+        }
+    }
+}
+
+impl Drop for ActiveUpload {
+    fn drop(&mut self) {
+        self.active.lock().unwrap().remove(&self.upload.id);
+        let sent = self.upload.sent.load(Ordering::Relaxed);
+        tracing::info!(id = self.upload.id, sent, "upload finished");
+        if let Some(events) = &self.events {
+            events.emit(Event::UploadFinished {
+                id: self.upload.id,
+                path: self.upload.path.clone(),
+                sent,
+            });
+        }
+    }
+}
@@ -0,0 +1,129 @@
+//! Keeps the shared files in step with the directories they're shared from: changes under the
+//! share roots are picked up (through inotify on Linux), and once they settle the affected files
+//! are hashed again and published to trackers with the next `UpdateFiles`.
+
+use crate::ClientError;
+use crate::file_server::{FileServer, FileSystem};
+use notify::{Event, RecommendedWatcher, RecursiveMode, Watcher};
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::time::Duration;
+
+/// How long changes must stop for before they're taken in, so that files being written are only
+/// hashed once
+pub const DEBOUNCE: Duration = Duration::from_millis(500);
+
+pub struct ShareWatcher {
+    _watcher: RecommendedWatcher,
+    events: Receiver<notify::Result<Event>>,
+    /// Every root as given, and as events name it
+    roots: Vec<(PathBuf, PathBuf)>,
+}
+
+impl ShareWatcher {
+    pub fn new(roots: &[PathBuf]) -> Result<Self, ClientError> {
+        let (tx, events) = std::sync::mpsc::channel();
+        let mut watcher = notify::recommended_watcher(tx)?;
+        let mut watched = Vec::new();
+        for root in roots {
+            watcher.watch(root, RecursiveMode::Recursive)?;
+            watched.push((root.clone(), root.canonicalize()?));
+        }
+        Ok(Self {
+            _watcher: watcher,
+            events,
+            roots: watched,
+        })
+    }
+
+    /// Takes in changes under the roots into `file_server` as they come, until the watcher fails
+    pub fn run<FS: FileSystem>(&self, file_server: &FileServer<FS>) -> Result<(), ClientError> {
+        loop {
+            let changed = self.changes()?;
+            if apply(&file_server.file_system, &changed) {
+                file_server.files_changed();
+            }
+        }
+    }
+
+    /// Waits for changes, then for them to settle, and returns every path changed
+    pub fn changes(&self) -> Result<BTreeSet<PathBuf>, ClientError> {
+        let mut changed = BTreeSet::new();
+        self.take(self.events.recv().map_err(closed)?, &mut changed);
+        loop {
+            match self.events.recv_timeout(DEBOUNCE) {
+                Ok(event) => self.take(event, &mut changed),
+                Err(RecvTimeoutError::Timeout) => return Ok(changed),
+                Err(RecvTimeoutError::Disconnected) => return Err(closed(())),
+            }
+        }
+    }
+
+    fn take(&self, event: notify::Result<Event>, changed: &mut BTreeSet<PathBuf>) {
+        match event {
+            Ok(event) if event.kind.is_access() => {}
+            Ok(event) => changed.extend(event.paths.into_iter().map(|p| self.as_shared(p))),
+            Err(e) => tracing::warn!(error = %e, "failed to watch shared files"),
+        }
+    }
+
+    /// `path` as found under the root it was shared from
+    fn as_shared(&self, path: PathBuf) -> PathBuf {
+        for (given, canonical) in &self.roots {
+            if let Ok(rest) = path.strip_prefix(canonical) {
+                return given.join(rest);
+            }
+        }
+        path
+    }
+}
+
+fn closed<T>(_: T) -> ClientError {
+    ClientError::Fatal("Stopped watching shared files".to_string())
+}
+
+/// Shares, hashes again or stops sharing every path in `changed`, returning whether the shared
+/// files changed
+pub fn apply(fs: &impl FileSystem, changed: &BTreeSet<PathBuf>) -> bool {
+    let mut any = false;
+    for path in changed {
+        let result = if path.is_dir() {
+            share_dir(fs, path)
+        } else if path.is_file() {
+            fs.add_path(path.clone()).map_err(ClientError::from)
+        } else {
+            Ok(fs.remove_path(path))
+        };
+        match result {
+            Ok(changed) => any |= changed,
+            // Most likely removed while being read, its removal is coming
+            Err(e) => tracing::warn!(path = %path.display(), error = %e, "failed to share file"),
+        }
+    }
+    any
+}
+
+/// Shares the file at `path`, or every file under it when it's a directory, returning whether
+/// any of them changed
+pub fn share_path(fs: &impl FileSystem, path: PathBuf) -> Result<bool, ClientError> {
+    if path.is_dir() {
+        share_dir(fs, &path)
+    } else {
+        Ok(fs.add_path(path)?)
+    }
+}
+
+/// Shares every file under `dir`, recursively, returning whether any of them changed
+pub fn share_dir(fs: &impl FileSystem, dir: &Path) -> Result<bool, ClientError> {
+    let mut changed = false;
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        changed |= if path.is_dir() {
+            share_dir(fs, &path)?
+        } else {
+            fs.add_path(path)?
+        };
+    }
+    Ok(changed)
+}
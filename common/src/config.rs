@@ -0,0 +1,82 @@
+//! Loading the TOML configuration files of the client and the server.
+
+use serde::de::DeserializeOwned;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, thiserror::Error)]
+pub enum ConfigError {
+    #[error("Can't read config {path:?}: {source}")]
+    Read {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("Invalid config {path:?}: {message}")]
+    Parse { path: PathBuf, message: String },
+    #[error("Invalid {field}: {reason}")]
+    Invalid { field: &'static str, reason: String },
+}
+
+/// Where the config file `name` is looked for when none is given,
+/// `$XDG_CONFIG_HOME/p2prs/NAME` or `~/.config/p2prs/NAME`
+pub fn default_path(name: &str) -> Option<PathBuf> {
+    let dir = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".config")))?;
+    Some(dir.join("p2prs").join(name))
+}
+
+/// Loads the config at `path`, or at the default path for `name` if there is one there, or
+/// else the defaults
+pub fn load<T: DeserializeOwned + Default>(
+    path: Option<&Path>,
+    name: &str,
+) -> Result<T, ConfigError> {
+    let path = match path {
+        Some(path) => path.to_path_buf(),
+        None => match default_path(name) {
+            Some(path) if path.exists() => path,
+            _ => return Ok(T::default()),
+        },
+    };
+    let text = std::fs::read_to_string(&path).map_err(|source| ConfigError::Read {
+        path: path.clone(),
+        source,
+    })?;
+    parse(&text, &path)
+}
+
+/// Parses the config read from `path`
+pub fn parse<T: DeserializeOwned>(text: &str, path: &Path) -> Result<T, ConfigError> {
+    toml::from_str(text).map_err(|e| ConfigError::Parse {
+        path: path.to_path_buf(),
+        message: e.message().to_string(),
+    })
+}
+
+/// The `[log]` section
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    /// A level for every target, optionally followed by levels for some targets, see
+    /// [`crate::log::parse_filter`]
+    pub level: String,
+    pub format: crate::log::LogFormat,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            level: "info".to_string(),
+            format: crate::log::LogFormat::Text,
+        }
+    }
+}
+
+impl LogConfig {
+    pub fn filter(&self) -> Result<tracing_subscriber::filter::Targets, ConfigError> {
+        crate::log::parse_filter(&self.level).map_err(|reason| ConfigError::Invalid {
+            field: "log.level",
+            reason,
+        })
+    }
+}
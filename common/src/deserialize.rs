@@ -1,10 +1,15 @@
-use crate::{AnyMessage, File, MsgType, client, server};
+use crate::{AnyMessage, File, MsgType, client, dht, federation, server};
 use std::ffi::OsString;
 use std::io::Read;
 use std::net::{Ipv4Addr, SocketAddrV4};
 use std::os::unix::ffi::OsStringExt; // for from_vec
 use std::path::PathBuf;
 
+/// Largest message or byte string a peer may declare; anything bigger is rejected before allocating.
+pub const MAX_MSG_SIZE: usize = 64 << 20;
+/// Largest item count a list may declare.
+pub const MAX_LIST_LEN: usize = 1 << 20;
+
 pub fn make_msg_type(m: u8) -> Result<MsgType, DeserializeError> {
     Ok(match m {
         1 => MsgType::Connect,
@@ -14,6 +19,27 @@ pub fn make_msg_type(m: u8) -> Result<MsgType, DeserializeError> {
         5 => MsgType::RegisterPeer,
         6 => MsgType::UpdatePeer,
         7 => MsgType::UnregisterPeer,
+        8 => MsgType::Ping,
+        9 => MsgType::Pong,
+        10 => MsgType::PeerSnapshot,
+        11 => MsgType::TrackerHello,
+        12 => MsgType::SyncPeers,
+        13 => MsgType::ReplicatePeer,
+        14 => MsgType::ForgetPeer,
+        15 => MsgType::FindNode,
+        16 => MsgType::FindProviders,
+        17 => MsgType::AddProvider,
+        18 => MsgType::Nodes,
+        19 => MsgType::Providers,
+        20 => MsgType::PeerExchange,
+        21 => MsgType::LanAnnounce,
+        22 => MsgType::RelayConnect,
+        23 => MsgType::RelayAccept,
+        24 => MsgType::RelayIncoming,
+        25 => MsgType::Shutdown,
+        26 => MsgType::Refused,
+        27 => MsgType::SendingFile,
+        28 => MsgType::Busy,
         x => return Err(DeserializeError::WrongMsgType(x)),
     })
 }
@@ -22,11 +48,16 @@ pub fn read_msg(stream: &mut impl Read) -> Result<AnyMessage, DeserializeError>
     use MsgType as M;
     use client::Message as C;
     use client::*;
+    use dht::Message as D;
+    use dht::*;
+    use federation::Message as F;
+    use federation::*;
     use server::Message as S;
     use server::*;
 
     let msg_type = u8::from_stream(stream).and_then(make_msg_type)?;
     let mut content = VecRead::from(Vec::from_stream(stream)?);
+    tracing::trace!(?msg_type, size = content.buf.len(), "read message");
 
     Ok(match msg_type {
         M::Connect => C::from(Connect::from_stream(&mut content)?).into(),
@@ -36,6 +67,27 @@ pub fn read_msg(stream: &mut impl Read) -> Result<AnyMessage, DeserializeError>
         M::RegisterPeer => S::from(RegisterPeer::from_stream(&mut content)?).into(),
         M::UpdatePeer => S::from(UpdatePeer::from_stream(&mut content)?).into(),
         M::UnregisterPeer => S::from(UnregisterPeer::from_stream(&mut content)?).into(),
+        M::Ping => S::from(Ping).into(),
+        M::Pong => C::from(Pong).into(),
+        M::PeerSnapshot => S::from(PeerSnapshot::from_stream(&mut content)?).into(),
+        M::TrackerHello => F::from(TrackerHello::from_stream(&mut content)?).into(),
+        M::SyncPeers => F::from(SyncPeers::from_stream(&mut content)?).into(),
+        M::ReplicatePeer => F::from(ReplicatePeer::from_stream(&mut content)?).into(),
+        M::ForgetPeer => F::from(ForgetPeer::from_stream(&mut content)?).into(),
+        M::FindNode => D::from(FindNode::from_stream(&mut content)?).into(),
+        M::FindProviders => D::from(FindProviders::from_stream(&mut content)?).into(),
+        M::AddProvider => D::from(AddProvider::from_stream(&mut content)?).into(),
+        M::Nodes => D::from(Nodes::from_stream(&mut content)?).into(),
+        M::Providers => D::from(Providers::from_stream(&mut content)?).into(),
+        M::PeerExchange => C::from(PeerExchange::from_stream(&mut content)?).into(),
+        M::LanAnnounce => C::from(LanAnnounce::from_stream(&mut content)?).into(),
+        M::RelayConnect => C::from(RelayConnect::from_stream(&mut content)?).into(),
+        M::RelayAccept => C::from(RelayAccept::from_stream(&mut content)?).into(),
+        M::RelayIncoming => S::from(RelayIncoming::from_stream(&mut content)?).into(),
+        M::Shutdown => S::from(Shutdown).into(),
+        M::Refused => S::from(Refused::from_stream(&mut content)?).into(),
+        M::SendingFile => C::from(SendingFile::from_stream(&mut content)?).into(),
+        M::Busy => C::from(Busy).into(),
     })
 }
 
@@ -65,7 +117,6 @@ impl Read for VecRead {
         let wish_take = buf.len();
         let new_pointer = self.pointer + wish_take;
         if new_pointer > self.buf.len() {
-            eprintln!("{self:?}");
             Err(std::io::Error::new(
                 std::io::ErrorKind::UnexpectedEof,
                 "VecRead EOF Error",
@@ -78,6 +129,27 @@ impl Read for VecRead {
     }
 }
 
+fn alloc_bytes(len: u64) -> Result<Vec<u8>, DeserializeError> {
+    let len = usize::try_from(len).unwrap_or(usize::MAX);
+    if len > MAX_MSG_SIZE {
+        return Err(DeserializeError::TooLong(len, MAX_MSG_SIZE));
+    }
+    let mut bytes = Vec::new();
+    bytes.try_reserve_exact(len)?;
+    bytes.resize(len, 0);
+    Ok(bytes)
+}
+
+fn alloc_list<T>(count: u32) -> Result<Vec<T>, DeserializeError> {
+    let count = count as usize;
+    if count > MAX_LIST_LEN {
+        return Err(DeserializeError::TooLong(count, MAX_LIST_LEN));
+    }
+    let mut items = Vec::new();
+    items.try_reserve(count)?;
+    Ok(items)
+}
+
 pub trait FromBytes: Sized {
     fn from_stream(stream: &mut impl Read) -> Result<Self, DeserializeError>;
 }
@@ -99,8 +171,8 @@ macro_rules! impl_read {
     ( [u8] => $convert:expr => $bt:ty ) => {
         impl FromBytes for $bt {
             fn from_stream(stream: &mut impl Read) -> Result<Self, DeserializeError> {
-                let bytes_size = u64::from_stream(stream)? as usize;
-                let mut bytes = vec![0u8; bytes_size];
+                let bytes_size = u64::from_stream(stream)?;
+                let mut bytes = alloc_bytes(bytes_size)?;
                 stream.read_exact(&mut bytes)?;
                 Ok($convert(bytes))
             }
@@ -137,7 +209,7 @@ impl FromBytes for client::Connect {
         // {serve_port}:u16 {file_count}:u32 [ {file_size}:u64 {path_len}:u64 {path}:path_len ]*
         let serve_port = u16::from_stream(stream)?;
         let file_count = u32::from_stream(stream)?;
-        let mut file_list = Vec::with_capacity(file_count as usize);
+        let mut file_list = alloc_list(file_count)?;
         for _ in 0..file_count {
             let size = u64::from_stream(stream)?;
             let path = PathBuf::from_stream(stream)?;
@@ -154,7 +226,7 @@ impl FromBytes for client::UpdateFiles {
     fn from_stream(stream: &mut impl Read) -> Result<Self, DeserializeError> {
         // {file_count}:u32 [ {file_size}:u64 {path_len}:u64 {path}:path_len ]*
         let file_count = u32::from_stream(stream)?;
-        let mut file_list = Vec::with_capacity(file_count as usize);
+        let mut file_list = alloc_list(file_count)?;
         for _ in 0..file_count {
             let size = u64::from_stream(stream)?;
             let path = PathBuf::from_stream(stream)?;
@@ -172,7 +244,7 @@ impl FromBytes for server::RegisterPeer {
         let ip = u32::from_stream(stream)?;
         let port = u16::from_stream(stream)?;
         let file_count = u32::from_stream(stream)?;
-        let mut file_list = Vec::with_capacity(file_count as usize);
+        let mut file_list = alloc_list(file_count)?;
         for _ in 0..file_count {
             let size = u64::from_stream(stream)?;
             let path = PathBuf::from_stream(stream)?;
@@ -197,6 +269,180 @@ impl FromBytes for server::UnregisterPeer {
     }
 }
 
+impl_read!(server::RegisterPeer => |server::RegisterPeer{sock, file_list}|server::PeerInfo{ sock, file_list } => server::PeerInfo);
+
+impl_read!(String => |reason| server::Refused { reason } => server::Refused);
+
+impl FromBytes for server::PeerSnapshot {
+    fn from_stream(stream: &mut impl Read) -> Result<Self, DeserializeError> {
+        // {peer_count}:u32 [ {serve_ip}:u32 {serve_port}:u16 {file_count}:u32 [ {file_size}:64 {path_len}:64 {path}:path_len ]* ]*
+        let peer_count = u32::from_stream(stream)?;
+        let mut peers = alloc_list(peer_count)?;
+        for _ in 0..peer_count {
+            peers.push(server::PeerInfo::from_stream(stream)?);
+        }
+        Ok(Self { peers })
+    }
+}
+
+impl FromBytes for federation::Registration {
+    fn from_stream(stream: &mut impl Read) -> Result<Self, DeserializeError> {
+        // {registered_at}:u64 {serve_ip}:u32 {serve_port}:u16 {file_count}:u32 [ {file_size}:64 {path_len}:64 {path}:path_len ]*
+        let registered_at = u64::from_stream(stream)?;
+        let server::RegisterPeer { sock, file_list } = server::RegisterPeer::from_stream(stream)?;
+        Ok(Self {
+            sock,
+            file_list,
+            registered_at,
+        })
+    }
+}
+
+impl FromBytes for federation::TrackerHello {
+    fn from_stream(stream: &mut impl Read) -> Result<Self, DeserializeError> {
+        let ip = u32::from_stream(stream)?;
+        let port = u16::from_stream(stream)?;
+        Ok(Self {
+            tracker: SocketAddrV4::new(Ipv4Addr::from_bits(ip), port),
+        })
+    }
+}
+
+impl FromBytes for federation::SyncPeers {
+    fn from_stream(stream: &mut impl Read) -> Result<Self, DeserializeError> {
+        // {peer_count}:u32 [ {registration} ]*
+        let peer_count = u32::from_stream(stream)?;
+        let mut peers = alloc_list(peer_count)?;
+        for _ in 0..peer_count {
+            peers.push(federation::Registration::from_stream(stream)?);
+        }
+        Ok(Self { peers })
+    }
+}
+
+impl_read!(federation::Registration => |peer|federation::ReplicatePeer{ peer } => federation::ReplicatePeer);
+impl_read!(server::UnregisterPeer => |server::UnregisterPeer{sock}|federation::ForgetPeer{ sock } => federation::ForgetPeer);
+
+impl FromBytes for SocketAddrV4 {
+    fn from_stream(stream: &mut impl Read) -> Result<Self, DeserializeError> {
+        // {ip}:u32 {port}:u16
+        let ip = u32::from_stream(stream)?;
+        let port = u16::from_stream(stream)?;
+        Ok(SocketAddrV4::new(Ipv4Addr::from_bits(ip), port))
+    }
+}
+
+impl FromBytes for dht::NodeId {
+    fn from_stream(stream: &mut impl Read) -> Result<Self, DeserializeError> {
+        let mut id = [0u8; 32];
+        stream.read_exact(&mut id)?;
+        Ok(Self(id))
+    }
+}
+
+impl FromBytes for dht::Contact {
+    fn from_stream(stream: &mut impl Read) -> Result<Self, DeserializeError> {
+        // {id}:[u8;32] {ip}:u32 {port}:u16
+        let id = dht::NodeId::from_stream(stream)?;
+        let addr = SocketAddrV4::from_stream(stream)?;
+        Ok(Self { id, addr })
+    }
+}
+
+fn read_list<T: FromBytes>(stream: &mut impl Read) -> Result<Vec<T>, DeserializeError> {
+    // {count}:u32 [ {item} ]*
+    let count = u32::from_stream(stream)?;
+    let mut items = alloc_list(count)?;
+    for _ in 0..count {
+        items.push(T::from_stream(stream)?);
+    }
+    Ok(items)
+}
+
+impl FromBytes for dht::FindNode {
+    fn from_stream(stream: &mut impl Read) -> Result<Self, DeserializeError> {
+        let sender = dht::Contact::from_stream(stream)?;
+        let target = dht::NodeId::from_stream(stream)?;
+        Ok(Self { sender, target })
+    }
+}
+
+impl_read!(dht::FindNode => |dht::FindNode{sender, target}|dht::FindProviders{ sender, key: target } => dht::FindProviders);
+
+impl FromBytes for dht::AddProvider {
+    fn from_stream(stream: &mut impl Read) -> Result<Self, DeserializeError> {
+        let sender = dht::Contact::from_stream(stream)?;
+        let key = dht::NodeId::from_stream(stream)?;
+        let provider = SocketAddrV4::from_stream(stream)?;
+        Ok(Self {
+            sender,
+            key,
+            provider,
+        })
+    }
+}
+
+impl FromBytes for dht::Nodes {
+    fn from_stream(stream: &mut impl Read) -> Result<Self, DeserializeError> {
+        let sender = dht::Contact::from_stream(stream)?;
+        let nodes = read_list(stream)?;
+        Ok(Self { sender, nodes })
+    }
+}
+
+impl FromBytes for dht::Providers {
+    fn from_stream(stream: &mut impl Read) -> Result<Self, DeserializeError> {
+        let sender = dht::Contact::from_stream(stream)?;
+        let providers = read_list(stream)?;
+        let nodes = read_list(stream)?;
+        Ok(Self {
+            sender,
+            providers,
+            nodes,
+        })
+    }
+}
+
+impl FromBytes for client::ExchangedPeer {
+    fn from_stream(stream: &mut impl Read) -> Result<Self, DeserializeError> {
+        // {age_ms}:u64 {serve_ip}:u32 {serve_port}:u16 {file_count}:u32 [ {file_size}:64 {path_len}:64 {path}:path_len ]*
+        let age_ms = u64::from_stream(stream)?;
+        let server::RegisterPeer { sock, file_list } = server::RegisterPeer::from_stream(stream)?;
+        Ok(Self {
+            sock,
+            file_list,
+            age_ms,
+        })
+    }
+}
+
+impl FromBytes for client::PeerExchange {
+    fn from_stream(stream: &mut impl Read) -> Result<Self, DeserializeError> {
+        let sender = SocketAddrV4::from_stream(stream)?;
+        let peers = read_list(stream)?;
+        Ok(Self { sender, peers })
+    }
+}
+
+impl FromBytes for client::LanAnnounce {
+    fn from_stream(stream: &mut impl Read) -> Result<Self, DeserializeError> {
+        let identity = dht::NodeId::from_stream(stream)?;
+        let sock = SocketAddrV4::from_stream(stream)?;
+        let mut digest = [0u8; 32];
+        stream.read_exact(&mut digest)?;
+        Ok(Self {
+            identity,
+            sock,
+            digest,
+        })
+    }
+}
+
+impl_read!(SocketAddrV4 => |target|client::RelayConnect{ target } => client::RelayConnect);
+impl_read!(u64 => |session|client::RelayAccept{ session } => client::RelayAccept);
+impl_read!(u64 => |size|client::SendingFile{ size } => client::SendingFile);
+impl_read!(u64 => |session|server::RelayIncoming{ session } => server::RelayIncoming);
+
 #[derive(Debug, thiserror::Error)]
 pub enum DeserializeError {
     #[error("Not enough bytes in stream {0:?} to read {1}")]
@@ -207,4 +453,8 @@ pub enum DeserializeError {
     OsStringUTF8Error(OsString),
     #[error("Failed to convert {0:?} to a Msg Type")]
     WrongMsgType(u8),
+    #[error("Declared length {0} is over the limit of {1}")]
+    TooLong(usize, usize),
+    #[error(transparent)]
+    Alloc(#[from] std::collections::TryReserveError),
 }
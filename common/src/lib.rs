@@ -1,4 +1,7 @@
+pub mod config;
 pub mod deserialize;
+pub mod log;
+pub mod rate;
 pub mod serialize;
 pub use deserialize::{DeserializeError, read_msg};
 use std::io::Write;
@@ -7,7 +10,7 @@ use std::net::TcpStream;
 #[cfg(test)]
 mod test;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 #[repr(u8)]
 pub enum MsgType {
     Connect = 1,
@@ -17,6 +20,27 @@ pub enum MsgType {
     RegisterPeer = 5,
     UpdatePeer = 6,
     UnregisterPeer = 7,
+    Ping = 8,
+    Pong = 9,
+    PeerSnapshot = 10,
+    TrackerHello = 11,
+    SyncPeers = 12,
+    ReplicatePeer = 13,
+    ForgetPeer = 14,
+    FindNode = 15,
+    FindProviders = 16,
+    AddProvider = 17,
+    Nodes = 18,
+    Providers = 19,
+    PeerExchange = 20,
+    LanAnnounce = 21,
+    RelayConnect = 22,
+    RelayAccept = 23,
+    RelayIncoming = 24,
+    Shutdown = 25,
+    Refused = 26,
+    SendingFile = 27,
+    Busy = 28,
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -29,6 +53,8 @@ pub struct File {
 pub enum AnyMessage {
     Client(client::Message),
     Server(server::Message),
+    Federation(federation::Message),
+    Dht(dht::Message),
 }
 
 impl From<client::Message> for AnyMessage {
@@ -43,9 +69,24 @@ impl From<server::Message> for AnyMessage {
     }
 }
 
+impl From<federation::Message> for AnyMessage {
+    fn from(value: federation::Message) -> Self {
+        AnyMessage::Federation(value)
+    }
+}
+
+impl From<dht::Message> for AnyMessage {
+    fn from(value: dht::Message) -> Self {
+        AnyMessage::Dht(value)
+    }
+}
+
 /// Messages a client can send
 pub mod client {
     use super::File;
+    use super::dht::NodeId;
+    use sha2::{Digest, Sha256};
+    use std::net::SocketAddrV4;
     use std::path::PathBuf;
 
     // 1. Connect
@@ -95,12 +136,142 @@ pub mod client {
         }
     }
 
+    // 5. Pong
+    /// Answer to a [`Ping`](super::server::Ping) from the tracker
+    #[derive(Debug, PartialEq)]
+    pub struct Pong;
+
+    impl From<Pong> for Message {
+        fn from(value: Pong) -> Self {
+            Message::Pong(value)
+        }
+    }
+
+    // 6. PeerExchange
+    /// Peers known to the sender, sent directly to another peer which answers with its own
+    #[derive(Debug, PartialEq)]
+    pub struct PeerExchange {
+        /// The sender's file server
+        pub sender: SocketAddrV4,
+        pub peers: Vec<ExchangedPeer>,
+    }
+
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct ExchangedPeer {
+        pub sock: SocketAddrV4,
+        pub file_list: Vec<File>,
+        /// Milliseconds since the peer was last known to be alive
+        pub age_ms: u64,
+    }
+
+    impl From<PeerExchange> for Message {
+        fn from(value: PeerExchange) -> Self {
+            Message::PeerExchange(value)
+        }
+    }
+
+    // 7. LanAnnounce
+    /// Sent as a single UDP datagram to the LAN's multicast group, peers whose `digest`
+    /// changed are asked for their files with a [`PeerExchange`]
+    #[derive(Debug, PartialEq)]
+    pub struct LanAnnounce {
+        /// Random per client, tells our own announcements apart
+        pub identity: NodeId,
+        /// The sender's file server, an unspecified IP means the datagram's source IP
+        pub sock: SocketAddrV4,
+        pub digest: [u8; 32],
+    }
+
+    impl LanAnnounce {
+        /// Hash of a file list, the same whatever order the files are in
+        pub fn digest(files: &[File]) -> [u8; 32] {
+            let mut files: Vec<_> = files.iter().collect();
+            files.sort_by(|a, b| a.path.cmp(&b.path).then(a.size.cmp(&b.size)));
+            let mut hasher = Sha256::new();
+            for file in files {
+                let path = file.path.as_os_str().as_encoded_bytes();
+                hasher.update(file.size.to_le_bytes());
+                hasher.update(path.len().to_le_bytes());
+                hasher.update(path);
+            }
+            hasher.finalize().into()
+        }
+    }
+
+    impl From<LanAnnounce> for Message {
+        fn from(value: LanAnnounce) -> Self {
+            Message::LanAnnounce(value)
+        }
+    }
+
+    // 8. RelayConnect
+    /// First message on a connection to a relay, which splices it to the file server at
+    /// `target` so it can be sent a [`RequestFile`] as if connected directly
+    #[derive(Debug, PartialEq)]
+    pub struct RelayConnect {
+        pub target: SocketAddrV4,
+    }
+
+    impl From<RelayConnect> for Message {
+        fn from(value: RelayConnect) -> Self {
+            Message::RelayConnect(value)
+        }
+    }
+
+    // 9. RelayAccept
+    /// First message on a connection a peer opens to the relay after being sent a
+    /// [`RelayIncoming`](super::server::RelayIncoming), the connection is then served like
+    /// one accepted by its file server
+    #[derive(Debug, PartialEq)]
+    pub struct RelayAccept {
+        pub session: u64,
+    }
+
+    impl From<RelayAccept> for Message {
+        fn from(value: RelayAccept) -> Self {
+            Message::RelayAccept(value)
+        }
+    }
+
+    // 10. SendingFile
+    /// Answer to a [`RequestFile`], the file's `size` bytes follow and the connection is
+    /// closed
+    #[derive(Debug, PartialEq)]
+    pub struct SendingFile {
+        pub size: u64,
+    }
+
+    impl From<SendingFile> for Message {
+        fn from(value: SendingFile) -> Self {
+            Message::SendingFile(value)
+        }
+    }
+
+    // 11. Busy
+    /// Answer to a [`RequestFile`] when every upload slot is taken and the queue is full, the
+    /// connection is then closed
+    #[derive(Debug, PartialEq)]
+    pub struct Busy;
+
+    impl From<Busy> for Message {
+        fn from(value: Busy) -> Self {
+            Message::Busy(value)
+        }
+    }
+
     #[derive(Debug, PartialEq)]
     pub enum Message {
         Connect(Connect),
         UpdateFiles(UpdateFiles),
         Disconnect(Disconnect),
         RequestFile(RequestFile),
+        Pong(Pong),
+        PeerExchange(PeerExchange),
+        LanAnnounce(LanAnnounce),
+        RelayConnect(RelayConnect),
+        RelayAccept(RelayAccept),
+        SendingFile(SendingFile),
+        Busy(Busy),
     }
 }
 
@@ -147,11 +318,311 @@ pub mod server {
         }
     }
 
+    // 4. Ping
+    /// Heartbeat sent by the tracker, peers must answer with a [`Pong`](super::client::Pong)
+    #[derive(Debug, PartialEq)]
+    pub struct Ping;
+
+    impl From<Ping> for Message {
+        fn from(value: Ping) -> Self {
+            Message::Ping(value)
+        }
+    }
+
+    // 5. PeerSnapshot
+    /// Every peer the tracker knows about, sent to a client right after it connects
+    #[derive(Debug, PartialEq)]
+    pub struct PeerSnapshot {
+        pub peers: Vec<PeerInfo>,
+    }
+
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct PeerInfo {
+        pub sock: SocketAddrV4,
+        pub file_list: Vec<File>,
+    }
+
+    impl From<PeerSnapshot> for Message {
+        fn from(value: PeerSnapshot) -> Self {
+            Message::PeerSnapshot(value)
+        }
+    }
+
+    // 6. RelayIncoming
+    /// Someone wants to reach the peer's file server through the tracker, which waits for the
+    /// peer to connect back with a [`RelayAccept`](super::client::RelayAccept)
+    #[derive(Debug, PartialEq)]
+    pub struct RelayIncoming {
+        pub session: u64,
+    }
+
+    impl From<RelayIncoming> for Message {
+        fn from(value: RelayIncoming) -> Self {
+            Message::RelayIncoming(value)
+        }
+    }
+
+    // 7. Shutdown
+    /// The tracker is going away, peers should reconnect to another one
+    #[derive(Debug, PartialEq)]
+    pub struct Shutdown;
+
+    impl From<Shutdown> for Message {
+        fn from(value: Shutdown) -> Self {
+            Message::Shutdown(value)
+        }
+    }
+
+    // 8. Refused
+    /// The tracker won't serve the client, or a file server won't send the file requested,
+    /// sent right before closing the connection
+    #[derive(Debug, PartialEq)]
+    pub struct Refused {
+        pub reason: String,
+    }
+
+    impl From<Refused> for Message {
+        fn from(value: Refused) -> Self {
+            Message::Refused(value)
+        }
+    }
+
     #[derive(Debug, PartialEq)]
     pub enum Message {
         RegisterPeer(RegisterPeer),
         UpdatePeer(UpdatePeer),
         UnregisterPeer(UnregisterPeer),
+        Ping(Ping),
+        PeerSnapshot(PeerSnapshot),
+        RelayIncoming(RelayIncoming),
+        Shutdown(Shutdown),
+        Refused(Refused),
+    }
+}
+
+/// Messages a server can send to the other servers it's federated with
+pub mod federation {
+    use super::File;
+    use std::net::SocketAddrV4;
+
+    /// A peer connected to the sending tracker
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct Registration {
+        pub sock: SocketAddrV4,
+        pub file_list: Vec<File>,
+        /// Milliseconds since the unix epoch at which the peer registered or last updated its
+        /// files, the most recent registration wins when several trackers know the same peer
+        pub registered_at: u64,
+    }
+
+    // 1. TrackerHello
+    #[derive(Debug, PartialEq)]
+    pub struct TrackerHello {
+        /// Address the sending tracker accepts connections on, used as its identity
+        pub tracker: SocketAddrV4,
+    }
+
+    impl From<TrackerHello> for Message {
+        fn from(value: TrackerHello) -> Self {
+            Message::TrackerHello(value)
+        }
+    }
+
+    // 2. SyncPeers
+    /// Every peer connected to the sending tracker, replacing what was known about it
+    #[derive(Debug, PartialEq)]
+    pub struct SyncPeers {
+        pub peers: Vec<Registration>,
+    }
+
+    impl From<SyncPeers> for Message {
+        fn from(value: SyncPeers) -> Self {
+            Message::SyncPeers(value)
+        }
+    }
+
+    // 3. ReplicatePeer
+    #[derive(Debug, PartialEq)]
+    pub struct ReplicatePeer {
+        pub peer: Registration,
+    }
+
+    impl From<ReplicatePeer> for Message {
+        fn from(value: ReplicatePeer) -> Self {
+            Message::ReplicatePeer(value)
+        }
+    }
+
+    // 4. ForgetPeer
+    #[derive(Debug, PartialEq)]
+    pub struct ForgetPeer {
+        pub sock: SocketAddrV4,
+    }
+
+    impl From<ForgetPeer> for Message {
+        fn from(value: ForgetPeer) -> Self {
+            Message::ForgetPeer(value)
+        }
+    }
+
+    #[derive(Debug, PartialEq)]
+    pub enum Message {
+        TrackerHello(TrackerHello),
+        SyncPeers(SyncPeers),
+        ReplicatePeer(ReplicatePeer),
+        ForgetPeer(ForgetPeer),
+    }
+}
+
+/// Messages peers exchange to run the distributed hash table
+pub mod dht {
+    use sha2::{Digest, Sha256};
+    use std::net::SocketAddrV4;
+    use std::path::Path;
+
+    /// Identifies a node, keys are stored on the nodes whose id is closest to them
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+    pub struct NodeId(pub [u8; 32]);
+
+    impl NodeId {
+        pub fn hash(bytes: &[u8]) -> Self {
+            Self(Sha256::digest(bytes).into())
+        }
+        /// Key under which the peers sharing `path` are stored
+        pub fn for_path(path: &Path) -> Self {
+            Self::hash(path.as_os_str().as_encoded_bytes())
+        }
+        /// XOR distance, compares as a big endian number
+        #[must_use]
+        pub fn distance(&self, other: &NodeId) -> NodeId {
+            NodeId(std::array::from_fn(|i| self.0[i] ^ other.0[i]))
+        }
+        /// Index of the highest bit in which `self` and `other` differ, `None` if they're equal
+        pub fn bucket(&self, other: &NodeId) -> Option<usize> {
+            let distance = self.distance(other);
+            let leading = distance
+                .0
+                .iter()
+                .position(|b| *b != 0)
+                .map(|i| i * 8 + distance.0[i].leading_zeros() as usize)?;
+            Some(255 - leading)
+        }
+        /// Parses the 64 hex digits [`NodeId`]s are displayed as
+        pub fn from_hex(hex: &str) -> Option<Self> {
+            if hex.len() != 64 || !hex.is_ascii() {
+                return None;
+            }
+            let mut id = [0u8; 32];
+            for (i, byte) in id.iter_mut().enumerate() {
+                *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok()?;
+            }
+            Some(Self(id))
+        }
+    }
+
+    impl std::fmt::Display for NodeId {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            self.0.iter().try_for_each(|b| write!(f, "{b:02x}"))
+        }
+    }
+
+    /// How to reach a node
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct Contact {
+        pub id: NodeId,
+        pub addr: SocketAddrV4,
+    }
+
+    // 1. FindNode
+    /// Asks for the contacts closest to `target`, answered with [`Nodes`]
+    #[derive(Debug, PartialEq)]
+    pub struct FindNode {
+        pub sender: Contact,
+        pub target: NodeId,
+    }
+
+    impl From<FindNode> for Message {
+        fn from(value: FindNode) -> Self {
+            Message::FindNode(value)
+        }
+    }
+
+    // 2. FindProviders
+    /// Asks for the peers providing `key`, answered with [`Providers`]
+    #[derive(Debug, PartialEq)]
+    pub struct FindProviders {
+        pub sender: Contact,
+        pub key: NodeId,
+    }
+
+    impl From<FindProviders> for Message {
+        fn from(value: FindProviders) -> Self {
+            Message::FindProviders(value)
+        }
+    }
+
+    // 3. AddProvider
+    /// Stores that the file server at `provider` has `key`, answered with [`Nodes`]
+    #[derive(Debug, PartialEq)]
+    pub struct AddProvider {
+        pub sender: Contact,
+        pub key: NodeId,
+        pub provider: SocketAddrV4,
+    }
+
+    impl From<AddProvider> for Message {
+        fn from(value: AddProvider) -> Self {
+            Message::AddProvider(value)
+        }
+    }
+
+    // 4. Nodes
+    #[derive(Debug, PartialEq)]
+    pub struct Nodes {
+        pub sender: Contact,
+        pub nodes: Vec<Contact>,
+    }
+
+    impl From<Nodes> for Message {
+        fn from(value: Nodes) -> Self {
+            Message::Nodes(value)
+        }
+    }
+
+    // 5. Providers
+    /// The providers the node knows of, and the contacts it knows closest to the key
+    #[derive(Debug, PartialEq)]
+    pub struct Providers {
+        pub sender: Contact,
+        pub providers: Vec<SocketAddrV4>,
+        pub nodes: Vec<Contact>,
+    }
+
+    impl From<Providers> for Message {
+        fn from(value: Providers) -> Self {
+            Message::Providers(value)
+        }
+    }
+
+    #[derive(Debug, PartialEq)]
+    pub enum Message {
+        FindNode(FindNode),
+        FindProviders(FindProviders),
+        AddProvider(AddProvider),
+        Nodes(Nodes),
+        Providers(Providers),
+    }
+
+    impl Message {
+        pub fn sender(&self) -> Contact {
+            match self {
+                Message::FindNode(m) => m.sender,
+                Message::FindProviders(m) => m.sender,
+                Message::AddProvider(m) => m.sender,
+                Message::Nodes(m) => m.sender,
+                Message::Providers(m) => m.sender,
+            }
+        }
     }
 }
 
@@ -174,9 +645,11 @@ pub fn write_msg_d(
     stream: &mut impl Write,
     msg: &impl serialize::Serialize,
 ) -> Result<(), CommonError> {
+    let size = msg.size();
     stream.write_all(&[msg.msg_type() as u8])?;
-    stream.write_all(&u64::to_le_bytes(msg.size() as u64))?;
+    stream.write_all(&u64::to_le_bytes(size as u64))?;
     msg.write(stream)?;
+    tracing::trace!(msg_type = ?msg.msg_type(), size, "wrote message");
     Ok(())
 }
 
@@ -187,3 +660,24 @@ pub enum CommonError {
     #[error(transparent)]
     Deserialize(#[from] DeserializeError),
 }
+
+impl CommonError {
+    /// Whether the error means the other side of the connection went away, or stopped
+    /// answering before a read or write timeout
+    pub fn is_disconnect(&self) -> bool {
+        use std::io::ErrorKind as K;
+        let kind = match self {
+            CommonError::IO(e) | CommonError::Deserialize(DeserializeError::IO(e)) => e.kind(),
+            CommonError::Deserialize(_) => return false,
+        };
+        matches!(
+            kind,
+            K::UnexpectedEof
+                | K::BrokenPipe
+                | K::ConnectionReset
+                | K::ConnectionAborted
+                | K::WouldBlock
+                | K::TimedOut
+        )
+    }
+}
@@ -0,0 +1,67 @@
+//! Logging through `tracing`: a level for every target, optionally raised or lowered per
+//! target, printed to stderr as text or as one JSON object per line.
+
+use crate::config::{ConfigError, LogConfig};
+use tracing_subscriber::filter::{LevelFilter, Targets};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+impl std::str::FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("unknown format {s:?}, expected text or json")),
+        }
+    }
+}
+
+fn parse_level(s: &str) -> Result<LevelFilter, String> {
+    match s.trim().to_ascii_lowercase().as_str() {
+        "off" => Ok(LevelFilter::OFF),
+        "error" => Ok(LevelFilter::ERROR),
+        "warn" => Ok(LevelFilter::WARN),
+        "info" => Ok(LevelFilter::INFO),
+        "debug" => Ok(LevelFilter::DEBUG),
+        "trace" => Ok(LevelFilter::TRACE),
+        _ => Err(format!(
+            "unknown level {s:?}, expected off, error, warn, info, debug or trace"
+        )),
+    }
+}
+
+/// Parses a level for every target, followed by levels for some targets, like
+/// `info,server::relay=debug`
+pub fn parse_filter(s: &str) -> Result<Targets, String> {
+    let mut targets = Targets::new();
+    for directive in s.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+        targets = match directive.split_once('=') {
+            Some((target, level)) => targets.with_target(target.trim(), parse_level(level)?),
+            None => targets.with_default(parse_level(directive)?),
+        };
+    }
+    Ok(targets)
+}
+
+/// Prints the events `config` lets through to stderr, for the rest of the process. Does
+/// nothing when something else already collects events.
+pub fn init(config: &LogConfig) -> Result<(), ConfigError> {
+    let filter = config.filter()?;
+    let layer = tracing_subscriber::fmt::layer().with_writer(std::io::stderr);
+    let registry = tracing_subscriber::registry().with(filter);
+    let _ = match config.format {
+        LogFormat::Text => registry.with(layer).try_init(),
+        LogFormat::Json => registry.with(layer.json()).try_init(),
+    };
+    Ok(())
+}
@@ -0,0 +1,58 @@
+//! Token buckets limiting how fast bytes, messages or connections go through.
+
+use std::time::{Duration, Instant};
+
+/// Token bucket allowing `rate` tokens per second, in bursts of up to a second's worth
+#[derive(Debug)]
+pub struct RateLimit {
+    rate: u64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl RateLimit {
+    pub fn new(rate: u64) -> Self {
+        Self {
+            rate,
+            tokens: rate as f64,
+            last: Instant::now(),
+        }
+    }
+
+    pub fn rate(&self) -> u64 {
+        self.rate
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let refill = now.duration_since(self.last).as_secs_f64() * self.rate as f64;
+        self.tokens = (self.tokens + refill).min(self.rate as f64);
+        self.last = now;
+    }
+
+    /// Takes `n` tokens, returning how long to wait before using them
+    pub fn take(&mut self, n: u64) -> Duration {
+        self.refill();
+        self.tokens -= n as f64;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate as f64)
+        }
+    }
+
+    /// Takes `n` tokens only if there are that many, returning whether it did
+    pub fn try_take(&mut self, n: u64) -> bool {
+        self.refill();
+        let enough = self.tokens >= n as f64;
+        if enough {
+            self.tokens -= n as f64;
+        }
+        enough
+    }
+
+    /// How long since tokens were last taken, after a second the bucket is full again
+    pub fn idle(&self) -> Duration {
+        self.last.elapsed()
+    }
+}
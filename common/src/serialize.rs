@@ -1,5 +1,6 @@
-use crate::{AnyMessage, MsgType, client, server};
+use crate::{AnyMessage, MsgType, client, dht, federation, server};
 use std::io::Write;
+use std::net::SocketAddrV4;
 
 /// Creates the three seperate components
 pub trait Serialize {
@@ -27,6 +28,46 @@ impl SerializeMessage for client::Disconnect {
     }
 }
 
+impl SerializeMessage for client::Pong {
+    const MSG_TYPE: MsgType = MsgType::Pong;
+    fn size(&self) -> usize {
+        0
+    }
+    fn write(&self, _: &mut impl Write) -> Result<(), std::io::Error> {
+        Ok(())
+    }
+}
+
+impl SerializeMessage for client::Busy {
+    const MSG_TYPE: MsgType = MsgType::Busy;
+    fn size(&self) -> usize {
+        0
+    }
+    fn write(&self, _: &mut impl Write) -> Result<(), std::io::Error> {
+        Ok(())
+    }
+}
+
+impl SerializeMessage for server::Ping {
+    const MSG_TYPE: MsgType = MsgType::Ping;
+    fn size(&self) -> usize {
+        0
+    }
+    fn write(&self, _: &mut impl Write) -> Result<(), std::io::Error> {
+        Ok(())
+    }
+}
+
+impl SerializeMessage for server::Shutdown {
+    const MSG_TYPE: MsgType = MsgType::Shutdown;
+    fn size(&self) -> usize {
+        0
+    }
+    fn write(&self, _: &mut impl Write) -> Result<(), std::io::Error> {
+        Ok(())
+    }
+}
+
 impl SerializeMessage for client::Connect {
     const MSG_TYPE: MsgType = MsgType::Connect;
     fn size(&self) -> usize {
@@ -144,6 +185,323 @@ impl SerializeMessage for server::UnregisterPeer {
     }
 }
 
+impl SerializeMessage for server::PeerSnapshot {
+    const MSG_TYPE: MsgType = MsgType::PeerSnapshot;
+    fn size(&self) -> usize {
+        self.peers
+            .iter()
+            .map(|p| {
+                p.file_list
+                    .iter()
+                    .map(|a| {
+                        a.path.as_os_str().as_encoded_bytes().len() + std::mem::size_of::<u64>() * 2
+                    })
+                    .sum::<usize>() // files
+                    + std::mem::size_of::<u16>() // server port
+                    + std::mem::size_of::<u32>() // server ip
+                    + std::mem::size_of::<u32>() // file count
+            })
+            .sum::<usize>()
+            + std::mem::size_of::<u32>() // peer count
+    }
+    fn write(&self, stream: &mut impl Write) -> Result<(), std::io::Error> {
+        // {peer_count}:u32 [ {serve_ip}:u32 {serve_port}:u16 {file_count}:u32 [ {file_size}:64 {path_len}:64 {path}:path_len ]* ]*
+        stream.write_all(&(self.peers.len() as u32).to_le_bytes())?;
+        for peer in &self.peers {
+            stream.write_all(&peer.sock.ip().to_bits().to_le_bytes())?;
+            stream.write_all(&peer.sock.port().to_le_bytes())?;
+            stream.write_all(&(peer.file_list.len() as u32).to_le_bytes())?;
+            for file in &peer.file_list {
+                stream.write_all(&file.size.to_le_bytes())?;
+                stream.write_all(&file.path.as_os_str().as_encoded_bytes().len().to_le_bytes())?;
+                stream.write_all(file.path.as_os_str().as_encoded_bytes())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl federation::Registration {
+    fn size(&self) -> usize {
+        self.file_list
+            .iter()
+            .map(|a| a.path.as_os_str().as_encoded_bytes().len() + std::mem::size_of::<u64>() * 2)
+            .sum::<usize>() // files
+            + std::mem::size_of::<u64>() // registered at
+            + std::mem::size_of::<u16>() // server port
+            + std::mem::size_of::<u32>() // server ip
+            + std::mem::size_of::<u32>() // file count
+    }
+    fn write(&self, stream: &mut impl Write) -> Result<(), std::io::Error> {
+        // {registered_at}:u64 {serve_ip}:u32 {serve_port}:u16 {file_count}:u32 [ {file_size}:64 {path_len}:64 {path}:path_len ]*
+        stream.write_all(&self.registered_at.to_le_bytes())?;
+        stream.write_all(&self.sock.ip().to_bits().to_le_bytes())?;
+        stream.write_all(&self.sock.port().to_le_bytes())?;
+        stream.write_all(&(self.file_list.len() as u32).to_le_bytes())?;
+        for file in &self.file_list {
+            stream.write_all(&file.size.to_le_bytes())?;
+            stream.write_all(&file.path.as_os_str().as_encoded_bytes().len().to_le_bytes())?;
+            stream.write_all(file.path.as_os_str().as_encoded_bytes())?;
+        }
+        Ok(())
+    }
+}
+
+impl SerializeMessage for federation::TrackerHello {
+    const MSG_TYPE: MsgType = MsgType::TrackerHello;
+    fn size(&self) -> usize {
+        std::mem::size_of::<u16>() + std::mem::size_of::<u32>()
+    }
+    fn write(&self, stream: &mut impl Write) -> Result<(), std::io::Error> {
+        // {tracker_ip}:u32 {tracker_port}:u16
+        stream.write_all(&self.tracker.ip().to_bits().to_le_bytes())?;
+        stream.write_all(&self.tracker.port().to_le_bytes())
+    }
+}
+
+impl SerializeMessage for federation::SyncPeers {
+    const MSG_TYPE: MsgType = MsgType::SyncPeers;
+    fn size(&self) -> usize {
+        self.peers
+            .iter()
+            .map(federation::Registration::size)
+            .sum::<usize>()
+            + std::mem::size_of::<u32>() // peer count
+    }
+    fn write(&self, stream: &mut impl Write) -> Result<(), std::io::Error> {
+        // {peer_count}:u32 [ {registration} ]*
+        stream.write_all(&(self.peers.len() as u32).to_le_bytes())?;
+        for peer in &self.peers {
+            peer.write(stream)?;
+        }
+        Ok(())
+    }
+}
+
+impl SerializeMessage for federation::ReplicatePeer {
+    const MSG_TYPE: MsgType = MsgType::ReplicatePeer;
+    fn size(&self) -> usize {
+        self.peer.size()
+    }
+    fn write(&self, stream: &mut impl Write) -> Result<(), std::io::Error> {
+        self.peer.write(stream)
+    }
+}
+
+impl SerializeMessage for federation::ForgetPeer {
+    const MSG_TYPE: MsgType = MsgType::ForgetPeer;
+    fn size(&self) -> usize {
+        std::mem::size_of::<u16>() + std::mem::size_of::<u32>()
+    }
+    fn write(&self, stream: &mut impl Write) -> Result<(), std::io::Error> {
+        // {serve_ip}:u32 {serve_port}:u16
+        stream.write_all(&self.sock.ip().to_bits().to_le_bytes())?;
+        stream.write_all(&self.sock.port().to_le_bytes())
+    }
+}
+
+const CONTACT_SIZE: usize = 32 + std::mem::size_of::<u32>() + std::mem::size_of::<u16>();
+const ADDR_SIZE: usize = std::mem::size_of::<u32>() + std::mem::size_of::<u16>();
+
+fn write_addr(addr: SocketAddrV4, stream: &mut impl Write) -> Result<(), std::io::Error> {
+    // {ip}:u32 {port}:u16
+    stream.write_all(&addr.ip().to_bits().to_le_bytes())?;
+    stream.write_all(&addr.port().to_le_bytes())
+}
+
+fn write_contact(contact: dht::Contact, stream: &mut impl Write) -> Result<(), std::io::Error> {
+    // {id}:[u8;32] {ip}:u32 {port}:u16
+    stream.write_all(&contact.id.0)?;
+    write_addr(contact.addr, stream)
+}
+
+fn write_contacts(
+    contacts: &[dht::Contact],
+    stream: &mut impl Write,
+) -> Result<(), std::io::Error> {
+    // {contact_count}:u32 [ {contact} ]*
+    stream.write_all(&(contacts.len() as u32).to_le_bytes())?;
+    for contact in contacts {
+        write_contact(*contact, stream)?;
+    }
+    Ok(())
+}
+
+impl SerializeMessage for dht::FindNode {
+    const MSG_TYPE: MsgType = MsgType::FindNode;
+    fn size(&self) -> usize {
+        CONTACT_SIZE + 32
+    }
+    fn write(&self, stream: &mut impl Write) -> Result<(), std::io::Error> {
+        // {sender} {target}:[u8;32]
+        write_contact(self.sender, stream)?;
+        stream.write_all(&self.target.0)
+    }
+}
+
+impl SerializeMessage for dht::FindProviders {
+    const MSG_TYPE: MsgType = MsgType::FindProviders;
+    fn size(&self) -> usize {
+        CONTACT_SIZE + 32
+    }
+    fn write(&self, stream: &mut impl Write) -> Result<(), std::io::Error> {
+        // {sender} {key}:[u8;32]
+        write_contact(self.sender, stream)?;
+        stream.write_all(&self.key.0)
+    }
+}
+
+impl SerializeMessage for dht::AddProvider {
+    const MSG_TYPE: MsgType = MsgType::AddProvider;
+    fn size(&self) -> usize {
+        CONTACT_SIZE + 32 + ADDR_SIZE
+    }
+    fn write(&self, stream: &mut impl Write) -> Result<(), std::io::Error> {
+        // {sender} {key}:[u8;32] {provider_ip}:u32 {provider_port}:u16
+        write_contact(self.sender, stream)?;
+        stream.write_all(&self.key.0)?;
+        write_addr(self.provider, stream)
+    }
+}
+
+impl SerializeMessage for dht::Nodes {
+    const MSG_TYPE: MsgType = MsgType::Nodes;
+    fn size(&self) -> usize {
+        CONTACT_SIZE + std::mem::size_of::<u32>() + CONTACT_SIZE * self.nodes.len()
+    }
+    fn write(&self, stream: &mut impl Write) -> Result<(), std::io::Error> {
+        // {sender} {node_count}:u32 [ {node} ]*
+        write_contact(self.sender, stream)?;
+        write_contacts(&self.nodes, stream)
+    }
+}
+
+impl SerializeMessage for dht::Providers {
+    const MSG_TYPE: MsgType = MsgType::Providers;
+    fn size(&self) -> usize {
+        CONTACT_SIZE
+            + std::mem::size_of::<u32>() // provider count
+            + ADDR_SIZE * self.providers.len()
+            + std::mem::size_of::<u32>() // node count
+            + CONTACT_SIZE * self.nodes.len()
+    }
+    fn write(&self, stream: &mut impl Write) -> Result<(), std::io::Error> {
+        // {sender} {provider_count}:u32 [ {provider_ip}:u32 {provider_port}:u16 ]* {node_count}:u32 [ {node} ]*
+        write_contact(self.sender, stream)?;
+        stream.write_all(&(self.providers.len() as u32).to_le_bytes())?;
+        for provider in &self.providers {
+            write_addr(*provider, stream)?;
+        }
+        write_contacts(&self.nodes, stream)
+    }
+}
+
+impl SerializeMessage for client::PeerExchange {
+    const MSG_TYPE: MsgType = MsgType::PeerExchange;
+    fn size(&self) -> usize {
+        ADDR_SIZE // sender
+            + std::mem::size_of::<u32>() // peer count
+            + self
+                .peers
+                .iter()
+                .map(|p| {
+                    p.file_list
+                        .iter()
+                        .map(|a| {
+                            a.path.as_os_str().as_encoded_bytes().len()
+                                + std::mem::size_of::<u64>() * 2
+                        })
+                        .sum::<usize>() // files
+                        + std::mem::size_of::<u64>() // age
+                        + ADDR_SIZE // peer
+                        + std::mem::size_of::<u32>() // file count
+                })
+                .sum::<usize>()
+    }
+    fn write(&self, stream: &mut impl Write) -> Result<(), std::io::Error> {
+        // {sender_ip}:u32 {sender_port}:u16 {peer_count}:u32 [ {age_ms}:u64 {serve_ip}:u32 {serve_port}:u16 {file_count}:u32 [ {file_size}:64 {path_len}:64 {path}:path_len ]* ]*
+        write_addr(self.sender, stream)?;
+        stream.write_all(&(self.peers.len() as u32).to_le_bytes())?;
+        for peer in &self.peers {
+            stream.write_all(&peer.age_ms.to_le_bytes())?;
+            write_addr(peer.sock, stream)?;
+            stream.write_all(&(peer.file_list.len() as u32).to_le_bytes())?;
+            for file in &peer.file_list {
+                stream.write_all(&file.size.to_le_bytes())?;
+                stream.write_all(&file.path.as_os_str().as_encoded_bytes().len().to_le_bytes())?;
+                stream.write_all(file.path.as_os_str().as_encoded_bytes())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl SerializeMessage for client::LanAnnounce {
+    const MSG_TYPE: MsgType = MsgType::LanAnnounce;
+    fn size(&self) -> usize {
+        self.identity.0.len() + ADDR_SIZE + self.digest.len()
+    }
+    fn write(&self, stream: &mut impl Write) -> Result<(), std::io::Error> {
+        // {identity}:[u8;32] {serve_ip}:u32 {serve_port}:u16 {digest}:[u8;32]
+        stream.write_all(&self.identity.0)?;
+        write_addr(self.sock, stream)?;
+        stream.write_all(&self.digest)
+    }
+}
+
+impl SerializeMessage for client::RelayConnect {
+    const MSG_TYPE: MsgType = MsgType::RelayConnect;
+    fn size(&self) -> usize {
+        ADDR_SIZE
+    }
+    fn write(&self, stream: &mut impl Write) -> Result<(), std::io::Error> {
+        // {target_ip}:u32 {target_port}:u16
+        write_addr(self.target, stream)
+    }
+}
+
+impl SerializeMessage for client::RelayAccept {
+    const MSG_TYPE: MsgType = MsgType::RelayAccept;
+    fn size(&self) -> usize {
+        std::mem::size_of::<u64>()
+    }
+    fn write(&self, stream: &mut impl Write) -> Result<(), std::io::Error> {
+        stream.write_all(&self.session.to_le_bytes())
+    }
+}
+
+impl SerializeMessage for client::SendingFile {
+    const MSG_TYPE: MsgType = MsgType::SendingFile;
+    fn size(&self) -> usize {
+        std::mem::size_of::<u64>()
+    }
+    fn write(&self, stream: &mut impl Write) -> Result<(), std::io::Error> {
+        stream.write_all(&self.size.to_le_bytes())
+    }
+}
+
+impl SerializeMessage for server::RelayIncoming {
+    const MSG_TYPE: MsgType = MsgType::RelayIncoming;
+    fn size(&self) -> usize {
+        std::mem::size_of::<u64>()
+    }
+    fn write(&self, stream: &mut impl Write) -> Result<(), std::io::Error> {
+        stream.write_all(&self.session.to_le_bytes())
+    }
+}
+
+impl SerializeMessage for server::Refused {
+    const MSG_TYPE: MsgType = MsgType::Refused;
+    fn size(&self) -> usize {
+        std::mem::size_of::<u64>() + self.reason.len()
+    }
+    fn write(&self, stream: &mut impl Write) -> Result<(), std::io::Error> {
+        // {reason_len}:u64 {reason}:reason_len
+        stream.write_all(&self.reason.len().to_le_bytes())?;
+        stream.write_all(self.reason.as_bytes())
+    }
+}
+
 impl Serialize for client::Message {
     fn msg_type(&self) -> MsgType {
         match self {
@@ -151,6 +509,13 @@ impl Serialize for client::Message {
             client::Message::Disconnect(m) => m.msg_type(),
             client::Message::UpdateFiles(m) => m.msg_type(),
             client::Message::RequestFile(m) => m.msg_type(),
+            client::Message::Pong(m) => m.msg_type(),
+            client::Message::PeerExchange(m) => m.msg_type(),
+            client::Message::LanAnnounce(m) => m.msg_type(),
+            client::Message::RelayConnect(m) => m.msg_type(),
+            client::Message::RelayAccept(m) => m.msg_type(),
+            client::Message::SendingFile(m) => m.msg_type(),
+            client::Message::Busy(m) => m.msg_type(),
         }
     }
     fn size(&self) -> usize {
@@ -159,6 +524,13 @@ impl Serialize for client::Message {
             client::Message::Disconnect(m) => m.size(),
             client::Message::UpdateFiles(m) => m.size(),
             client::Message::RequestFile(m) => m.size(),
+            client::Message::Pong(m) => m.size(),
+            client::Message::PeerExchange(m) => m.size(),
+            client::Message::LanAnnounce(m) => m.size(),
+            client::Message::RelayConnect(m) => m.size(),
+            client::Message::RelayAccept(m) => m.size(),
+            client::Message::SendingFile(m) => m.size(),
+            client::Message::Busy(m) => m.size(),
         }
     }
     fn write(&self, stream: &mut impl Write) -> Result<(), std::io::Error> {
@@ -167,6 +539,13 @@ impl Serialize for client::Message {
             client::Message::Disconnect(m) => m.write(stream),
             client::Message::UpdateFiles(m) => m.write(stream),
             client::Message::RequestFile(m) => m.write(stream),
+            client::Message::Pong(m) => m.write(stream),
+            client::Message::PeerExchange(m) => m.write(stream),
+            client::Message::LanAnnounce(m) => m.write(stream),
+            client::Message::RelayConnect(m) => m.write(stream),
+            client::Message::RelayAccept(m) => m.write(stream),
+            client::Message::SendingFile(m) => m.write(stream),
+            client::Message::Busy(m) => m.write(stream),
         }
     }
 }
@@ -177,6 +556,11 @@ impl Serialize for server::Message {
             server::Message::UpdatePeer(m) => m.msg_type(),
             server::Message::RegisterPeer(m) => m.msg_type(),
             server::Message::UnregisterPeer(m) => m.msg_type(),
+            server::Message::Ping(m) => m.msg_type(),
+            server::Message::PeerSnapshot(m) => m.msg_type(),
+            server::Message::RelayIncoming(m) => m.msg_type(),
+            server::Message::Shutdown(m) => m.msg_type(),
+            server::Message::Refused(m) => m.msg_type(),
         }
     }
     fn size(&self) -> usize {
@@ -184,6 +568,11 @@ impl Serialize for server::Message {
             server::Message::UpdatePeer(m) => m.size(),
             server::Message::RegisterPeer(m) => m.size(),
             server::Message::UnregisterPeer(m) => m.size(),
+            server::Message::Ping(m) => m.size(),
+            server::Message::PeerSnapshot(m) => m.size(),
+            server::Message::RelayIncoming(m) => m.size(),
+            server::Message::Shutdown(m) => m.size(),
+            server::Message::Refused(m) => m.size(),
         }
     }
     fn write(&self, stream: &mut impl Write) -> Result<(), std::io::Error> {
@@ -191,6 +580,68 @@ impl Serialize for server::Message {
             server::Message::UpdatePeer(m) => m.write(stream),
             server::Message::RegisterPeer(m) => m.write(stream),
             server::Message::UnregisterPeer(m) => m.write(stream),
+            server::Message::Ping(m) => m.write(stream),
+            server::Message::PeerSnapshot(m) => m.write(stream),
+            server::Message::RelayIncoming(m) => m.write(stream),
+            server::Message::Shutdown(m) => m.write(stream),
+            server::Message::Refused(m) => m.write(stream),
+        }
+    }
+}
+
+impl Serialize for federation::Message {
+    fn msg_type(&self) -> MsgType {
+        match self {
+            federation::Message::TrackerHello(m) => m.msg_type(),
+            federation::Message::SyncPeers(m) => m.msg_type(),
+            federation::Message::ReplicatePeer(m) => m.msg_type(),
+            federation::Message::ForgetPeer(m) => m.msg_type(),
+        }
+    }
+    fn size(&self) -> usize {
+        match self {
+            federation::Message::TrackerHello(m) => m.size(),
+            federation::Message::SyncPeers(m) => m.size(),
+            federation::Message::ReplicatePeer(m) => m.size(),
+            federation::Message::ForgetPeer(m) => m.size(),
+        }
+    }
+    fn write(&self, stream: &mut impl Write) -> Result<(), std::io::Error> {
+        match self {
+            federation::Message::TrackerHello(m) => m.write(stream),
+            federation::Message::SyncPeers(m) => m.write(stream),
+            federation::Message::ReplicatePeer(m) => m.write(stream),
+            federation::Message::ForgetPeer(m) => m.write(stream),
+        }
+    }
+}
+
+impl Serialize for dht::Message {
+    fn msg_type(&self) -> MsgType {
+        match self {
+            dht::Message::FindNode(m) => m.msg_type(),
+            dht::Message::FindProviders(m) => m.msg_type(),
+            dht::Message::AddProvider(m) => m.msg_type(),
+            dht::Message::Nodes(m) => m.msg_type(),
+            dht::Message::Providers(m) => m.msg_type(),
+        }
+    }
+    fn size(&self) -> usize {
+        match self {
+            dht::Message::FindNode(m) => m.size(),
+            dht::Message::FindProviders(m) => m.size(),
+            dht::Message::AddProvider(m) => m.size(),
+            dht::Message::Nodes(m) => m.size(),
+            dht::Message::Providers(m) => m.size(),
+        }
+    }
+    fn write(&self, stream: &mut impl Write) -> Result<(), std::io::Error> {
+        match self {
+            dht::Message::FindNode(m) => m.write(stream),
+            dht::Message::FindProviders(m) => m.write(stream),
+            dht::Message::AddProvider(m) => m.write(stream),
+            dht::Message::Nodes(m) => m.write(stream),
+            dht::Message::Providers(m) => m.write(stream),
         }
     }
 }
@@ -200,18 +651,24 @@ impl Serialize for AnyMessage {
         match self {
             AnyMessage::Client(m) => m.size(),
             AnyMessage::Server(m) => m.size(),
+            AnyMessage::Federation(m) => m.size(),
+            AnyMessage::Dht(m) => m.size(),
         }
     }
     fn msg_type(&self) -> MsgType {
         match self {
             AnyMessage::Client(m) => m.msg_type(),
             AnyMessage::Server(m) => m.msg_type(),
+            AnyMessage::Federation(m) => m.msg_type(),
+            AnyMessage::Dht(m) => m.msg_type(),
         }
     }
     fn write(&self, stream: &mut impl Write) -> Result<(), std::io::Error> {
         match self {
             AnyMessage::Client(m) => m.write(stream),
             AnyMessage::Server(m) => m.write(stream),
+            AnyMessage::Federation(m) => m.write(stream),
+            AnyMessage::Dht(m) => m.write(stream),
         }
     }
 }
@@ -16,7 +16,16 @@ fn test_de_serial() -> Result<(), CommonError> {
         path: PathBuf::from("hi.txt"),
         size: 1024 * 1024 * 4, // 4 MiB
     };
-    let msgs: [AnyMessage; 7] = [
+    let registration = || federation::Registration {
+        sock: "10.134.213.134:49583".parse().unwrap(),
+        file_list: vec![file(), file()],
+        registered_at: 1_700_000_000_000,
+    };
+    let contact = |n: u8| dht::Contact {
+        id: dht::NodeId([n; 32]),
+        addr: "10.134.213.134:49583".parse().unwrap(),
+    };
+    let msgs: [AnyMessage; 29] = [
         client::Message::Connect(client::Connect {
             serve_port: 0,
             file_list: vec![file(), file(), file()],
@@ -45,9 +54,193 @@ fn test_de_serial() -> Result<(), CommonError> {
             sock: "10.134.213.134:49583".parse().unwrap(),
         })
         .into(),
+        server::Message::Ping(server::Ping).into(),
+        client::Message::Pong(client::Pong).into(),
+        server::Message::PeerSnapshot(server::PeerSnapshot {
+            peers: vec![
+                server::PeerInfo {
+                    sock: "10.134.213.134:49583".parse().unwrap(),
+                    file_list: vec![file(), file()],
+                },
+                server::PeerInfo {
+                    sock: "10.134.213.135:49584".parse().unwrap(),
+                    file_list: vec![],
+                },
+            ],
+        })
+        .into(),
+        server::Message::PeerSnapshot(server::PeerSnapshot { peers: vec![] }).into(),
+        federation::Message::TrackerHello(federation::TrackerHello {
+            tracker: "10.134.213.1:6969".parse().unwrap(),
+        })
+        .into(),
+        federation::Message::SyncPeers(federation::SyncPeers {
+            peers: vec![registration(), registration()],
+        })
+        .into(),
+        federation::Message::ReplicatePeer(federation::ReplicatePeer {
+            peer: registration(),
+        })
+        .into(),
+        federation::Message::ForgetPeer(federation::ForgetPeer {
+            sock: "10.134.213.134:49583".parse().unwrap(),
+        })
+        .into(),
+        client::Message::PeerExchange(client::PeerExchange {
+            sender: "10.134.213.135:49584".parse().unwrap(),
+            peers: vec![client::ExchangedPeer {
+                sock: "10.134.213.134:49583".parse().unwrap(),
+                file_list: vec![file()],
+                age_ms: 1500,
+            }],
+        })
+        .into(),
+        client::Message::LanAnnounce(client::LanAnnounce {
+            identity: dht::NodeId::hash(b"identity"),
+            sock: "0.0.0.0:49584".parse().unwrap(),
+            digest: client::LanAnnounce::digest(&[file()]),
+        })
+        .into(),
+        client::Message::RelayConnect(client::RelayConnect {
+            target: "10.134.213.134:49583".parse().unwrap(),
+        })
+        .into(),
+        client::Message::RelayAccept(client::RelayAccept { session: 42 }).into(),
+        client::Message::SendingFile(client::SendingFile { size: 4096 }).into(),
+        client::Message::Busy(client::Busy).into(),
+        server::Message::RelayIncoming(server::RelayIncoming { session: 42 }).into(),
+        server::Message::Shutdown(server::Shutdown).into(),
+        server::Message::Refused(server::Refused {
+            reason: "the tracker is full".to_string(),
+        })
+        .into(),
+        dht::Message::FindNode(dht::FindNode {
+            sender: contact(1),
+            target: dht::NodeId::hash(b"target"),
+        })
+        .into(),
+        dht::Message::FindProviders(dht::FindProviders {
+            sender: contact(1),
+            key: dht::NodeId::for_path(&PathBuf::from("hi.txt")),
+        })
+        .into(),
+        dht::Message::AddProvider(dht::AddProvider {
+            sender: contact(1),
+            key: dht::NodeId::for_path(&PathBuf::from("hi.txt")),
+            provider: "10.134.213.135:49584".parse().unwrap(),
+        })
+        .into(),
+        dht::Message::Nodes(dht::Nodes {
+            sender: contact(1),
+            nodes: vec![contact(2), contact(3)],
+        })
+        .into(),
+        dht::Message::Providers(dht::Providers {
+            sender: contact(1),
+            providers: vec!["10.134.213.135:49584".parse().unwrap()],
+            nodes: vec![contact(4)],
+        })
+        .into(),
     ];
     for msg in msgs {
         test_serialize_deserialize(msg)?;
     }
     Ok(())
 }
+
+#[test]
+fn test_huge_lengths_are_rejected() {
+    use crate::deserialize::DeserializeError;
+    // A message claiming a terabyte of content
+    let mut msg = vec![MsgType::LanAnnounce as u8];
+    msg.extend((1u64 << 40).to_le_bytes());
+    assert!(matches!(
+        read_msg(&mut &msg[..]),
+        Err(DeserializeError::TooLong(..))
+    ));
+
+    // A file list claiming four billion entries
+    let mut content = 0u16.to_le_bytes().to_vec();
+    content.extend(u32::MAX.to_le_bytes());
+    let mut msg = vec![MsgType::Connect as u8];
+    msg.extend((content.len() as u64).to_le_bytes());
+    msg.extend(content);
+    assert!(matches!(
+        read_msg(&mut &msg[..]),
+        Err(DeserializeError::TooLong(..))
+    ));
+}
+
+#[test]
+fn test_lan_digest_ignores_order() {
+    let file = |path: &str, size| File {
+        path: PathBuf::from(path),
+        size,
+    };
+    let digest = client::LanAnnounce::digest;
+    assert_eq!(
+        digest(&[file("a", 1), file("b", 2)]),
+        digest(&[file("b", 2), file("a", 1)])
+    );
+    assert_ne!(digest(&[file("a", 1)]), digest(&[file("a", 2)]));
+    assert_ne!(digest(&[]), digest(&[file("a", 1)]));
+}
+
+#[test]
+fn test_dht_distance() {
+    let a = dht::NodeId([0; 32]);
+    let mut b = a;
+    b.0[31] = 1;
+    let mut c = a;
+    c.0[0] = 0x80;
+    assert_eq!(a.bucket(&a), None);
+    assert_eq!(a.bucket(&b), Some(0));
+    assert_eq!(a.bucket(&c), Some(255));
+    assert!(a.distance(&b) < a.distance(&c));
+    assert_eq!(b.distance(&c), c.distance(&b));
+}
+
+#[test]
+fn test_node_id_hex() {
+    let id = dht::NodeId::for_path(std::path::Path::new("a/b.txt"));
+    let hex = id.to_string();
+    assert_eq!(hex.len(), 64);
+    assert_eq!(dht::NodeId::from_hex(&hex), Some(id));
+    assert_eq!(dht::NodeId::from_hex(&hex.to_uppercase()), Some(id));
+    assert_eq!(dht::NodeId::from_hex("a/b.txt"), None);
+    assert_eq!(dht::NodeId::from_hex(&"é".repeat(32)), None);
+}
+
+#[test]
+fn test_log_filter() {
+    use tracing::Level;
+    use tracing_subscriber::filter::LevelFilter;
+
+    let filter = log::parse_filter("warn, server::relay=DEBUG").unwrap();
+    assert!(filter.would_enable("server", &Level::WARN));
+    assert!(!filter.would_enable("server", &Level::INFO));
+    assert!(filter.would_enable("server::relay", &Level::DEBUG));
+    assert!(filter.would_enable("server::relay::pipe", &Level::DEBUG));
+    assert_eq!(
+        log::parse_filter("off").unwrap().default_level(),
+        Some(LevelFilter::OFF)
+    );
+    assert!(log::parse_filter("loud").is_err());
+    assert!(log::parse_filter("server=loud").is_err());
+
+    assert_eq!("JSON".parse(), Ok(log::LogFormat::Json));
+    assert_eq!("text".parse(), Ok(log::LogFormat::Text));
+    assert!("xml".parse::<log::LogFormat>().is_err());
+}
+
+#[test]
+fn rate_limit_delays_bursts() {
+    use crate::rate::RateLimit;
+    use std::time::Duration;
+
+    let mut limit = RateLimit::new(1000);
+    assert_eq!(limit.take(1000), Duration::ZERO);
+    let wait = limit.take(500);
+    assert!(wait > Duration::from_millis(400) && wait <= Duration::from_millis(500));
+    assert!(!limit.try_take(1));
+}
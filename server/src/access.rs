@@ -0,0 +1,204 @@
+//! Who may use the tracker: IP allow and deny lists, connection and message rates per IP,
+//! and a maximum number of peers.
+//!
+//! Refused clients are sent [`Refused`] with the reason before their connection is closed.
+//! Federated trackers are subject to the same limits, except the maximum number of peers.
+
+use crate::Context;
+use common::rate::RateLimit;
+use common::server::{self, Refused};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::io::Read;
+use std::net::{Ipv4Addr, Shutdown, TcpStream};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+/// Rate limits kept before forgetting those of idle IPs
+const TRACKED_IPS: usize = 1024;
+
+/// How long refused clients get to read why before their connection is closed
+const LINGER: Duration = Duration::from_secs(1);
+
+/// An IPv4 network like `10.0.0.0/8`, a lone address is a `/32`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Cidr {
+    addr: Ipv4Addr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: Ipv4Addr) -> bool {
+        let mask = u32::MAX
+            .checked_shl(32 - u32::from(self.prefix))
+            .unwrap_or(0);
+        u32::from(ip) & mask == u32::from(self.addr) & mask
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = s.split_once('/').unwrap_or((s, "32"));
+        let addr = addr
+            .parse()
+            .map_err(|_| format!("{addr:?} isn't an IPv4 address"))?;
+        match prefix.parse() {
+            Ok(prefix @ 0..=32) => Ok(Self { addr, prefix }),
+            _ => Err(format!("{prefix:?} isn't a prefix length from 0 to 32")),
+        }
+    }
+}
+
+impl TryFrom<String> for Cidr {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl std::fmt::Display for Cidr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AccessConfig {
+    /// When not empty, only these networks may connect
+    pub allow: Vec<Cidr>,
+    /// Networks that may never connect, even when allowed
+    pub deny: Vec<Cidr>,
+    /// New connections per second allowed from each IP, in bursts of up to a second's worth
+    pub connection_rate: Option<u32>,
+    /// Messages per second allowed from each IP over all of its connections
+    pub message_rate: Option<u32>,
+    pub max_peers: Option<usize>,
+}
+
+/// Why a client was refused
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum Refusal {
+    #[error("your address isn't allowed")]
+    Denied,
+    #[error("your address is banned")]
+    Banned,
+    #[error("too many connections, slow down")]
+    ConnectionRate,
+    #[error("too many messages, slow down")]
+    MessageRate,
+    #[error("the tracker is full")]
+    Full,
+    #[error("this tracker doesn't federate with you")]
+    NotFederated,
+}
+
+impl Refusal {
+    /// Label of the refusal in metrics
+    pub fn name(&self) -> &'static str {
+        match self {
+            Refusal::Denied => "denied",
+            Refusal::Banned => "banned",
+            Refusal::ConnectionRate => "connection_rate",
+            Refusal::MessageRate => "message_rate",
+            Refusal::Full => "full",
+            Refusal::NotFederated => "not_federated",
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Access {
+    config: AccessConfig,
+    connections: HashMap<Ipv4Addr, RateLimit>,
+    messages: HashMap<Ipv4Addr, RateLimit>,
+}
+
+impl Access {
+    pub fn new(config: AccessConfig) -> Self {
+        Self {
+            config,
+            ..Self::default()
+        }
+    }
+
+    fn allows(&self, ip: Ipv4Addr) -> bool {
+        let allowed =
+            self.config.allow.is_empty() || self.config.allow.iter().any(|c| c.contains(ip));
+        allowed && !self.config.deny.iter().any(|c| c.contains(ip))
+    }
+
+    /// Checks a new connection from `ip` against the lists and the connection rate
+    pub fn connection(&mut self, ip: Ipv4Addr) -> Result<(), Refusal> {
+        if !self.allows(ip) {
+            return Err(Refusal::Denied);
+        }
+        match self.config.connection_rate {
+            Some(rate) if !take(&mut self.connections, ip, rate) => Err(Refusal::ConnectionRate),
+            _ => Ok(()),
+        }
+    }
+
+    /// Checks a message from a client at `ip` against the message rate
+    pub fn message(&mut self, ip: Ipv4Addr) -> Result<(), Refusal> {
+        match self.config.message_rate {
+            Some(rate) if !take(&mut self.messages, ip, rate) => Err(Refusal::MessageRate),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn max_peers(&self) -> Option<usize> {
+        self.config.max_peers
+    }
+}
+
+/// Takes a token from the bucket of `ip`, forgetting the buckets that are full again when
+/// there are too many
+fn take(limits: &mut HashMap<Ipv4Addr, RateLimit>, ip: Ipv4Addr, rate: u32) -> bool {
+    if !limits.contains_key(&ip) && limits.len() >= TRACKED_IPS {
+        limits.retain(|_, limit| limit.idle() < Duration::from_secs(1));
+    }
+    limits
+        .entry(ip)
+        .or_insert_with(|| RateLimit::new(u64::from(rate)))
+        .try_take(1)
+}
+
+impl Context {
+    /// Checks a new connection from `ip`, see [`Access::connection`]
+    pub(crate) fn admit(&mut self, ip: Ipv4Addr) -> Result<(), Refusal> {
+        if self.banned.contains(&ip) {
+            return Err(Refusal::Banned);
+        }
+        self.access.connection(ip)
+    }
+
+    /// Tells the client on `conn` why it's refused and closes the connection
+    pub(crate) fn refuse(&self, conn: &mut TcpStream, refusal: Refusal) {
+        tracing::info!(remote = ?conn.peer_addr().ok(), %refusal, "refusing client");
+        self.metrics.refused(refusal.name());
+        let refused = server::Message::from(Refused {
+            reason: refusal.to_string(),
+        });
+        let _ = conn.set_write_timeout(Some(LINGER));
+        if let Err(e) = self.metrics.write(conn, &refused) {
+            tracing::debug!(error = %e, "failed to tell the client why it's refused");
+        }
+        let _ = conn.shutdown(Shutdown::Write);
+        // Closing with unread data resets the connection, which can lose the reason before the
+        // client reads it, so what it still sends is read and dropped for a while
+        if let Ok(mut draining) = conn.try_clone() {
+            std::thread::spawn(move || {
+                let deadline = Instant::now() + LINGER;
+                let _ = draining.set_read_timeout(Some(LINGER));
+                let mut buf = [0u8; 1024];
+                while Instant::now() < deadline && draining.read(&mut buf).is_ok_and(|n| n > 0) {}
+                let _ = draining.shutdown(Shutdown::Both);
+            });
+        }
+    }
+}
@@ -0,0 +1,186 @@
+//! Admin API, served as JSON over HTTP on a loopback address when one is configured.
+//!
+//! - `GET /peers`: every known peer with its files, `tracker` is `null` for peers connected
+//!   to this tracker
+//! - `GET /files[?path=PATH]`: every known file, or only `PATH`, with the peers sharing it
+//! - `POST /peers/ADDR/kick`: disconnects a peer connected to this tracker, the others are
+//!   sent [`UnregisterPeer`](common::server::UnregisterPeer)
+//! - `POST /peers/ADDR/ban`: refuses connections from the peer's IP from then on, kicking
+//!   every peer connected from it
+//! - `GET /bans`, `DELETE /bans/IP`: lists banned IPs, lifts a ban
+//! - `GET /stats`: counts of peers, files, links and relayed connections
+//!
+//! Requests from browsers are refused: those with an `Origin` header, and those whose `Host`
+//! isn't a loopback address, which a page on another site could only send after rebinding
+//! its domain to a loopback address.
+
+use crate::Context;
+use crate::http::{self, Request, Response};
+use common::File;
+use serde_json::{Value, json};
+use std::collections::BTreeMap;
+use std::net::{IpAddr, Ipv4Addr, Shutdown, SocketAddrV4, TcpListener};
+use std::sync::{Arc, Mutex};
+
+/// Answers admin requests forever
+pub fn serve(listener: &TcpListener, ctx: &Arc<Mutex<Context>>) {
+    http::serve(listener, |request| {
+        if !request.remote.ip().is_loopback() {
+            return error("403 Forbidden", "the admin API is only served locally");
+        }
+        if request.headers.contains_key("origin")
+            || !request
+                .headers
+                .get("host")
+                .is_some_and(|h| loopback_host(h))
+        {
+            return error("403 Forbidden", "the admin API isn't served to browsers");
+        }
+        let response = handle(&mut ctx.lock().unwrap(), request);
+        tracing::debug!(
+            method = request.method,
+            path = request.path,
+            status = response.status,
+            "answered admin request"
+        );
+        response
+    })
+}
+
+/// Whether `host`, as in a `Host` header, names a loopback address
+fn loopback_host(host: &str) -> bool {
+    let name = match host.strip_prefix('[') {
+        Some(v6) => v6.split_once(']').map_or(v6, |(ip, _)| ip),
+        None => host.rsplit_once(':').map_or(host, |(name, _)| name),
+    };
+    name.eq_ignore_ascii_case("localhost")
+        || name.parse::<IpAddr>().is_ok_and(|ip| ip.is_loopback())
+}
+
+fn handle(ctx: &mut Context, request: &Request) -> Response {
+    let segments: Vec<&str> = request.path.split('/').filter(|s| !s.is_empty()).collect();
+    match (request.method.as_str(), segments.as_slice()) {
+        ("GET", ["peers"]) => Response::json("200 OK", &ctx.admin_peers()),
+        ("GET", ["files"]) => {
+            let path = request.query.get("path").map(String::as_str);
+            Response::json("200 OK", &ctx.admin_files(path))
+        }
+        ("GET", ["stats"]) => Response::json("200 OK", &ctx.admin_stats()),
+        ("GET", ["bans"]) => {
+            let bans: Vec<_> = ctx.banned.iter().map(Ipv4Addr::to_string).collect();
+            Response::json("200 OK", &json!(bans))
+        }
+        ("POST", ["peers", addr, action @ ("kick" | "ban")]) => {
+            let Ok(sock) = addr.parse::<SocketAddrV4>() else {
+                return error("400 Bad Request", &format!("{addr} isn't an IPv4 address"));
+            };
+            if *action == "ban" {
+                tracing::info!(ip = %sock.ip(), "banning peer");
+                ctx.banned.insert(*sock.ip());
+                let kicked = ctx.kick(|p| p.ip() == sock.ip());
+                return Response::json("200 OK", &json!({ "kicked": kicked }));
+            }
+            match ctx.kick(|p| *p == sock).as_slice() {
+                [] => error("404 Not Found", &format!("{sock} isn't connected here")),
+                kicked => Response::json("200 OK", &json!({ "kicked": kicked })),
+            }
+        }
+        ("DELETE", ["bans", ip]) => match ip.parse() {
+            Ok(ip) if ctx.banned.remove(&ip) => {
+                tracing::info!(%ip, "lifted ban");
+                Response::json("200 OK", &json!({ "unbanned": ip }))
+            }
+            Ok(ip) => error("404 Not Found", &format!("{ip} isn't banned")),
+            Err(_) => error("400 Bad Request", &format!("{ip} isn't an IPv4 address")),
+        },
+        ("GET" | "POST" | "DELETE", _) => error("404 Not Found", "no such endpoint"),
+        _ => Response::method_not_allowed(),
+    }
+}
+
+fn files_json(files: &[File]) -> Value {
+    files
+        .iter()
+        .map(|f| json!({ "path": f.path, "size": f.size }))
+        .collect()
+}
+
+fn error(status: &'static str, message: &str) -> Response {
+    Response::json(status, &json!({ "error": message }))
+}
+
+impl Context {
+    fn admin_peers(&self) -> Value {
+        let local = self.peers.iter().map(|p| {
+            json!({
+                "addr": p.server_addr,
+                "tracker": null,
+                "registered_at": p.registered_at,
+                "last_seen_secs": p.last_seen.elapsed().as_secs(),
+                "files": files_json(&p.files),
+            })
+        });
+        let remote = self.remote.iter().flat_map(|(tracker, peers)| {
+            peers.values().map(move |r| {
+                json!({
+                    "addr": r.sock,
+                    "tracker": tracker,
+                    "registered_at": r.registered_at,
+                    "files": files_json(&r.file_list),
+                })
+            })
+        });
+        Value::Array(local.chain(remote).collect())
+    }
+
+    /// Every file in the view with the peers sharing it, sorted by path
+    fn admin_files(&self, only: Option<&str>) -> Value {
+        let mut files: BTreeMap<_, Vec<Value>> = BTreeMap::new();
+        for (sock, file_list) in self.view() {
+            for file in file_list {
+                if only.is_none_or(|path| file.path.as_os_str() == path) {
+                    let peer = json!({ "addr": sock, "size": file.size });
+                    files.entry(file.path).or_default().push(peer);
+                }
+            }
+        }
+        files
+            .into_iter()
+            .map(|(path, peers)| json!({ "path": path, "peers": peers }))
+            .collect()
+    }
+
+    fn admin_stats(&self) -> Value {
+        let view = self.view();
+        json!({
+            "uptime_secs": self.started.elapsed().as_secs(),
+            "peers": self.peers.len(),
+            "known_peers": view.len(),
+            "files": self.peers.iter().map(|p| p.files.len()).sum::<usize>(),
+            "known_files": view.values().map(Vec::len).sum::<usize>(),
+            "federated_trackers": self.links.keys().collect::<Vec<_>>(),
+            "relay": {
+                "spliced": self.relay.spliced(),
+                "pending": self.relay.pending(),
+            },
+            "banned": self.banned.len(),
+            "closing": self.closing,
+        })
+    }
+
+    /// Disconnects every local peer whose address matches, returning their addresses
+    fn kick(&mut self, matches: impl Fn(&SocketAddrV4) -> bool) -> Vec<SocketAddrV4> {
+        let kicked: Vec<_> = self
+            .peers
+            .iter()
+            .filter(|p| matches(&p.server_addr))
+            .map(|p| (p.server_addr, Arc::clone(&p.conn)))
+            .collect();
+        for (sock, conn) in &kicked {
+            tracing::info!(peer = %sock, "kicking peer");
+            let _ = conn.lock().unwrap().shutdown(Shutdown::Both);
+            self.unregister_peer(conn);
+        }
+        kicked.into_iter().map(|(sock, _)| sock).collect()
+    }
+}
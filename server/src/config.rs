@@ -0,0 +1,303 @@
+//! The tracker's configuration: built-in defaults, overridden by the TOML file, overridden by
+//! environment variables and command line arguments.
+//!
+//! ```toml
+//! listen = "0.0.0.0:6969"
+//! advertise = "10.0.0.1:6969"
+//! federate = ["10.0.0.2:6969"]
+//!
+//! [heartbeat]
+//! interval_secs = 5
+//! timeout_secs = 15
+//!
+//! [relay]
+//! enabled = true
+//! rate = 1048576        # bytes per second, per relayed connection
+//! total_rate = 10485760 # bytes per second, all relayed connections together
+//! accept_timeout_secs = 10
+//!
+//! [access]
+//! allow = ["10.0.0.0/8"]
+//! deny = ["10.6.6.0/24"]
+//! connection_rate = 5 # per second, per IP
+//! message_rate = 50   # per second, per IP
+//! max_peers = 1000
+//!
+//! [metrics]
+//! listen = "127.0.0.1:9100"
+//!
+//! [admin]
+//! listen = "127.0.0.1:6970"
+//!
+//! [log]
+//! level = "info,server::relay=debug"
+//! format = "json"
+//! ```
+
+use crate::HeartbeatConfig;
+use crate::access::{AccessConfig, Cidr};
+use crate::relay::RelayConfig;
+use clap::Parser;
+use common::config::{ConfigError, LogConfig};
+use common::log::LogFormat;
+use serde::Deserialize;
+use std::net::SocketAddrV4;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Keeps track of the peers sharing files and tells them about each other
+#[derive(Parser)]
+#[command(name = "server")]
+pub struct Args {
+    /// Address to accept peers and trackers on [default: 127.0.0.1:6969]
+    #[arg(env = "P2PRS_SERVER_LISTEN")]
+    pub listen: Option<SocketAddrV4>,
+    /// Config file [default: $XDG_CONFIG_HOME/p2prs/server.toml if it exists]
+    #[arg(long, env = "P2PRS_SERVER_CONFIG", value_name = "PATH")]
+    pub config: Option<PathBuf>,
+    /// Address federated trackers reach this one on [default: the listen address]
+    #[arg(long, env = "P2PRS_SERVER_ADVERTISE", value_name = "ADDR")]
+    pub advertise: Option<SocketAddrV4>,
+    /// Tracker to replicate peers with, only those are accepted as federated trackers, can be
+    /// repeated
+    #[arg(
+        long,
+        env = "P2PRS_SERVER_FEDERATE",
+        value_delimiter = ',',
+        value_name = "ADDR"
+    )]
+    pub federate: Vec<SocketAddrV4>,
+    /// Relay connections to peers that can't accept them
+    #[arg(long)]
+    pub relay: bool,
+    /// Bytes per second allowed through each relayed connection
+    #[arg(long, value_name = "BYTES_PER_SEC")]
+    pub relay_rate: Option<u64>,
+    /// Bytes per second allowed through all relayed connections together
+    #[arg(long, value_name = "BYTES_PER_SEC")]
+    pub relay_total_rate: Option<u64>,
+    /// Only accept clients from this IP or network, like 10.0.0.0/8, can be repeated
+    #[arg(
+        long,
+        env = "P2PRS_SERVER_ALLOW",
+        value_delimiter = ',',
+        value_name = "CIDR"
+    )]
+    pub allow: Vec<Cidr>,
+    /// Refuse clients from this IP or network, can be repeated
+    #[arg(
+        long,
+        env = "P2PRS_SERVER_DENY",
+        value_delimiter = ',',
+        value_name = "CIDR"
+    )]
+    pub deny: Vec<Cidr>,
+    /// New connections per second allowed from each IP
+    #[arg(long, value_name = "PER_SEC")]
+    pub connection_rate: Option<u32>,
+    /// Messages per second allowed from each client IP
+    #[arg(long, value_name = "PER_SEC")]
+    pub message_rate: Option<u32>,
+    /// Peers allowed to be connected at once
+    #[arg(long, value_name = "COUNT")]
+    pub max_peers: Option<usize>,
+    /// Address to serve Prometheus metrics on, at /metrics
+    #[arg(long, env = "P2PRS_SERVER_METRICS", value_name = "ADDR")]
+    pub metrics: Option<SocketAddrV4>,
+    /// Loopback address to serve the admin API on
+    #[arg(long, env = "P2PRS_SERVER_ADMIN", value_name = "ADDR")]
+    pub admin: Option<SocketAddrV4>,
+    /// off, error, warn, info, debug or trace, optionally followed by levels for some
+    /// targets, like info,server::relay=debug
+    #[arg(long, env = "P2PRS_SERVER_LOG", value_name = "LEVEL")]
+    pub log_level: Option<String>,
+    /// text or json
+    #[arg(long, env = "P2PRS_SERVER_LOG_FORMAT", value_name = "FORMAT")]
+    pub log_format: Option<LogFormat>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub listen: SocketAddrV4,
+    pub advertise: Option<SocketAddrV4>,
+    pub federate: Vec<SocketAddrV4>,
+    pub heartbeat: HeartbeatSection,
+    pub relay: RelaySection,
+    pub access: AccessConfig,
+    pub metrics: MetricsSection,
+    pub admin: AdminSection,
+    pub log: LogConfig,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HeartbeatSection {
+    pub interval_secs: u64,
+    pub timeout_secs: u64,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RelaySection {
+    pub enabled: bool,
+    pub rate: Option<u64>,
+    pub total_rate: Option<u64>,
+    pub accept_timeout_secs: Option<u64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsSection {
+    pub listen: Option<SocketAddrV4>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdminSection {
+    pub listen: Option<SocketAddrV4>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            listen: SocketAddrV4::new(std::net::Ipv4Addr::LOCALHOST, 6969),
+            advertise: None,
+            federate: Vec::new(),
+            heartbeat: HeartbeatSection::default(),
+            relay: RelaySection::default(),
+            access: AccessConfig::default(),
+            metrics: MetricsSection::default(),
+            admin: AdminSection::default(),
+            log: LogConfig::default(),
+        }
+    }
+}
+
+impl Default for HeartbeatSection {
+    fn default() -> Self {
+        let heartbeat = HeartbeatConfig::default();
+        Self {
+            interval_secs: heartbeat.interval.as_secs(),
+            timeout_secs: heartbeat.timeout.as_secs(),
+        }
+    }
+}
+
+impl ServerConfig {
+    /// Loads the config file given in `args`, applies `args` over it and validates the result
+    pub fn load(args: Args) -> Result<Self, ConfigError> {
+        let mut config: Self = common::config::load(args.config.as_deref(), "server.toml")?;
+        config.apply(args);
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn apply(&mut self, args: Args) {
+        if let Some(listen) = args.listen {
+            self.listen = listen;
+        }
+        self.advertise = args.advertise.or(self.advertise);
+        if !args.federate.is_empty() {
+            self.federate = args.federate;
+        }
+        self.relay.enabled |= args.relay;
+        self.relay.rate = args.relay_rate.or(self.relay.rate);
+        self.relay.total_rate = args.relay_total_rate.or(self.relay.total_rate);
+        if !args.allow.is_empty() {
+            self.access.allow = args.allow;
+        }
+        if !args.deny.is_empty() {
+            self.access.deny = args.deny;
+        }
+        self.access.connection_rate = args.connection_rate.or(self.access.connection_rate);
+        self.access.message_rate = args.message_rate.or(self.access.message_rate);
+        self.access.max_peers = args.max_peers.or(self.access.max_peers);
+        self.metrics.listen = args.metrics.or(self.metrics.listen);
+        self.admin.listen = args.admin.or(self.admin.listen);
+        if let Some(level) = args.log_level {
+            self.log.level = level;
+        }
+        self.log.format = args.log_format.unwrap_or(self.log.format);
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |field, reason: &str| {
+            Err(ConfigError::Invalid {
+                field,
+                reason: reason.to_string(),
+            })
+        };
+        if self.federate.contains(&self.advertise()) {
+            return invalid("federate", "can't federate with ourselves");
+        }
+        if !self.federate.is_empty() && self.advertise().ip().is_unspecified() {
+            return invalid("advertise", "needed to federate while listening on 0.0.0.0");
+        }
+        if self.heartbeat.interval_secs == 0 {
+            return invalid("heartbeat.interval_secs", "must be at least 1");
+        }
+        if self.heartbeat.timeout_secs <= self.heartbeat.interval_secs {
+            return invalid(
+                "heartbeat.timeout_secs",
+                "must be longer than heartbeat.interval_secs",
+            );
+        }
+        if self.relay.rate == Some(0) {
+            return invalid("relay.rate", "must be at least 1");
+        }
+        if self.relay.total_rate == Some(0) {
+            return invalid("relay.total_rate", "must be at least 1");
+        }
+        if self.access.connection_rate == Some(0) {
+            return invalid("access.connection_rate", "must be at least 1");
+        }
+        if self.access.message_rate == Some(0) {
+            return invalid("access.message_rate", "must be at least 1");
+        }
+        if self.access.max_peers == Some(0) {
+            return invalid("access.max_peers", "must be at least 1");
+        }
+        if self.metrics.listen == Some(self.listen) {
+            return invalid("metrics.listen", "already used to accept peers");
+        }
+        if let Some(admin) = self.admin.listen {
+            if !admin.ip().is_loopback() {
+                return invalid("admin.listen", "must be a loopback address");
+            }
+            if admin == self.listen || Some(admin) == self.metrics.listen {
+                return invalid(
+                    "admin.listen",
+                    "already used to accept peers or serve metrics",
+                );
+            }
+        }
+        self.log.filter()?;
+        Ok(())
+    }
+
+    /// The address announced to federated trackers
+    pub fn advertise(&self) -> SocketAddrV4 {
+        self.advertise.unwrap_or(self.listen)
+    }
+
+    pub fn heartbeat(&self) -> HeartbeatConfig {
+        HeartbeatConfig {
+            interval: Duration::from_secs(self.heartbeat.interval_secs),
+            timeout: Duration::from_secs(self.heartbeat.timeout_secs),
+        }
+    }
+
+    pub fn relay(&self) -> RelayConfig {
+        let default = RelayConfig::default();
+        RelayConfig {
+            enabled: self.relay.enabled,
+            session_rate: self.relay.rate,
+            total_rate: self.relay.total_rate,
+            accept_timeout: self
+                .relay
+                .accept_timeout_secs
+                .map_or(default.accept_timeout, Duration::from_secs),
+        }
+    }
+}
@@ -0,0 +1,240 @@
+//! Replication of peer registrations between trackers.
+//!
+//! Every tracker keeps one link to each tracker it's federated with and sends it the peers
+//! connected to itself, first as a whole with [`SyncPeers`] and then change by change. Only
+//! local registrations are replicated, so trackers must be federated in a full mesh.
+//!
+//! Trackers ping each other over their links every heartbeat interval, and a link that stays
+//! quiet for the heartbeat timeout is dropped with the peers learned through it, then dialed
+//! again.
+//!
+//! Links are only accepted from the trackers this one federates with, connecting from the IP
+//! of the address they announce in [`TrackerHello`]. Their messages count towards the
+//! message rate of their IP like those of clients.
+
+use crate::access::Refusal;
+use crate::{Context, Peer};
+use common::federation::{
+    ForgetPeer, Message, Registration, ReplicatePeer, SyncPeers, TrackerHello,
+};
+use common::serialize::Serialize;
+use common::{AnyMessage, CommonError, server};
+use std::net::{Shutdown, SocketAddrV4, TcpStream};
+use std::sync::{Arc, Mutex};
+
+/// A connection to a federated tracker
+#[derive(Debug)]
+pub struct Link {
+    conn: Arc<Mutex<TcpStream>>,
+    /// Tracker that opened the connection
+    dialer: SocketAddrV4,
+}
+
+/// Keeps a link open with `tracker`, dialing it whenever there is none
+pub fn federate(ctx: &Arc<Mutex<Context>>, tracker: SocketAddrV4) {
+    let _span = tracing::info_span!("federation", %tracker).entered();
+    loop {
+        let (linked, addr, interval, metrics) = {
+            let ctx = ctx.lock().unwrap();
+            if ctx.closing {
+                return;
+            }
+            (
+                ctx.links.contains_key(&tracker),
+                ctx.addr,
+                ctx.heartbeat.interval,
+                Arc::clone(&ctx.metrics),
+            )
+        };
+        if !linked {
+            let linked = TcpStream::connect(tracker)
+                .map_err(CommonError::from)
+                .and_then(|mut stream| {
+                    let hello = Message::from(TrackerHello { tracker: addr });
+                    metrics.write(&mut stream, &hello)?;
+                    handle_tracker(ctx, stream, tracker, true)
+                });
+            if let Err(e) = linked {
+                tracing::warn!(error = %e, "federation failed");
+            }
+        }
+        std::thread::sleep(interval);
+    }
+}
+
+/// Links with `tracker`, which announced itself on `stream` with [`TrackerHello`], when it's
+/// one we federate with
+pub fn accept(
+    ctx: &Arc<Mutex<Context>>,
+    mut stream: TcpStream,
+    tracker: SocketAddrV4,
+) -> Result<(), CommonError> {
+    let remote = crate::remote_ip(&stream)?;
+    {
+        let ctx = ctx.lock().unwrap();
+        if !ctx.federate.contains(&tracker) || remote != *tracker.ip() {
+            tracing::warn!(%tracker, %remote, "refusing link from a tracker we don't federate with");
+            ctx.refuse(&mut stream, Refusal::NotFederated);
+            return Ok(());
+        }
+    }
+    handle_tracker(ctx, stream, tracker, false)
+}
+
+/// Receives the registrations `tracker` replicates until the link breaks
+fn handle_tracker(
+    ctx: &Arc<Mutex<Context>>,
+    mut stream: TcpStream,
+    tracker: SocketAddrV4,
+    dialed: bool,
+) -> Result<(), CommonError> {
+    let (timeout, metrics) = {
+        let ctx = ctx.lock().unwrap();
+        (ctx.heartbeat.timeout, Arc::clone(&ctx.metrics))
+    };
+    // Pinged every heartbeat interval, so a link quiet for longer is dead
+    stream.set_read_timeout(Some(timeout))?;
+    let conn = stream.try_clone()?;
+    conn.set_write_timeout(Some(timeout))?;
+    let conn = Arc::new(Mutex::new(conn));
+    if !ctx.lock().unwrap().add_link(tracker, &conn, dialed) {
+        let _ = stream.shutdown(Shutdown::Both);
+        return Ok(());
+    }
+    tracing::info!(%tracker, dialed, "linked with tracker");
+
+    loop {
+        let m = match metrics.read(&mut stream) {
+            Ok(m) => m,
+            Err(e) => {
+                tracing::info!(%tracker, error = %e, "link with tracker closed");
+                let _ = stream.shutdown(Shutdown::Both);
+                ctx.lock().unwrap().drop_link(tracker, &conn);
+                return if e.is_disconnect() { Ok(()) } else { Err(e) };
+            }
+        };
+        let mut ctx = ctx.lock().unwrap();
+        if let Err(refusal) = ctx.access.message(*tracker.ip()) {
+            ctx.refuse(&mut conn.lock().unwrap(), refusal);
+            ctx.drop_link(tracker, &conn);
+            return Ok(());
+        }
+        match m {
+            AnyMessage::Federation(Message::SyncPeers(SyncPeers { peers })) => {
+                ctx.sync_remote(tracker, peers);
+            }
+            AnyMessage::Federation(Message::ReplicatePeer(ReplicatePeer { peer })) => {
+                ctx.replicate_remote(tracker, peer);
+            }
+            AnyMessage::Federation(Message::ForgetPeer(ForgetPeer { sock })) => {
+                ctx.forget_remote(tracker, sock);
+            }
+            AnyMessage::Server(server::Message::Ping(_)) => {}
+            m => tracing::debug!(%tracker, msg = ?m, "ignoring unexpected message"),
+        }
+    }
+}
+
+impl Context {
+    /// Starts replicating to `tracker` through `conn`, returning whether the link was kept.
+    ///
+    /// When both trackers dialed each other only the link dialed by the one with the lowest
+    /// address is kept.
+    fn add_link(
+        &mut self,
+        tracker: SocketAddrV4,
+        conn: &Arc<Mutex<TcpStream>>,
+        dialed: bool,
+    ) -> bool {
+        if self.closing {
+            return false;
+        }
+        let dialer = if dialed { self.addr } else { tracker };
+        let preferred = std::cmp::min(self.addr, tracker);
+        if let Some(old) = self.links.get(&tracker) {
+            if old.dialer == preferred && dialer != preferred {
+                return false;
+            }
+            let _ = old.conn.lock().unwrap().shutdown(Shutdown::Both);
+        }
+        let sync = SyncPeers {
+            peers: self.peers.iter().map(Peer::registration).collect(),
+        };
+        if let Err(e) = self
+            .metrics
+            .write(&mut conn.lock().unwrap(), &Message::from(sync))
+        {
+            tracing::warn!(%tracker, error = %e, "failed to sync peers");
+            return false;
+        }
+        let conn = Arc::clone(conn);
+        self.links.insert(tracker, Link { conn, dialer });
+        true
+    }
+
+    /// Forgets every peer registered on `tracker` if `conn` is still its link
+    fn drop_link(&mut self, tracker: SocketAddrV4, conn: &Arc<Mutex<TcpStream>>) {
+        if self
+            .links
+            .get(&tracker)
+            .is_some_and(|l| Arc::ptr_eq(&l.conn, conn))
+        {
+            self.links.remove(&tracker);
+            self.update_view(|ctx| {
+                ctx.remote.remove(&tracker);
+            });
+        }
+    }
+
+    /// Closes every link, federated trackers then forget the peers registered here
+    pub(crate) fn close_links(&mut self) {
+        for link in self.links.values() {
+            let _ = link.conn.lock().unwrap().shutdown(Shutdown::Both);
+        }
+    }
+
+    /// Sends `msg` to every federated tracker, broken links are closed and dropped by their
+    /// reading thread
+    pub(crate) fn replicate(&mut self, msg: &Message) {
+        self.send_links(msg);
+    }
+
+    /// Keeps every link from timing out
+    pub(crate) fn ping_links(&mut self) {
+        self.send_links(&server::Message::from(server::Ping));
+    }
+
+    fn send_links(&mut self, msg: &impl Serialize) {
+        for (tracker, link) in &self.links {
+            let mut conn = link.conn.lock().unwrap();
+            if let Err(e) = self.metrics.write(&mut conn, msg) {
+                tracing::warn!(%tracker, error = %e, "failed to replicate");
+                let _ = conn.shutdown(Shutdown::Both);
+            }
+        }
+    }
+
+    fn sync_remote(&mut self, tracker: SocketAddrV4, peers: Vec<Registration>) {
+        self.update_view(|ctx| {
+            let peers = peers.into_iter().map(|p| (p.sock, p)).collect();
+            ctx.remote.insert(tracker, peers);
+        });
+    }
+
+    fn replicate_remote(&mut self, tracker: SocketAddrV4, peer: Registration) {
+        self.update_view(|ctx| {
+            ctx.remote
+                .entry(tracker)
+                .or_default()
+                .insert(peer.sock, peer);
+        });
+    }
+
+    fn forget_remote(&mut self, tracker: SocketAddrV4, sock: SocketAddrV4) {
+        self.update_view(|ctx| {
+            if let Some(peers) = ctx.remote.get_mut(&tracker) {
+                peers.remove(&sock);
+            }
+        });
+    }
+}
@@ -0,0 +1,144 @@
+//! Just enough HTTP/1.1 for the metrics and admin endpoints: one request per connection,
+//! request bodies are ignored.
+
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::time::Duration;
+
+/// Longest request head read, the rest is ignored
+const MAX_HEAD: usize = 8 * 1024;
+
+#[derive(Debug)]
+pub struct Request {
+    pub remote: SocketAddr,
+    pub method: String,
+    /// Percent-decoded path, without the query
+    pub path: String,
+    pub query: HashMap<String, String>,
+    /// By lowercase name
+    pub headers: HashMap<String, String>,
+}
+
+#[derive(Debug)]
+pub struct Response {
+    pub status: &'static str,
+    pub content_type: &'static str,
+    pub body: String,
+}
+
+impl Response {
+    pub fn text(status: &'static str, body: impl Into<String>) -> Self {
+        Self {
+            status,
+            content_type: "text/plain; charset=utf-8",
+            body: body.into(),
+        }
+    }
+
+    pub fn json(status: &'static str, body: &serde_json::Value) -> Self {
+        Self {
+            status,
+            content_type: "application/json",
+            body: format!("{body}\n"),
+        }
+    }
+
+    pub fn not_found() -> Self {
+        Self::text("404 Not Found", "Not found\n")
+    }
+
+    pub fn method_not_allowed() -> Self {
+        Self::text("405 Method Not Allowed", "Method not allowed\n")
+    }
+}
+
+/// Answers every connection to `listener` with what `handle` returns for its request
+pub fn serve(listener: &TcpListener, mut handle: impl FnMut(&Request) -> Response) {
+    for stream in listener.incoming() {
+        let Ok(mut stream) = stream else {
+            continue;
+        };
+        let answered = read_request(&mut stream).and_then(|request| {
+            let response = handle(&request);
+            write_response(&mut stream, &response)
+        });
+        if let Err(e) = answered {
+            tracing::debug!(error = %e, "failed to answer an HTTP request");
+        }
+    }
+}
+
+fn read_request(stream: &mut TcpStream) -> Result<Request, std::io::Error> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    stream.set_write_timeout(Some(Duration::from_secs(5)))?;
+    let mut head = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") && head.len() < MAX_HEAD {
+        let n = stream.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        head.extend_from_slice(&buf[..n]);
+    }
+    let head = String::from_utf8_lossy(&head);
+    let mut lines = head.lines();
+    let mut line = lines.next().unwrap_or_default().split(' ');
+    let method = line.next().unwrap_or_default().to_string();
+    let target = line.next().unwrap_or_default();
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let query = query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (decode(key, true), decode(value, true))
+        })
+        .collect();
+    let headers = lines
+        .take_while(|line| !line.is_empty())
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_ascii_lowercase(), value.trim().to_string()))
+        .collect();
+    Ok(Request {
+        remote: stream.peer_addr()?,
+        method,
+        path: decode(path, false),
+        query,
+        headers,
+    })
+}
+
+fn write_response(stream: &mut TcpStream, response: &Response) -> Result<(), std::io::Error> {
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        response.status,
+        response.content_type,
+        response.body.len(),
+        response.body
+    )
+}
+
+/// Decodes `%XX` escapes, and `+` as a space in queries. Invalid escapes are kept as is.
+pub fn decode(s: &str, query: bool) -> String {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut rest = s.as_bytes();
+    while let Some((&b, tail)) = rest.split_first() {
+        let hex = tail
+            .get(..2)
+            .and_then(|h| std::str::from_utf8(h).ok())
+            .and_then(|h| u8::from_str_radix(h, 16).ok());
+        match (b, hex) {
+            (b'%', Some(decoded)) => {
+                bytes.push(decoded);
+                rest = &tail[2..];
+                continue;
+            }
+            (b'+', _) if query => bytes.push(b' '),
+            _ => bytes.push(b),
+        }
+        rest = tail;
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
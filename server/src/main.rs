@@ -1,84 +1,579 @@
-use common::{AnyMessage, CommonError, File, client, read_msg, server, write_msg};
-use std::net::{SocketAddrV4, TcpListener, TcpStream};
+use common::{AnyMessage, CommonError, File, client, server};
+use std::collections::{BTreeSet, HashMap};
+use std::net::{Ipv4Addr, Shutdown, SocketAddr, SocketAddrV4, TcpListener, TcpStream};
+use std::sync::mpsc::{Receiver, Sender, channel};
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+mod access;
+use access::AccessConfig;
+mod admin;
+mod config;
+use config::ServerConfig;
+
+mod federation;
+mod http;
+mod metrics;
+use metrics::Metrics;
+mod relay;
+use relay::RelayConfig;
+
+#[cfg(test)]
+mod test;
 
 #[derive(Debug)]
 pub struct Peer {
     pub server_addr: SocketAddrV4,
     pub files: Vec<File>,
     pub conn: Arc<Mutex<TcpStream>>,
+    /// Messages waiting to be written to `conn`, see [`write_outbox`]
+    pub outbox: Sender<Arc<server::Message>>,
+    pub last_seen: Instant,
+    /// See [`common::federation::Registration::registered_at`]
+    pub registered_at: u64,
 }
 
+/// How often peers are pinged and how long they may stay silent before being evicted
+#[derive(Debug, Clone, Copy)]
+pub struct HeartbeatConfig {
+    pub interval: Duration,
+    pub timeout: Duration,
+}
+
+impl Default for HeartbeatConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(5),
+            timeout: Duration::from_secs(15),
+        }
+    }
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+/// The tracker only listens on IPv4
+fn remote_ip(stream: &TcpStream) -> Result<Ipv4Addr, CommonError> {
+    match stream.peer_addr()? {
+        SocketAddr::V4(addr) => Ok(*addr.ip()),
+        SocketAddr::V6(_) => unreachable!("connection to an IPv4 listener"),
+    }
+}
+
+/// Connections get the heartbeat timeout to send their first message
 fn handle(ctx: &Arc<Mutex<Context>>, mut stream: TcpStream) -> Result<(), CommonError> {
-    let m = read_msg(&mut stream)?;
-    println!("{m:?}");
-    if let AnyMessage::Client(client::Message::Connect(client::Connect {
+    let (handshake_timeout, metrics) = {
+        let ctx = ctx.lock().unwrap();
+        (ctx.heartbeat.timeout, Arc::clone(&ctx.metrics))
+    };
+    stream.set_read_timeout(Some(handshake_timeout))?;
+    let m = metrics.read(&mut stream)?;
+    stream.set_read_timeout(None)?;
+    tracing::debug!(msg = ?m, "received first message");
+    {
+        let mut ctx = ctx.lock().unwrap();
+        if let Err(refusal) = ctx.access.message(remote_ip(&stream)?) {
+            ctx.refuse(&mut stream, refusal);
+            return Ok(());
+        }
+    }
+    match m {
+        AnyMessage::Client(client::Message::Connect(connect)) => {
+            handle_client(ctx, stream, connect)
+        }
+        AnyMessage::Federation(common::federation::Message::TrackerHello(hello)) => {
+            federation::accept(ctx, stream, hello.tracker)
+        }
+        AnyMessage::Client(client::Message::RelayConnect(client::RelayConnect { target })) => {
+            relay::handle_requester(ctx, stream, target)
+        }
+        AnyMessage::Client(client::Message::RelayAccept(client::RelayAccept { session })) => {
+            relay::handle_seeder(ctx, stream, session);
+            Ok(())
+        }
+        m => {
+            tracing::debug!(msg = ?m, "ignoring unexpected message");
+            Ok(())
+        }
+    }
+}
+
+fn handle_client(
+    ctx: &Arc<Mutex<Context>>,
+    mut stream: TcpStream,
+    client::Connect {
         file_list,
         serve_port,
-    })) = m
-    {
-        let mut server_addr = match stream.peer_addr().unwrap() {
-            std::net::SocketAddr::V4(v4) => v4,
-            std::net::SocketAddr::V6(_) => panic!(""),
-        };
-        server_addr.set_port(serve_port);
-        let new_peer = Peer {
-            server_addr,
-            files: file_list,
-            conn: Arc::new(Mutex::new(stream)),
+    }: client::Connect,
+) -> Result<(), CommonError> {
+    let server_addr = SocketAddrV4::new(remote_ip(&stream)?, serve_port);
+    let _span = tracing::info_span!("peer", peer = %server_addr).entered();
+    let (write_timeout, metrics) = {
+        let ctx = ctx.lock().unwrap();
+        (ctx.heartbeat.timeout, Arc::clone(&ctx.metrics))
+    };
+    let conn = stream.try_clone()?;
+    conn.set_write_timeout(Some(write_timeout))?;
+    let conn = Arc::new(Mutex::new(conn));
+    let (outbox, queued) = channel();
+    let (writer, writer_metrics) = (Arc::clone(&conn), Arc::clone(&metrics));
+    let span = tracing::Span::current();
+    std::thread::spawn(move || {
+        let _span = span.entered();
+        write_outbox(&writer, &queued, &writer_metrics);
+    });
+    let new_peer = Peer {
+        server_addr,
+        files: file_list,
+        conn: Arc::clone(&conn),
+        outbox,
+        last_seen: Instant::now(),
+        registered_at: now_millis(),
+    };
+    if !ctx.lock().unwrap().register_peer(new_peer) {
+        return Ok(());
+    }
+
+    loop {
+        let m = match metrics.read(&mut stream) {
+            Ok(m) => m,
+            Err(e) => {
+                ctx.lock().unwrap().unregister_peer(&conn);
+                return if e.is_disconnect() { Ok(()) } else { Err(e) };
+            }
         };
-        ctx.lock().unwrap().register_peer(new_peer);
-    } else {
-        println!("{m:?}");
+        let mut ctx = ctx.lock().unwrap();
+        if let Err(refusal) = ctx.access.message(*server_addr.ip()) {
+            ctx.refuse(&mut conn.lock().unwrap(), refusal);
+            ctx.unregister_peer(&conn);
+            return Ok(());
+        }
+        ctx.touch_peer(&conn);
+        match m {
+            AnyMessage::Client(client::Message::Pong(_)) => {}
+            AnyMessage::Client(client::Message::UpdateFiles(client::UpdateFiles { file_list })) => {
+                ctx.update_peer(&conn, file_list);
+            }
+            AnyMessage::Client(client::Message::Disconnect(_)) => {
+                ctx.unregister_peer(&conn);
+                return Ok(());
+            }
+            m => tracing::debug!(msg = ?m, "ignoring unexpected message"),
+        }
+    }
+}
+
+/// Writes the messages queued for a peer until it's forgotten, so a slow peer doesn't hold up
+/// the others. The connection is shut down if writing fails, which unregisters the peer.
+fn write_outbox(
+    conn: &Mutex<TcpStream>,
+    queued: &Receiver<Arc<server::Message>>,
+    metrics: &Metrics,
+) {
+    for msg in queued {
+        let mut conn = conn.lock().unwrap();
+        if let Err(e) = metrics.write(&mut conn, &*msg) {
+            tracing::warn!(error = %e, "dropping peer");
+            let _ = conn.shutdown(Shutdown::Both);
+            return;
+        }
     }
-    Ok(())
 }
 
-#[derive(Default, Debug)]
+/// Pings every peer each `interval`, evicting those that didn't answer within `timeout`
+fn heartbeat(ctx: &Arc<Mutex<Context>>) {
+    loop {
+        let interval = ctx.lock().unwrap().heartbeat.interval;
+        std::thread::sleep(interval);
+        ctx.lock().unwrap().check_heartbeats();
+    }
+}
+
+/// How long relayed connections get to finish when shutting down
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Stops the tracker: connections are refused from then on, peers are told with
+/// [`server::Shutdown`] and forgotten by federated trackers, then relayed connections get
+/// `timeout` to finish
+fn shutdown(ctx: &Arc<Mutex<Context>>, timeout: Duration) {
+    {
+        let mut ctx = ctx.lock().unwrap();
+        ctx.closing = true;
+        for peer in std::mem::take(&mut ctx.peers) {
+            let mut conn = peer.conn.lock().unwrap();
+            let shutdown = server::Message::from(server::Shutdown);
+            if let Err(e) = ctx.metrics.write(&mut conn, &shutdown) {
+                tracing::warn!(peer = %peer.server_addr, error = %e, "failed to tell about the shutdown");
+            }
+            let _ = conn.shutdown(Shutdown::Both);
+            let sock = peer.server_addr;
+            ctx.replicate(&common::federation::ForgetPeer { sock }.into());
+        }
+        ctx.close_links();
+    }
+    let start = Instant::now();
+    loop {
+        let spliced = ctx.lock().unwrap().relay.spliced();
+        if spliced == 0 {
+            return;
+        }
+        if start.elapsed() >= timeout {
+            tracing::warn!(spliced, "aborting relayed connections");
+            return;
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    }
+}
+
+/// The file list of every peer clients should know about
+type View = HashMap<SocketAddrV4, Vec<File>>;
+
+#[derive(Debug)]
 struct Context {
+    /// Address this tracker is reached on, identifies it to federated trackers
+    addr: SocketAddrV4,
+    /// Trackers links are kept with, the only ones accepted as federated trackers
+    federate: Vec<SocketAddrV4>,
     peers: Vec<Peer>,
+    heartbeat: HeartbeatConfig,
+    links: HashMap<SocketAddrV4, federation::Link>,
+    /// Peers registered on federated trackers, by tracker
+    remote: HashMap<SocketAddrV4, HashMap<SocketAddrV4, common::federation::Registration>>,
+    relay: relay::Relay,
+    access: access::Access,
+    metrics: Arc<Metrics>,
+    /// IPs connections are refused from, see [`admin`]
+    banned: BTreeSet<Ipv4Addr>,
+    started: Instant,
+    /// Set once shutting down, connections are refused from then on
+    closing: bool,
 }
 
 impl Context {
-    fn new() -> Self {
-        Self::default()
+    fn new(addr: SocketAddrV4, heartbeat: HeartbeatConfig) -> Self {
+        Self {
+            addr,
+            federate: Vec::new(),
+            peers: Vec::new(),
+            heartbeat,
+            links: HashMap::new(),
+            remote: HashMap::new(),
+            relay: relay::Relay::default(),
+            access: access::Access::default(),
+            metrics: Arc::default(),
+            banned: BTreeSet::new(),
+            started: Instant::now(),
+            closing: false,
+        }
     }
-    fn register_peer(&mut self, new_peer: Peer) {
-        let sock = new_peer.server_addr;
-        let msg = server::Message::RegisterPeer(server::RegisterPeer {
-            sock,
-            file_list: new_peer.files.clone(),
+
+    fn with_relay(mut self, config: RelayConfig) -> Self {
+        self.relay = relay::Relay::new(config);
+        self
+    }
+
+    fn with_access(mut self, config: AccessConfig) -> Self {
+        self.access = access::Access::new(config);
+        self
+    }
+
+    fn find_peer(&mut self, conn: &Arc<Mutex<TcpStream>>) -> Option<&mut Peer> {
+        self.peers.iter_mut().find(|p| Arc::ptr_eq(&p.conn, conn))
+    }
+
+    /// Every known peer, local or remote. When several trackers know the same peer the most
+    /// recent registration wins, ties going to the tracker with the highest address.
+    fn view(&self) -> View {
+        let local = self
+            .peers
+            .iter()
+            .map(|p| (p.server_addr, p.registered_at, self.addr, &p.files));
+        let remote = self.remote.iter().flat_map(|(tracker, peers)| {
+            peers
+                .values()
+                .map(|r| (r.sock, r.registered_at, *tracker, &r.file_list))
         });
-        self.peers.retain_mut(
-            |peer| match write_msg(&mut peer.conn.lock().unwrap(), &msg) {
-                Ok(..) => true,
-                Err(common::CommonError::IO(e)) if e.kind() == std::io::ErrorKind::BrokenPipe => {
-                    false
+        let mut winners: HashMap<SocketAddrV4, (u64, SocketAddrV4, &Vec<File>)> = HashMap::new();
+        for (sock, registered_at, tracker, files) in local.chain(remote) {
+            match winners.get(&sock) {
+                Some(&(at, by, _)) if (at, by) >= (registered_at, tracker) => {}
+                _ => {
+                    winners.insert(sock, (registered_at, tracker, files));
+                }
+            }
+        }
+        winners
+            .into_iter()
+            .map(|(sock, (_, _, files))| (sock, files.clone()))
+            .collect()
+    }
+
+    /// Applies `change` and tells every client how the peer view changed because of it
+    fn update_view(&mut self, change: impl FnOnce(&mut Self)) {
+        let before = self.view();
+        change(self);
+        let after = self.view();
+        let files = self.peers.iter().map(|p| p.files.len()).sum();
+        self.metrics.set_peers(self.peers.len(), after.len(), files);
+        for (sock, file_list) in &after {
+            let msg = match before.get(sock) {
+                None => server::RegisterPeer {
+                    sock: *sock,
+                    file_list: file_list.clone(),
+                }
+                .into(),
+                Some(old) if old != file_list => server::UpdatePeer {
+                    sock: *sock,
+                    file_list: file_list.clone(),
                 }
-                Err(e) => panic!("{e}"),
-            },
-        );
-        self.peers.iter().for_each(|p| {
-            let msg = server::Message::RegisterPeer(server::RegisterPeer {
-                sock: p.server_addr,
-                file_list: new_peer.files.clone(),
-            });
-            write_msg(&mut new_peer.conn.lock().unwrap(), &msg).unwrap();
+                .into(),
+                Some(_) => continue,
+            };
+            self.broadcast_from(Some(*sock), msg);
+        }
+        for sock in before.into_keys().filter(|s| !after.contains_key(s)) {
+            self.broadcast_from(Some(sock), server::UnregisterPeer { sock }.into());
+        }
+    }
+
+    /// Queues `msg` for every peer but `origin`
+    fn broadcast_from(&self, origin: Option<SocketAddrV4>, msg: server::Message) {
+        let start = Instant::now();
+        let msg = Arc::new(msg);
+        for peer in self.peers.iter().filter(|p| Some(p.server_addr) != origin) {
+            // The writer only stops once the connection failed, the reader then unregisters it
+            let _ = peer.outbox.send(Arc::clone(&msg));
+        }
+        self.metrics.broadcast_took(start.elapsed());
+    }
+
+    fn broadcast(&self, msg: server::Message) {
+        self.broadcast_from(None, msg);
+    }
+
+    /// Every peer known to this tracker except `sock`
+    fn snapshot(&self, sock: SocketAddrV4) -> server::PeerSnapshot {
+        let peers = self
+            .view()
+            .into_iter()
+            .filter(|(s, _)| *s != sock)
+            .map(|(sock, file_list)| server::PeerInfo { sock, file_list })
+            .collect();
+        server::PeerSnapshot { peers }
+    }
+
+    /// Tells `new_peer` about the others and them about it, returning whether it was registered
+    fn register_peer(&mut self, new_peer: Peer) -> bool {
+        if self.closing {
+            let _ = new_peer.conn.lock().unwrap().shutdown(Shutdown::Both);
+            return false;
+        }
+        let sock = new_peer.server_addr;
+        let reconnecting = self.peers.iter().any(|p| p.server_addr == sock);
+        if !reconnecting
+            && self
+                .access
+                .max_peers()
+                .is_some_and(|max| self.peers.len() >= max)
+        {
+            self.refuse(&mut new_peer.conn.lock().unwrap(), access::Refusal::Full);
+            return false;
+        }
+        // Queued before the peer is known, so it comes before any update
+        let snapshot = server::Message::from(self.snapshot(sock));
+        let _ = new_peer.outbox.send(Arc::new(snapshot));
+        tracing::info!(peer = %sock, files = new_peer.files.len(), "peer registered");
+        let peer = new_peer.registration();
+        self.update_view(|ctx| {
+            // A peer reconnecting before its old connection timed out
+            if let Some(pos) = ctx.peers.iter().position(|p| p.server_addr == sock) {
+                let old = ctx.peers.remove(pos);
+                let _ = old.conn.lock().unwrap().shutdown(Shutdown::Both);
+            }
+            ctx.peers.push(new_peer);
+        });
+        self.replicate(&common::federation::ReplicatePeer { peer }.into());
+        true
+    }
+
+    fn update_peer(&mut self, conn: &Arc<Mutex<TcpStream>>, file_list: Vec<File>) {
+        let mut registration = None;
+        self.update_view(|ctx| {
+            if let Some(peer) = ctx.find_peer(conn) {
+                tracing::info!(peer = %peer.server_addr, files = file_list.len(), "peer updated its files");
+                peer.files = file_list;
+                peer.registered_at = now_millis();
+                registration = Some(peer.registration());
+            }
         });
-        self.peers.push(new_peer);
+        if let Some(peer) = registration {
+            self.replicate(&common::federation::ReplicatePeer { peer }.into());
+        }
+    }
+
+    fn unregister_peer(&mut self, conn: &Arc<Mutex<TcpStream>>) {
+        let Some(pos) = self.peers.iter().position(|p| Arc::ptr_eq(&p.conn, conn)) else {
+            return;
+        };
+        let mut sock = None;
+        self.update_view(|ctx| sock = Some(ctx.peers.remove(pos).server_addr));
+        if let Some(sock) = sock {
+            tracing::info!(peer = %sock, "peer unregistered");
+            self.replicate(&common::federation::ForgetPeer { sock }.into());
+        }
+    }
+
+    fn touch_peer(&mut self, conn: &Arc<Mutex<TcpStream>>) {
+        if let Some(peer) = self.find_peer(conn) {
+            peer.last_seen = Instant::now();
+        }
+    }
+
+    fn check_heartbeats(&mut self) {
+        let timeout = self.heartbeat.timeout;
+        let dead: Vec<_> = self
+            .peers
+            .iter()
+            .filter(|p| p.last_seen.elapsed() > timeout)
+            .map(|p| (p.server_addr, Arc::clone(&p.conn)))
+            .collect();
+        for (sock, conn) in dead {
+            tracing::info!(peer = %sock, "peer missed its heartbeats");
+            let _ = conn.lock().unwrap().shutdown(Shutdown::Both);
+            self.unregister_peer(&conn);
+        }
+        self.broadcast(server::Ping.into());
+        self.ping_links();
+    }
+}
+
+impl Peer {
+    fn registration(&self) -> common::federation::Registration {
+        common::federation::Registration {
+            sock: self.server_addr,
+            file_list: self.files.clone(),
+            registered_at: self.registered_at,
+        }
     }
 }
 
-fn main() -> Result<(), CommonError> {
-    let ctx = Arc::new(Mutex::new(Context::new()));
-    let listener = TcpListener::bind("127.0.0.1:6969")?;
+/// Accepts clients and federated trackers forever, keeping a link open to every tracker in
+/// `federate_with`
+fn serve(
+    listener: &TcpListener,
+    ctx: &Arc<Mutex<Context>>,
+    federate_with: &[SocketAddrV4],
+) -> Result<(), CommonError> {
+    ctx.lock().unwrap().federate = federate_with.to_vec();
+    let heartbeat_ctx = Arc::clone(ctx);
+    std::thread::spawn(move || heartbeat(&heartbeat_ctx));
+    for &tracker in federate_with {
+        let ctx = Arc::clone(ctx);
+        std::thread::spawn(move || federation::federate(&ctx, tracker));
+    }
     for stream in listener.incoming() {
-        std::thread::scope(|s| {
-            println!("{stream:?}");
-            let stream = stream?;
-            s.spawn(|| handle(&ctx, stream));
-            Ok::<(), std::io::Error>(())
-        })?;
+        let mut stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                tracing::warn!(error = %e, "failed to accept a connection");
+                // Out of file descriptors, give connections some time to close
+                std::thread::sleep(Duration::from_millis(100));
+                continue;
+            }
+        };
+        // Already closed
+        let Ok(remote) = stream.peer_addr() else {
+            continue;
+        };
+        {
+            let mut ctx = ctx.lock().unwrap();
+            if ctx.closing {
+                ctx.metrics.refused("closing");
+                tracing::debug!(%remote, "refusing connection while shutting down");
+                continue;
+            }
+            if let SocketAddr::V4(remote) = remote
+                && let Err(refusal) = ctx.admit(*remote.ip())
+            {
+                ctx.refuse(&mut stream, refusal);
+                continue;
+            }
+            ctx.metrics.connection_accepted();
+        }
+        let span = tracing::info_span!("connection", %remote);
+        let ctx = Arc::clone(ctx);
+        std::thread::spawn(move || {
+            let _span = span.entered();
+            tracing::debug!("accepted connection");
+            if let Err(e) = handle(&ctx, stream) {
+                tracing::warn!(error = %e, "connection failed");
+            }
+        });
     }
     unreachable!()
 }
+
+#[derive(Debug, thiserror::Error)]
+enum ServerError {
+    #[error(transparent)]
+    IO(#[from] std::io::Error),
+    #[error(transparent)]
+    Lib(#[from] CommonError),
+    #[error(transparent)]
+    Config(#[from] common::config::ConfigError),
+    #[error(transparent)]
+    Signal(#[from] ctrlc::Error),
+}
+
+/// See [`config`] for the settings and where they're read from.
+///
+/// With `--relay` peers that can't accept connections are reached through the tracker, each
+/// relayed connection limited to `--relay-rate` and all of them together to
+/// `--relay-total-rate`.
+///
+/// With `--admin ADDR` the [`admin`] API is served on `http://ADDR/`, ADDR must be a loopback
+/// address.
+///
+/// With `--metrics ADDR` Prometheus metrics are served on `http://ADDR/metrics`, see
+/// [`metrics`].
+///
+/// On SIGINT or SIGTERM the tracker stops accepting connections, tells its peers it's going
+/// away and waits for relayed connections to finish before exiting, see [`shutdown`].
+fn main() -> Result<(), ServerError> {
+    use clap::Parser;
+    let config = ServerConfig::load(config::Args::parse())?;
+    common::log::init(&config.log)?;
+    let listener = TcpListener::bind(config.listen)?;
+    let ctx = Context::new(config.advertise(), config.heartbeat())
+        .with_relay(config.relay())
+        .with_access(config.access.clone());
+    if let Some(addr) = config.metrics.listen {
+        let metrics_listener = TcpListener::bind(addr)?;
+        let metrics = Arc::clone(&ctx.metrics);
+        std::thread::spawn(move || metrics::serve(&metrics_listener, &metrics));
+    }
+    let ctx = Arc::new(Mutex::new(ctx));
+    if let Some(addr) = config.admin.listen {
+        let admin_listener = TcpListener::bind(addr)?;
+        let ctx = Arc::clone(&ctx);
+        std::thread::spawn(move || admin::serve(&admin_listener, &ctx));
+    }
+    let (stop_tx, stop) = std::sync::mpsc::channel();
+    let signalled = stop_tx.clone();
+    ctrlc::set_handler(move || {
+        let _ = signalled.send(Ok(()));
+    })?;
+    {
+        let ctx = Arc::clone(&ctx);
+        std::thread::spawn(move || {
+            let _ = stop_tx.send(serve(&listener, &ctx, &config.federate));
+        });
+    }
+    let stopped = stop.recv().expect("the signal handler is never dropped");
+    tracing::info!("shutting down");
+    shutdown(&ctx, DRAIN_TIMEOUT);
+    Ok(stopped?)
+}
@@ -0,0 +1,247 @@
+//! Prometheus metrics about the tracker, served over HTTP on `GET /metrics` when a metrics
+//! address is configured.
+//!
+//! Messages and bytes are counted where the tracker reads and writes them, relayed bytes
+//! included, and peers when the view clients are told about changes.
+
+use crate::http::{self, Response};
+use common::serialize::Serialize;
+use common::{AnyMessage, CommonError, DeserializeError, MsgType, read_msg, write_msg};
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::Read;
+use std::net::{TcpListener, TcpStream};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Upper bounds of the broadcast latency buckets, in seconds
+const LATENCY_BUCKETS: [f64; 9] = [0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0];
+
+/// Size of the message type and size prefixing every message
+const HEADER_SIZE: u64 = 9;
+
+#[derive(Debug, Default)]
+pub struct Metrics {
+    /// Peers connected to this tracker
+    peers: AtomicU64,
+    /// Peers clients are told about, including those of federated trackers
+    known_peers: AtomicU64,
+    /// Files announced by the peers connected to this tracker
+    files: AtomicU64,
+    connections: AtomicU64,
+    refused: Mutex<BTreeMap<&'static str, u64>>,
+    received: Mutex<BTreeMap<MsgType, u64>>,
+    sent: Mutex<BTreeMap<MsgType, u64>>,
+    deserialize_errors: Mutex<BTreeMap<&'static str, u64>>,
+    bytes_received: AtomicU64,
+    bytes_sent: AtomicU64,
+    broadcasts: Mutex<Histogram>,
+}
+
+#[derive(Debug, Default)]
+struct Histogram {
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Metrics {
+    /// Reads a message from `stream`, counting it or why it couldn't be read
+    pub fn read(&self, stream: &mut impl Read) -> Result<AnyMessage, CommonError> {
+        match read_msg(stream) {
+            Ok(m) => {
+                *self
+                    .received
+                    .lock()
+                    .unwrap()
+                    .entry(m.msg_type())
+                    .or_default() += 1;
+                let size = HEADER_SIZE + m.size() as u64;
+                self.bytes_received.fetch_add(size, Ordering::Relaxed);
+                Ok(m)
+            }
+            Err(e) => {
+                let name = error_name(&e);
+                let e = CommonError::from(e);
+                if !e.is_disconnect() {
+                    *self
+                        .deserialize_errors
+                        .lock()
+                        .unwrap()
+                        .entry(name)
+                        .or_default() += 1;
+                }
+                Err(e)
+            }
+        }
+    }
+
+    /// Writes `msg` to `stream`, counting it once it was written
+    pub fn write(&self, stream: &mut TcpStream, msg: &impl Serialize) -> Result<(), CommonError> {
+        write_msg(stream, msg)?;
+        *self.sent.lock().unwrap().entry(msg.msg_type()).or_default() += 1;
+        let size = HEADER_SIZE + msg.size() as u64;
+        self.bytes_sent.fetch_add(size, Ordering::Relaxed);
+        Ok(())
+    }
+
+    pub fn connection_accepted(&self) {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a client refused for `reason`
+    pub fn refused(&self, reason: &'static str) {
+        *self.refused.lock().unwrap().entry(reason).or_default() += 1;
+    }
+
+    /// Counts `bytes` relayed from one peer to another, which the tracker received and sent
+    pub fn relayed(&self, bytes: u64) {
+        self.bytes_received.fetch_add(bytes, Ordering::Relaxed);
+        self.bytes_sent.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn set_peers(&self, peers: usize, known_peers: usize, files: usize) {
+        self.peers.store(peers as u64, Ordering::Relaxed);
+        self.known_peers
+            .store(known_peers as u64, Ordering::Relaxed);
+        self.files.store(files as u64, Ordering::Relaxed);
+    }
+
+    /// Records how long queuing a message for every peer took
+    pub fn broadcast_took(&self, took: Duration) {
+        let secs = took.as_secs_f64();
+        let mut histogram = self.broadcasts.lock().unwrap();
+        for (bucket, bound) in histogram.buckets.iter_mut().zip(LATENCY_BUCKETS) {
+            if secs <= bound {
+                *bucket += 1;
+            }
+        }
+        histogram.count += 1;
+        histogram.sum += secs;
+    }
+
+    /// The metrics in the Prometheus text format
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let gauge = |out: &mut String, name, help, value: &AtomicU64| {
+            let value = value.load(Ordering::Relaxed);
+            let _ = writeln!(
+                out,
+                "# HELP {name} {help}\n# TYPE {name} gauge\n{name} {value}"
+            );
+        };
+        gauge(
+            &mut out,
+            "p2prs_tracker_peers",
+            "Peers connected to this tracker.",
+            &self.peers,
+        );
+        gauge(
+            &mut out,
+            "p2prs_tracker_known_peers",
+            "Peers known to this tracker, including those of federated trackers.",
+            &self.known_peers,
+        );
+        gauge(
+            &mut out,
+            "p2prs_tracker_files",
+            "Files announced by the peers connected to this tracker.",
+            &self.files,
+        );
+        let counter = |out: &mut String, name, help, value: &AtomicU64| {
+            let value = value.load(Ordering::Relaxed);
+            let _ = writeln!(
+                out,
+                "# HELP {name} {help}\n# TYPE {name} counter\n{name} {value}"
+            );
+        };
+        counter(
+            &mut out,
+            "p2prs_tracker_connections_total",
+            "Connections accepted.",
+            &self.connections,
+        );
+        counter(
+            &mut out,
+            "p2prs_tracker_received_bytes_total",
+            "Bytes received, relayed bytes included.",
+            &self.bytes_received,
+        );
+        counter(
+            &mut out,
+            "p2prs_tracker_sent_bytes_total",
+            "Bytes sent, relayed bytes included.",
+            &self.bytes_sent,
+        );
+
+        let name = "p2prs_tracker_refused_total";
+        let _ = writeln!(out, "# HELP {name} Clients refused, by reason.");
+        let _ = writeln!(out, "# TYPE {name} counter");
+        for (reason, count) in self.refused.lock().unwrap().iter() {
+            let _ = writeln!(out, "{name}{{reason=\"{reason}\"}} {count}");
+        }
+
+        let name = "p2prs_tracker_messages_total";
+        let _ = writeln!(out, "# HELP {name} Messages received and sent, by type.");
+        let _ = writeln!(out, "# TYPE {name} counter");
+        for (direction, counts) in [("received", &self.received), ("sent", &self.sent)] {
+            for (msg_type, count) in counts.lock().unwrap().iter() {
+                let _ = writeln!(
+                    out,
+                    "{name}{{direction=\"{direction}\",type=\"{msg_type:?}\"}} {count}"
+                );
+            }
+        }
+
+        let name = "p2prs_tracker_deserialize_errors_total";
+        let _ = writeln!(
+            out,
+            "# HELP {name} Messages that couldn't be read, by error."
+        );
+        let _ = writeln!(out, "# TYPE {name} counter");
+        for (error, count) in self.deserialize_errors.lock().unwrap().iter() {
+            let _ = writeln!(out, "{name}{{error=\"{error}\"}} {count}");
+        }
+
+        let name = "p2prs_tracker_broadcast_seconds";
+        let histogram = self.broadcasts.lock().unwrap();
+        let _ = writeln!(
+            out,
+            "# HELP {name} Time taken to queue a message for every peer."
+        );
+        let _ = writeln!(out, "# TYPE {name} histogram");
+        for (bound, count) in LATENCY_BUCKETS.iter().zip(histogram.buckets) {
+            let _ = writeln!(out, "{name}_bucket{{le=\"{bound}\"}} {count}");
+        }
+        let _ = writeln!(out, "{name}_bucket{{le=\"+Inf\"}} {}", histogram.count);
+        let _ = writeln!(out, "{name}_sum {}", histogram.sum);
+        let _ = writeln!(out, "{name}_count {}", histogram.count);
+        out
+    }
+}
+
+fn error_name(e: &DeserializeError) -> &'static str {
+    match e {
+        DeserializeError::EOF(..) => "EOF",
+        DeserializeError::IO(_) => "IO",
+        DeserializeError::OsStringUTF8Error(_) => "OsStringUTF8Error",
+        DeserializeError::WrongMsgType(_) => "WrongMsgType",
+        DeserializeError::TooLong(..) => "TooLong",
+        DeserializeError::Alloc(_) => "Alloc",
+    }
+}
+
+/// Answers `GET /metrics` with `metrics` forever
+pub fn serve(listener: &TcpListener, metrics: &Metrics) {
+    http::serve(listener, |request| {
+        match (request.method.as_str(), request.path.as_str()) {
+            ("GET", "/metrics") => Response {
+                content_type: "text/plain; version=0.0.4",
+                ..Response::text("200 OK", metrics.render())
+            },
+            ("GET", _) => Response::not_found(),
+            _ => Response::method_not_allowed(),
+        }
+    });
+}
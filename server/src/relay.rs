@@ -0,0 +1,201 @@
+//! Relaying file transfers for peers that can't accept inbound connections.
+//!
+//! A requester opens a connection to the tracker starting with [`RelayConnect`], the tracker
+//! asks the target peer over its tracker connection to connect back with [`RelayIncoming`],
+//! and splices both connections together once the peer does with [`RelayAccept`]. From then
+//! on the requester talks to the peer's file server as if connected directly.
+//!
+//! [`RelayConnect`]: common::client::RelayConnect
+//! [`RelayAccept`]: common::client::RelayAccept
+
+use crate::Context;
+use common::rate::RateLimit;
+use common::{CommonError, server};
+use std::collections::HashMap;
+use std::hash::{BuildHasher, RandomState};
+use std::io::{Read, Write};
+use std::net::{Shutdown, SocketAddrV4, TcpStream};
+use std::sync::mpsc::{Sender, channel};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy)]
+pub struct RelayConfig {
+    pub enabled: bool,
+    /// Bytes per second allowed through each relayed connection
+    pub session_rate: Option<u64>,
+    /// Bytes per second allowed through all relayed connections together
+    pub total_rate: Option<u64>,
+    /// How long the target peer has to connect back
+    pub accept_timeout: Duration,
+}
+
+impl Default for RelayConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            session_rate: None,
+            total_rate: None,
+            accept_timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Relay {
+    config: RelayConfig,
+    total: Option<Arc<Mutex<RateLimit>>>,
+    /// Requesters waiting for the target peer to connect back, by session
+    pending: HashMap<u64, Sender<TcpStream>>,
+    /// Makes session ids unguessable, so only the target peer can claim a session
+    keys: RandomState,
+    sessions: u64,
+    /// Connections being spliced right now
+    spliced: usize,
+}
+
+impl Relay {
+    pub fn new(config: RelayConfig) -> Self {
+        Self {
+            config,
+            total: config
+                .total_rate
+                .map(|rate| Arc::new(Mutex::new(RateLimit::new(rate)))),
+            ..Self::default()
+        }
+    }
+
+    pub fn spliced(&self) -> usize {
+        self.spliced
+    }
+
+    /// Requesters waiting for their target to connect back
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+}
+
+/// Asks `target` to connect back and splices `stream` to it, closing `stream` when the relay
+/// is disabled or `target` isn't connected to this tracker
+pub fn handle_requester(
+    ctx: &Arc<Mutex<Context>>,
+    stream: TcpStream,
+    target: SocketAddrV4,
+) -> Result<(), CommonError> {
+    let (tx, rx) = channel();
+    let (session, config, total) = {
+        let mut ctx = ctx.lock().unwrap();
+        if !ctx.relay.config.enabled {
+            tracing::warn!(%target, "refusing to relay, relaying is disabled");
+            return Ok(());
+        }
+        let Some(outbox) = ctx
+            .peers
+            .iter()
+            .find(|p| p.server_addr == target)
+            .map(|p| p.outbox.clone())
+        else {
+            tracing::warn!(%target, "can't relay, the target isn't connected");
+            return Ok(());
+        };
+        let ctx = &mut *ctx;
+        let relay = &mut ctx.relay;
+        let session = relay.keys.hash_one(relay.sessions);
+        relay.sessions += 1;
+        relay.pending.insert(session, tx);
+        let incoming = server::Message::from(server::RelayIncoming { session });
+        if outbox.send(Arc::new(incoming)).is_err() {
+            relay.pending.remove(&session);
+            tracing::warn!(%target, "can't relay, the target's connection failed");
+            return Ok(());
+        }
+        (session, relay.config, relay.total.clone())
+    };
+    let _span = tracing::info_span!("relay", %target, session).entered();
+    tracing::debug!("waiting for the target to connect back");
+
+    let seeder = rx.recv_timeout(config.accept_timeout);
+    ctx.lock().unwrap().relay.pending.remove(&session);
+    let Ok(seeder) = seeder else {
+        tracing::warn!("the target didn't connect back");
+        return Ok(());
+    };
+    let limits: Vec<_> = config
+        .session_rate
+        .map(|rate| Arc::new(Mutex::new(RateLimit::new(rate))))
+        .into_iter()
+        .chain(total)
+        .collect();
+    ctx.lock().unwrap().relay.spliced += 1;
+    let spliced = splice(stream, seeder, &limits);
+    {
+        let mut ctx = ctx.lock().unwrap();
+        ctx.relay.spliced -= 1;
+        if let Ok((up, down)) = spliced {
+            ctx.metrics.relayed(up + down);
+        }
+    }
+    let (up, down) = spliced?;
+    tracing::info!(bytes_up = up, bytes_down = down, "relayed session finished");
+    Ok(())
+}
+
+/// Hands the connection a peer opened for `session` to the requester waiting for it
+pub fn handle_seeder(ctx: &Arc<Mutex<Context>>, stream: TcpStream, session: u64) {
+    match ctx.lock().unwrap().relay.pending.remove(&session) {
+        Some(requester) => {
+            let _ = requester.send(stream);
+        }
+        None => tracing::warn!(session, "no requester is waiting for the relayed session"),
+    }
+}
+
+/// Copies data both ways between `a` and `b` until both are done sending, returning how many
+/// bytes `a` and `b` sent
+fn splice(
+    a: TcpStream,
+    b: TcpStream,
+    limits: &[Arc<Mutex<RateLimit>>],
+) -> Result<(u64, u64), CommonError> {
+    let (a2, b2) = (a.try_clone()?, b.try_clone()?);
+    let up_limits = limits.to_vec();
+    let up = std::thread::spawn(move || pipe(a2, b2, &up_limits));
+    let down = pipe(b, a, limits);
+    let up = up.join().expect("relay thread panicked");
+    Ok((up?, down?))
+}
+
+/// Copies `from` into `to` until `from` is done sending, returning how many bytes were
+/// copied. A failure closes both.
+fn pipe(
+    mut from: TcpStream,
+    mut to: TcpStream,
+    limits: &[Arc<Mutex<RateLimit>>],
+) -> Result<u64, std::io::Error> {
+    let mut buf = [0u8; 16 * 1024];
+    let mut total = 0;
+    let copied = (|| {
+        loop {
+            let n = from.read(&mut buf)?;
+            if n == 0 {
+                return Ok(total);
+            }
+            for limit in limits {
+                let wait = limit.lock().unwrap().take(n as u64);
+                std::thread::sleep(wait);
+            }
+            to.write_all(&buf[..n])?;
+            total += n as u64;
+        }
+    })();
+    match copied {
+        Ok(_) => {
+            let _ = to.shutdown(Shutdown::Write);
+        }
+        Err(_) => {
+            let _ = from.shutdown(Shutdown::Both);
+            let _ = to.shutdown(Shutdown::Both);
+        }
+    }
+    copied
+}
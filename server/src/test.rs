@@ -0,0 +1,908 @@
+use crate::*;
+use common::{read_msg, write_msg};
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+
+type View = HashMap<SocketAddrV4, Vec<File>>;
+
+fn start_tracker() -> SocketAddr {
+    start_federated_trackers(1)[0]
+}
+
+fn start_relay(relay: RelayConfig) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let SocketAddr::V4(v4) = addr else {
+        unreachable!()
+    };
+    let ctx = Context::new(v4, HeartbeatConfig::default()).with_relay(relay);
+    let ctx = Arc::new(Mutex::new(ctx));
+    std::thread::spawn(move || serve(&listener, &ctx, &[]));
+    addr
+}
+
+fn start_guarded_tracker(access: AccessConfig) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let SocketAddr::V4(v4) = addr else {
+        unreachable!()
+    };
+    let ctx = Context::new(v4, HeartbeatConfig::default()).with_access(access);
+    let ctx = Arc::new(Mutex::new(ctx));
+    std::thread::spawn(move || serve(&listener, &ctx, &[]));
+    addr
+}
+
+/// Starts `n` trackers federated in a full mesh
+fn start_federated_trackers(n: usize) -> Vec<SocketAddr> {
+    start_federated_contexts(n)
+        .into_iter()
+        .map(|(addr, _)| addr)
+        .collect()
+}
+
+fn start_federated_contexts(n: usize) -> Vec<(SocketAddr, Arc<Mutex<Context>>)> {
+    let listeners: Vec<_> = (0..n)
+        .map(|_| TcpListener::bind("127.0.0.1:0").unwrap())
+        .collect();
+    let addrs: Vec<SocketAddrV4> = listeners
+        .iter()
+        .map(|l| match l.local_addr().unwrap() {
+            SocketAddr::V4(addr) => addr,
+            SocketAddr::V6(_) => unreachable!(),
+        })
+        .collect();
+    let mut trackers = Vec::new();
+    for (i, listener) in listeners.into_iter().enumerate() {
+        let ctx = Arc::new(Mutex::new(Context::new(
+            addrs[i],
+            HeartbeatConfig::default(),
+        )));
+        let federate_with: Vec<_> = addrs.iter().copied().filter(|a| *a != addrs[i]).collect();
+        trackers.push((SocketAddr::V4(addrs[i]), Arc::clone(&ctx)));
+        std::thread::spawn(move || serve(&listener, &ctx, &federate_with));
+    }
+    trackers
+}
+
+fn files(names: &[&str]) -> Vec<File> {
+    names
+        .iter()
+        .map(|name| File {
+            path: PathBuf::from(name),
+            size: name.len() as u64,
+        })
+        .collect()
+}
+
+/// A client speaking the tracker protocol directly, keeping the peer view it was told about
+struct TestPeer {
+    stream: TcpStream,
+    sock: SocketAddrV4,
+    files: Vec<File>,
+    view: View,
+}
+
+impl TestPeer {
+    fn connect(tracker: SocketAddr, serve_port: u16, file_list: Vec<File>) -> Self {
+        let mut stream = TcpStream::connect(tracker).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(2)))
+            .unwrap();
+        let msg = client::Message::from(client::Connect {
+            file_list: file_list.clone(),
+            serve_port,
+        });
+        write_msg(&mut stream, &msg).unwrap();
+        Self {
+            stream,
+            sock: SocketAddrV4::new(Ipv4Addr::LOCALHOST, serve_port),
+            files: file_list,
+            view: View::new(),
+        }
+    }
+
+    fn update(&mut self, file_list: Vec<File>) {
+        self.files.clone_from(&file_list);
+        let msg = client::Message::from(client::UpdateFiles { file_list });
+        write_msg(&mut self.stream, &msg).unwrap();
+    }
+
+    fn disconnect(mut self) {
+        write_msg(&mut self.stream, &client::Message::from(client::Disconnect)).unwrap();
+    }
+
+    fn apply(&mut self, msg: AnyMessage) {
+        match msg {
+            AnyMessage::Server(server::Message::PeerSnapshot(s)) => {
+                self.view = s.peers.into_iter().map(|p| (p.sock, p.file_list)).collect();
+            }
+            AnyMessage::Server(server::Message::RegisterPeer(p)) => {
+                assert!(
+                    self.view.insert(p.sock, p.file_list).is_none(),
+                    "{} registered twice",
+                    p.sock
+                );
+            }
+            AnyMessage::Server(server::Message::UpdatePeer(p)) => {
+                assert!(
+                    self.view.insert(p.sock, p.file_list).is_some(),
+                    "{} updated before being registered",
+                    p.sock
+                );
+            }
+            AnyMessage::Server(server::Message::UnregisterPeer(p)) => {
+                assert!(
+                    self.view.remove(&p.sock).is_some(),
+                    "{} unregistered before being registered",
+                    p.sock
+                );
+            }
+            AnyMessage::Server(server::Message::Ping(_)) => {}
+            m => panic!("unexpected message {m:?}"),
+        }
+        assert!(
+            !self.view.contains_key(&self.sock),
+            "{} was told about itself",
+            self.sock
+        );
+    }
+
+    /// Reads messages until the peer's view is `expected`
+    fn sync(&mut self, expected: &[&TestPeer]) {
+        let expected: View = expected.iter().map(|p| (p.sock, p.files.clone())).collect();
+        while self.view != expected {
+            let msg = read_msg(&mut self.stream).unwrap_or_else(|e| {
+                panic!(
+                    "{} stuck with view {:?}, expected {expected:?}: {e}",
+                    self.sock, self.view
+                )
+            });
+            self.apply(msg);
+        }
+    }
+
+    /// Asserts the tracker has nothing more to tell this peer
+    fn assert_idle(&mut self) {
+        self.stream
+            .set_read_timeout(Some(Duration::from_millis(200)))
+            .unwrap();
+        loop {
+            match read_msg(&mut self.stream) {
+                Ok(AnyMessage::Server(server::Message::Ping(_))) => {}
+                Ok(m) => panic!("{} got unexpected {m:?}", self.sock),
+                Err(common::DeserializeError::IO(e))
+                    if e.kind() == std::io::ErrorKind::WouldBlock =>
+                {
+                    break;
+                }
+                Err(e) => panic!("{e}"),
+            }
+        }
+        self.stream
+            .set_read_timeout(Some(Duration::from_secs(2)))
+            .unwrap();
+    }
+}
+
+#[test]
+fn joining_peer_receives_snapshot() {
+    let tracker = start_tracker();
+    let mut a = TestPeer::connect(tracker, 41001, files(&["a.txt"]));
+    a.sync(&[]);
+    let mut b = TestPeer::connect(tracker, 41002, files(&["b.txt", "bb.txt"]));
+    b.sync(&[&a]);
+    a.sync(&[&b]);
+    let mut c = TestPeer::connect(tracker, 41003, vec![]);
+    c.sync(&[&a, &b]);
+    a.sync(&[&b, &c]);
+    b.sync(&[&a, &c]);
+    for p in [&mut a, &mut b, &mut c] {
+        p.assert_idle();
+    }
+}
+
+#[test]
+fn updates_reach_every_other_peer() {
+    let tracker = start_tracker();
+    let mut a = TestPeer::connect(tracker, 42001, files(&["a.txt"]));
+    a.sync(&[]);
+    let mut b = TestPeer::connect(tracker, 42002, files(&["b.txt"]));
+    b.sync(&[&a]);
+    a.sync(&[&b]);
+
+    b.update(files(&["b.txt", "new.txt"]));
+    a.sync(&[&b]);
+    a.update(vec![]);
+    b.sync(&[&a]);
+
+    let mut c = TestPeer::connect(tracker, 42003, files(&["c.txt"]));
+    c.sync(&[&a, &b]);
+    a.sync(&[&b, &c]);
+    b.sync(&[&a, &c]);
+    for p in [&mut a, &mut b, &mut c] {
+        p.assert_idle();
+    }
+}
+
+#[test]
+fn leaving_peers_are_unregistered() {
+    let tracker = start_tracker();
+    let mut a = TestPeer::connect(tracker, 43001, files(&["a.txt"]));
+    a.sync(&[]);
+    let mut b = TestPeer::connect(tracker, 43002, files(&["b.txt"]));
+    b.sync(&[&a]);
+    let mut c = TestPeer::connect(tracker, 43003, files(&["c.txt"]));
+    c.sync(&[&a, &b]);
+    a.sync(&[&b, &c]);
+    b.sync(&[&a, &c]);
+
+    // Polite leave
+    b.disconnect();
+    a.sync(&[&c]);
+    c.sync(&[&a]);
+
+    // Dropped connection
+    drop(c);
+    a.sync(&[]);
+
+    let mut d = TestPeer::connect(tracker, 43004, files(&["d.txt"]));
+    d.sync(&[&a]);
+    a.sync(&[&d]);
+    a.assert_idle();
+    d.assert_idle();
+}
+
+#[test]
+fn silent_peers_are_evicted() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let SocketAddr::V4(addr) = listener.local_addr().unwrap() else {
+        unreachable!()
+    };
+    let heartbeat = HeartbeatConfig {
+        interval: Duration::from_millis(100),
+        timeout: Duration::from_millis(300),
+    };
+    let ctx = Arc::new(Mutex::new(Context::new(addr, heartbeat)));
+    std::thread::spawn(move || serve(&listener, &ctx, &[]));
+    let tracker = SocketAddr::V4(addr);
+
+    let mut a = TestPeer::connect(tracker, 43101, files(&["a.txt"]));
+    a.sync(&[]);
+    // Never answers Ping
+    let mut silent = TestPeer::connect(tracker, 43102, files(&["silent.txt"]));
+    silent.sync(&[&a]);
+    a.sync(&[&silent]);
+
+    let deadline = Instant::now() + Duration::from_secs(2);
+    while a.view.contains_key(&silent.sock) {
+        assert!(Instant::now() < deadline, "silent peer wasn't evicted");
+        match read_msg(&mut a.stream).unwrap() {
+            AnyMessage::Server(server::Message::Ping(_)) => {
+                write_msg(&mut a.stream, &client::Message::from(client::Pong)).unwrap();
+            }
+            m => a.apply(m),
+        }
+    }
+    assert!(a.view.is_empty());
+    // The tracker closed the connection of the evicted peer
+    silent
+        .stream
+        .set_read_timeout(Some(Duration::from_secs(2)))
+        .unwrap();
+    while let Ok(m) = read_msg(&mut silent.stream) {
+        assert!(matches!(m, AnyMessage::Server(server::Message::Ping(_))));
+    }
+}
+
+#[test]
+fn connections_that_never_speak_are_closed() {
+    use std::io::Read;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let SocketAddr::V4(addr) = listener.local_addr().unwrap() else {
+        unreachable!()
+    };
+    let heartbeat = HeartbeatConfig {
+        interval: Duration::from_millis(100),
+        timeout: Duration::from_millis(300),
+    };
+    let ctx = Arc::new(Mutex::new(Context::new(addr, heartbeat)));
+    std::thread::spawn(move || serve(&listener, &ctx, &[]));
+
+    let mut silent = TcpStream::connect(addr).unwrap();
+    silent
+        .set_read_timeout(Some(Duration::from_secs(2)))
+        .unwrap();
+    let mut buf = [0; 1];
+    assert_eq!(silent.read(&mut buf).unwrap(), 0);
+    // Still accepting once the silent connection is gone
+    let mut a = TestPeer::connect(SocketAddr::V4(addr), 43111, files(&["a.txt"]));
+    a.sync(&[]);
+}
+
+#[test]
+fn federated_trackers_share_peers() {
+    let trackers = start_federated_trackers(3);
+    let mut a = TestPeer::connect(trackers[0], 44001, files(&["a.txt"]));
+    a.sync(&[]);
+    let mut b = TestPeer::connect(trackers[1], 44002, files(&["b.txt"]));
+    b.sync(&[&a]);
+    a.sync(&[&b]);
+    let mut c = TestPeer::connect(trackers[2], 44003, files(&["c.txt"]));
+    c.sync(&[&a, &b]);
+    a.sync(&[&b, &c]);
+    b.sync(&[&a, &c]);
+
+    b.update(files(&["b.txt", "bb.txt"]));
+    a.sync(&[&b, &c]);
+    c.sync(&[&a, &b]);
+
+    drop(c);
+    a.sync(&[&b]);
+    b.sync(&[&a]);
+    a.assert_idle();
+    b.assert_idle();
+}
+
+#[test]
+fn trackers_only_link_with_those_they_federate_with() {
+    use common::federation::{Message, ReplicatePeer, TrackerHello};
+
+    let trackers = start_federated_trackers(2);
+    let mut a = TestPeer::connect(trackers[0], 44101, vec![]);
+    let snapshot = read_msg(&mut a.stream).unwrap();
+    a.apply(snapshot);
+    let SocketAddr::V4(known) = trackers[1] else {
+        unreachable!()
+    };
+    // An unknown tracker, and a known one but from another IP
+    for tracker in [
+        SocketAddrV4::new(Ipv4Addr::LOCALHOST, 1),
+        SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), known.port()),
+    ] {
+        let mut stream = TcpStream::connect(trackers[0]).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(2)))
+            .unwrap();
+        write_msg(&mut stream, &Message::from(TrackerHello { tracker })).unwrap();
+        let replicate = ReplicatePeer {
+            peer: common::federation::Registration {
+                sock: SocketAddrV4::new(Ipv4Addr::LOCALHOST, 44102),
+                file_list: files(&["injected.txt"]),
+                registered_at: u64::MAX,
+            },
+        };
+        let _ = write_msg(&mut stream, &Message::from(replicate));
+        match read_msg(&mut stream).unwrap() {
+            AnyMessage::Server(server::Message::Refused(server::Refused { reason })) => {
+                assert_eq!(reason, "this tracker doesn't federate with you");
+            }
+            m => panic!("unexpected message {m:?}"),
+        }
+    }
+    a.assert_idle();
+}
+
+#[test]
+fn silent_tracker_links_are_dropped_and_redialed() {
+    use common::federation::{Message, Registration, ReplicatePeer};
+
+    let fake = TcpListener::bind("127.0.0.1:0").unwrap();
+    let SocketAddr::V4(fake_addr) = fake.local_addr().unwrap() else {
+        unreachable!()
+    };
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let SocketAddr::V4(addr) = listener.local_addr().unwrap() else {
+        unreachable!()
+    };
+    let heartbeat = HeartbeatConfig {
+        interval: Duration::from_millis(100),
+        timeout: Duration::from_millis(300),
+    };
+    let ctx = Arc::new(Mutex::new(Context::new(addr, heartbeat)));
+    std::thread::spawn(move || serve(&listener, &ctx, &[fake_addr]));
+
+    let mut a = TestPeer::connect(SocketAddr::V4(addr), 44201, vec![]);
+    let snapshot = read_msg(&mut a.stream).unwrap();
+    a.apply(snapshot);
+    // The tracker dials the fake one, which replicates a peer and then goes silent
+    let (mut link, _) = fake.accept().unwrap();
+    for _ in 0..2 {
+        read_msg(&mut link).unwrap();
+    }
+    let ghost = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 44202);
+    let replicate = ReplicatePeer {
+        peer: Registration {
+            sock: ghost,
+            file_list: files(&["ghost.txt"]),
+            registered_at: 1,
+        },
+    };
+    write_msg(&mut link, &Message::from(replicate)).unwrap();
+
+    let mut seen = false;
+    let deadline = Instant::now() + Duration::from_secs(2);
+    while !seen || a.view.contains_key(&ghost) {
+        assert!(Instant::now() < deadline, "ghost peer wasn't dropped");
+        match read_msg(&mut a.stream).unwrap() {
+            AnyMessage::Server(server::Message::Ping(_)) => {
+                write_msg(&mut a.stream, &client::Message::from(client::Pong)).unwrap();
+            }
+            m => a.apply(m),
+        }
+        seen |= a.view.contains_key(&ghost);
+    }
+    // Dialed again once dropped
+    let (mut redialed, _) = fake.accept().unwrap();
+    assert!(matches!(
+        read_msg(&mut redialed).unwrap(),
+        AnyMessage::Federation(Message::TrackerHello(_))
+    ));
+}
+
+#[test]
+fn newest_registration_wins_across_trackers() {
+    let trackers = start_federated_trackers(2);
+    let mut a = TestPeer::connect(trackers[0], 45001, vec![]);
+    a.sync(&[]);
+    let mut b = TestPeer::connect(trackers[1], 45002, vec![]);
+    b.sync(&[&a]);
+    a.sync(&[&b]);
+
+    // The same peer registered on both trackers, with different files
+    let x_old = TestPeer::connect(trackers[0], 45010, files(&["old.txt"]));
+    a.sync(&[&b, &x_old]);
+    b.sync(&[&a, &x_old]);
+    std::thread::sleep(Duration::from_millis(5));
+    let x_new = TestPeer::connect(trackers[1], 45010, files(&["new.txt"]));
+    a.sync(&[&b, &x_new]);
+    b.sync(&[&a, &x_new]);
+
+    // Falls back to the older registration once the newer one is gone
+    x_new.disconnect();
+    a.sync(&[&b, &x_old]);
+    b.sync(&[&a, &x_old]);
+    x_old.disconnect();
+    a.sync(&[&b]);
+    b.sync(&[&a]);
+    a.assert_idle();
+    b.assert_idle();
+}
+
+#[test]
+fn shutdown_tells_peers_and_federated_trackers() {
+    let trackers = start_federated_contexts(2);
+    let (closing, ctx) = &trackers[0];
+    let mut a = TestPeer::connect(*closing, 47001, files(&["a.txt"]));
+    a.sync(&[]);
+    let mut b = TestPeer::connect(trackers[1].0, 47002, files(&["b.txt"]));
+    b.sync(&[&a]);
+    a.sync(&[&b]);
+
+    shutdown(ctx, Duration::from_secs(1));
+    loop {
+        match read_msg(&mut a.stream).unwrap() {
+            AnyMessage::Server(server::Message::Shutdown(_)) => break,
+            m => a.apply(m),
+        }
+    }
+    assert!(read_msg(&mut a.stream).is_err());
+    b.sync(&[]);
+    b.assert_idle();
+
+    let mut late = TcpStream::connect(closing).unwrap();
+    late.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+    assert!(read_msg(&mut late).is_err());
+}
+
+/// Opens a relayed connection to `target` through `relay` and asks it for `file`
+fn relay_request(relay: SocketAddr, target: SocketAddrV4, file: &str) -> TcpStream {
+    let mut requester = TcpStream::connect(relay).unwrap();
+    write_msg(
+        &mut requester,
+        &client::Message::from(client::RelayConnect { target }),
+    )
+    .unwrap();
+    let request = client::RequestFile {
+        file: PathBuf::from(file),
+    };
+    write_msg(&mut requester, &client::Message::from(request)).unwrap();
+    requester
+}
+
+#[test]
+fn relay_splices_requester_to_seeder() {
+    use std::io::{Read, Write};
+    let relay = start_relay(RelayConfig {
+        enabled: true,
+        ..RelayConfig::default()
+    });
+    // Nothing listens on the seeder's port, it can only be reached through the relay
+    let mut seeder = TestPeer::connect(relay, 46001, files(&["a.txt"]));
+    // The snapshot is sent once the seeder is registered, only then can it be relayed to
+    let snapshot = read_msg(&mut seeder.stream).unwrap();
+    seeder.apply(snapshot);
+
+    let mut requester = relay_request(relay, seeder.sock, "a.txt");
+    let session = loop {
+        match read_msg(&mut seeder.stream).unwrap() {
+            AnyMessage::Server(server::Message::RelayIncoming(r)) => break r.session,
+            m => seeder.apply(m),
+        }
+    };
+    let mut relayed = TcpStream::connect(relay).unwrap();
+    write_msg(
+        &mut relayed,
+        &client::Message::from(client::RelayAccept { session }),
+    )
+    .unwrap();
+    match read_msg(&mut relayed).unwrap() {
+        AnyMessage::Client(client::Message::RequestFile(r)) => {
+            assert_eq!(r.file, PathBuf::from("a.txt"));
+        }
+        m => panic!("unexpected message {m:?}"),
+    }
+    relayed.write_all(b"relayed contents").unwrap();
+    drop(relayed);
+
+    let mut received = String::new();
+    requester.read_to_string(&mut received).unwrap();
+    assert_eq!(received, "relayed contents");
+    seeder.assert_idle();
+}
+
+#[test]
+fn relay_refuses_unknown_targets() {
+    use std::io::Read;
+    // The relay closes the connection, resetting it since the request wasn't read
+    let refused = |mut requester: TcpStream| {
+        let mut received = Vec::new();
+        !matches!(requester.read_to_end(&mut received), Ok(n) if n > 0)
+    };
+    let relay = start_relay(RelayConfig {
+        enabled: true,
+        ..RelayConfig::default()
+    });
+    let target = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 46002);
+    assert!(refused(relay_request(relay, target, "a.txt")));
+
+    let disabled = start_tracker();
+    let mut seeder = TestPeer::connect(disabled, 46003, vec![]);
+    let snapshot = read_msg(&mut seeder.stream).unwrap();
+    seeder.apply(snapshot);
+    assert!(refused(relay_request(disabled, seeder.sock, "a.txt")));
+    seeder.assert_idle();
+}
+
+/// Reads until the tracker refuses the peer, returning why
+fn refusal(peer: &mut TestPeer) -> String {
+    loop {
+        match read_msg(&mut peer.stream).unwrap() {
+            AnyMessage::Server(server::Message::Refused(server::Refused { reason })) => {
+                assert!(read_msg(&mut peer.stream).is_err(), "still connected");
+                return reason;
+            }
+            m => peer.apply(m),
+        }
+    }
+}
+
+#[test]
+fn cidrs_match_their_network() {
+    use access::Cidr;
+    let net: Cidr = "10.1.0.0/16".parse().unwrap();
+    assert!(net.contains(Ipv4Addr::new(10, 1, 200, 3)));
+    assert!(!net.contains(Ipv4Addr::new(10, 2, 0, 1)));
+    let lone: Cidr = "10.1.2.3".parse().unwrap();
+    assert_eq!(lone.to_string(), "10.1.2.3/32");
+    assert!(lone.contains(Ipv4Addr::new(10, 1, 2, 3)));
+    assert!(!lone.contains(Ipv4Addr::new(10, 1, 2, 4)));
+    let all: Cidr = "0.0.0.0/0".parse().unwrap();
+    assert!(all.contains(Ipv4Addr::new(192, 168, 1, 1)));
+    assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+    assert!("10.0.0/8".parse::<Cidr>().is_err());
+}
+
+#[test]
+fn refused_clients_are_told_why() {
+    let deny = AccessConfig {
+        deny: vec!["127.0.0.0/8".parse().unwrap()],
+        ..AccessConfig::default()
+    };
+    let tracker = start_guarded_tracker(deny);
+    let mut denied = TestPeer::connect(tracker, 49001, vec![]);
+    assert_eq!(refusal(&mut denied), "your address isn't allowed");
+
+    let allow = AccessConfig {
+        allow: vec!["10.0.0.0/8".parse().unwrap()],
+        ..AccessConfig::default()
+    };
+    let tracker = start_guarded_tracker(allow);
+    let mut denied = TestPeer::connect(tracker, 49002, vec![]);
+    assert_eq!(refusal(&mut denied), "your address isn't allowed");
+
+    let full = AccessConfig {
+        max_peers: Some(1),
+        ..AccessConfig::default()
+    };
+    let tracker = start_guarded_tracker(full);
+    let mut a = TestPeer::connect(tracker, 49003, files(&["a.txt"]));
+    let snapshot = read_msg(&mut a.stream).unwrap();
+    a.apply(snapshot);
+    let mut b = TestPeer::connect(tracker, 49004, vec![]);
+    assert_eq!(refusal(&mut b), "the tracker is full");
+    a.assert_idle();
+
+    let chatty = AccessConfig {
+        message_rate: Some(3),
+        ..AccessConfig::default()
+    };
+    let tracker = start_guarded_tracker(chatty);
+    let mut a = TestPeer::connect(tracker, 49005, vec![]);
+    a.sync(&[]);
+    for i in 0..3 {
+        a.update(files(&[&"x".repeat(i + 1)]));
+    }
+    assert_eq!(refusal(&mut a), "too many messages, slow down");
+
+    let hasty = AccessConfig {
+        connection_rate: Some(2),
+        ..AccessConfig::default()
+    };
+    let tracker = start_guarded_tracker(hasty);
+    let mut peers: Vec<_> = (0..3)
+        .map(|i| TestPeer::connect(tracker, 49006 + i, vec![]))
+        .collect();
+    assert_eq!(refusal(&mut peers[2]), "too many connections, slow down");
+}
+
+/// Sends `head`, a request line and headers, and returns the whole response
+fn http_raw(addr: SocketAddr, head: &str) -> String {
+    use std::io::{Read, Write};
+    let mut stream = TcpStream::connect(addr).unwrap();
+    write!(stream, "{head}\r\n\r\n").unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    response
+}
+
+fn http(addr: SocketAddr, method: &str, path: &str) -> String {
+    http_raw(addr, &format!("{method} {path} HTTP/1.1\r\nHost: {addr}"))
+}
+
+fn http_get(addr: SocketAddr, path: &str) -> String {
+    http(addr, "GET", path)
+}
+
+/// The status line and JSON body of the response
+fn admin(addr: SocketAddr, method: &str, path: &str) -> (String, serde_json::Value) {
+    let response = http(addr, method, path);
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    let status = head.lines().next().unwrap().to_string();
+    (status, serde_json::from_str(body).unwrap())
+}
+
+#[test]
+fn metrics_count_peers_messages_and_errors() {
+    use std::io::Write;
+    let (tracker, ctx) = start_federated_contexts(1).remove(0);
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let metrics_addr = listener.local_addr().unwrap();
+    let metrics = Arc::clone(&ctx.lock().unwrap().metrics);
+    std::thread::spawn(move || metrics::serve(&listener, &metrics));
+
+    let mut a = TestPeer::connect(tracker, 47001, files(&["a.txt", "aa.txt"]));
+    a.sync(&[]);
+    let mut b = TestPeer::connect(tracker, 47002, files(&["b.txt"]));
+    b.sync(&[&a]);
+    a.sync(&[&b]);
+    let mut garbage = TcpStream::connect(tracker).unwrap();
+    garbage.write_all(&[200, 0, 0, 0, 0, 0, 0, 0, 0]).unwrap();
+    std::thread::sleep(Duration::from_millis(100));
+
+    let response = http_get(metrics_addr, "/metrics");
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+    for line in [
+        "p2prs_tracker_peers 2",
+        "p2prs_tracker_files 3",
+        "p2prs_tracker_connections_total 3",
+        "p2prs_tracker_messages_total{direction=\"received\",type=\"Connect\"} 2",
+        "p2prs_tracker_messages_total{direction=\"sent\",type=\"PeerSnapshot\"} 2",
+        "p2prs_tracker_messages_total{direction=\"sent\",type=\"RegisterPeer\"} 1",
+        "p2prs_tracker_deserialize_errors_total{error=\"WrongMsgType\"} 1",
+        "p2prs_tracker_broadcast_seconds_count 2",
+    ] {
+        assert!(
+            response.lines().any(|l| l == line),
+            "no {line:?} in {response}"
+        );
+    }
+    let received = response
+        .lines()
+        .find_map(|l| l.strip_prefix("p2prs_tracker_received_bytes_total "))
+        .unwrap();
+    assert!(received.parse::<u64>().unwrap() > 0);
+
+    assert!(http_get(metrics_addr, "/").starts_with("HTTP/1.1 404"));
+}
+
+#[test]
+fn admin_api_lists_kicks_and_bans_peers() {
+    use serde_json::json;
+    let (tracker, ctx) = start_federated_contexts(1).remove(0);
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let api = listener.local_addr().unwrap();
+    std::thread::spawn(move || admin::serve(&listener, &ctx));
+
+    let mut a = TestPeer::connect(tracker, 48001, files(&["a.txt", "shared.txt"]));
+    a.sync(&[]);
+    let mut b = TestPeer::connect(tracker, 48002, files(&["shared.txt"]));
+    b.sync(&[&a]);
+    a.sync(&[&b]);
+
+    let (status, peers) = admin(api, "GET", "/peers");
+    assert_eq!(status, "HTTP/1.1 200 OK");
+    let mut addrs: Vec<_> = peers
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|p| p["addr"].as_str().unwrap())
+        .collect();
+    addrs.sort();
+    assert_eq!(addrs, ["127.0.0.1:48001", "127.0.0.1:48002"]);
+    assert_eq!(peers[0]["tracker"], json!(null));
+
+    let (_, files) = admin(api, "GET", "/files?path=shared%2Etxt");
+    let holders = files[0]["peers"].as_array().unwrap();
+    assert_eq!((files.as_array().unwrap().len(), holders.len()), (1, 2));
+    let (_, stats) = admin(api, "GET", "/stats");
+    assert_eq!(
+        (stats["peers"].clone(), stats["files"].clone()),
+        (json!(2), json!(3))
+    );
+
+    let (status, kicked) = admin(api, "POST", "/peers/127.0.0.1:48002/kick");
+    assert_eq!(status, "HTTP/1.1 200 OK");
+    assert_eq!(kicked, json!({ "kicked": ["127.0.0.1:48002"] }));
+    a.sync(&[]);
+    let (status, _) = admin(api, "POST", "/peers/127.0.0.1:48002/kick");
+    assert_eq!(status, "HTTP/1.1 404 Not Found");
+
+    let (_, banned) = admin(api, "POST", "/peers/127.0.0.1:48001/ban");
+    assert_eq!(banned, json!({ "kicked": ["127.0.0.1:48001"] }));
+    assert_eq!(admin(api, "GET", "/bans").1, json!(["127.0.0.1"]));
+    let mut refused = TestPeer::connect(tracker, 48003, vec![]);
+    assert_eq!(refusal(&mut refused), "your address is banned");
+
+    let (status, _) = admin(api, "DELETE", "/bans/127.0.0.1");
+    assert_eq!(status, "HTTP/1.1 200 OK");
+    let mut c = TestPeer::connect(tracker, 48003, vec![]);
+    c.sync(&[]);
+
+    // Browsers, even those tricked into connecting to loopback, are refused
+    let port = api.port();
+    for head in [
+        format!(
+            "POST /peers/127.0.0.1:48003/ban HTTP/1.1\r\nHost: localhost:{port}\r\nOrigin: http://evil.example"
+        ),
+        format!("GET /peers HTTP/1.1\r\nHost: evil.example:{port}"),
+        "GET /peers HTTP/1.1".to_string(),
+    ] {
+        assert!(
+            http_raw(api, &head).starts_with("HTTP/1.1 403 Forbidden"),
+            "{head}"
+        );
+    }
+    assert!(
+        http_raw(api, &format!("GET /bans HTTP/1.1\r\nhost: [::1]:{port}"))
+            .starts_with("HTTP/1.1 200 OK")
+    );
+    assert_eq!(admin(api, "GET", "/bans").1, json!([]));
+}
+
+#[test]
+fn config_file_is_validated_after_arguments() {
+    use crate::config::{Args, ServerConfig};
+    use clap::Parser;
+    use common::config::{ConfigError, parse};
+
+    let file = std::path::Path::new("server.toml");
+    let mut config: ServerConfig = parse(
+        "listen = \"0.0.0.0:7000\"\n[heartbeat]\ninterval_secs = 2\ntimeout_secs = 6\n",
+        file,
+    )
+    .unwrap();
+    let args = Args::try_parse_from([
+        "server",
+        "--relay",
+        "--relay-rate",
+        "1000",
+        "--metrics",
+        "127.0.0.1:9100",
+        "--allow",
+        "10.0.0.0/8,192.168.1.7",
+        "--max-peers",
+        "20",
+    ])
+    .unwrap();
+    config.apply(args);
+    config.validate().unwrap();
+    assert_eq!(config.listen, "0.0.0.0:7000".parse().unwrap());
+    assert_eq!(config.heartbeat().timeout, Duration::from_secs(6));
+    let relay = config.relay();
+    assert!(relay.enabled);
+    assert_eq!(relay.session_rate, Some(1000));
+    assert_eq!(relay.accept_timeout, RelayConfig::default().accept_timeout);
+    assert_eq!(
+        config.metrics.listen,
+        Some("127.0.0.1:9100".parse().unwrap())
+    );
+    assert_eq!(config.access.allow.len(), 2);
+    assert_eq!(config.access.max_peers, Some(20));
+
+    let args = Args::try_parse_from(["server", "--federate", "0.0.0.0:7000"]).unwrap();
+    config.apply(args);
+    assert!(matches!(
+        config.validate(),
+        Err(ConfigError::Invalid {
+            field: "federate",
+            ..
+        })
+    ));
+
+    let invalid = |text: &str| parse::<ServerConfig>(text, file).and_then(|c| c.validate());
+    assert!(matches!(
+        invalid("[heartbeat]\ninterval_secs = 10\ntimeout_secs = 10"),
+        Err(ConfigError::Invalid {
+            field: "heartbeat.timeout_secs",
+            ..
+        })
+    ));
+    let federating = "listen = \"0.0.0.0:7000\"\nfederate = [\"10.0.0.2:7000\"]";
+    assert!(matches!(
+        invalid(federating),
+        Err(ConfigError::Invalid {
+            field: "advertise",
+            ..
+        })
+    ));
+    invalid(&format!("{federating}\nadvertise = \"10.0.0.1:7000\"")).unwrap();
+    assert!(matches!(
+        invalid("[relay]\nrate = 0"),
+        Err(ConfigError::Invalid {
+            field: "relay.rate",
+            ..
+        })
+    ));
+    assert!(matches!(
+        invalid("listen = \"0.0.0.0:7000\"\n[metrics]\nlisten = \"0.0.0.0:7000\""),
+        Err(ConfigError::Invalid {
+            field: "metrics.listen",
+            ..
+        })
+    ));
+    assert!(matches!(
+        invalid("[admin]\nlisten = \"0.0.0.0:6970\""),
+        Err(ConfigError::Invalid {
+            field: "admin.listen",
+            ..
+        })
+    ));
+    assert!(matches!(
+        invalid("[access]\nmessage_rate = 0"),
+        Err(ConfigError::Invalid {
+            field: "access.message_rate",
+            ..
+        })
+    ));
+    assert!(matches!(
+        invalid("[access]\ndeny = [\"10.0.0.0/40\"]"),
+        Err(ConfigError::Parse { .. })
+    ));
+    assert!(matches!(
+        invalid("listen = 6969"),
+        Err(ConfigError::Parse { .. })
+    ));
+}